@@ -2,9 +2,11 @@
 #[cfg(target_arch = "x86_64")] pub mod generic {
     use super::x86;
     pub use self::x86::Registers;
+    pub use self::x86::backtrace;
+    pub use self::x86::watchdog;
 
     pub mod intrinsics {
-        pub use super::x86::intrinsics::halt;
+        pub use super::x86::intrinsics::{halt, halt_other_cores, wait_for_interrupt};
     }
 }
 
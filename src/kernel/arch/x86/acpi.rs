@@ -0,0 +1,132 @@
+//! ACPI Table Discovery
+//!
+//! Firmware describes the hardware it knows about in ACPI tables. The Root
+//! System Description Pointer, which the boot loader copies into a multiboot
+//! tag, points at the RSDT (32 bit entries) or on ACPI 2.0+ the XSDT (64 bit
+//! entries), an array of physical pointers to every other table. All tables
+//! begin with a common header holding a 4 byte signature, such as `HPET`, and
+//! their length.
+//!
+//! Tables are ordinary memory, so those within the physical memory window
+//! are read in place through `phys_to_virt()`. Tables may lie anywhere in
+//! physical memory though, so any beyond the window are copied to the heap
+//! through temporary mappings. Every table is found once by `initialize()`.
+
+use alloc::boxed::Box;
+use alloc::vec::Vec;
+use core::{ptr, slice};
+
+use crate::sync::Once;
+
+use super::frame_allocator::PAGE_SIZE;
+use super::paging::{phys_to_virt, with_temporary_map, PHYS_WINDOW_SIZE};
+
+/// Size of the header common to all tables
+pub const HEADER_SIZE: usize = 36;
+
+/// Every valid table pointed to by the root table, including their headers
+static TABLES: Once<Vec<&'static [u8]>> = Once::new();
+
+/// The useful contents of the Root System Description Pointer
+#[derive(Copy, Clone, Debug)]
+pub struct Rsdp {
+    pub revision: u8,
+    /// Physical address of the RSDT
+    pub rsdt: u32,
+    /// Physical address of the XSDT, which is only valid if `revision >= 2`
+    pub xsdt: u64,
+}
+
+impl Rsdp {
+    /// Parses an RSDP, returning `None` if the signature or checksum is wrong
+    pub unsafe fn from_ptr(ptr: *const u8) -> Option<Rsdp> {
+        const V1_SIZE: usize = 20;
+        let v1 = slice::from_raw_parts(ptr, V1_SIZE);
+        if &v1[..8] != b"RSD PTR " || !checksum(v1) {
+            return None;
+        }
+        let revision = v1[15];
+        Some(Rsdp {
+            revision: revision,
+            rsdt: *(ptr.offset(16) as *const u32),
+            xsdt: if revision >= 2 { *(ptr.offset(24) as *const u64) } else { 0 },
+        })
+    }
+}
+
+/// Finds the tables pointed to by the root table, for `find_table()`
+///
+/// Tables with a bad checksum are ignored. Requires the heap.
+pub fn initialize(rsdp: Rsdp) {
+    let (root, entry_size) = if rsdp.revision >= 2 && rsdp.xsdt != 0 {
+        (read_table(rsdp.xsdt as usize), 8)
+    } else {
+        (read_table(rsdp.rsdt as usize), 4)
+    };
+
+    let tables = match root {
+        Some(root) => {
+            root[HEADER_SIZE..].chunks(entry_size)
+                .filter(|entry| entry.len() == entry_size)
+                .map(|entry| entry.iter().rev().fold(0, |addr, &byte| addr << 8 | byte as usize))
+                .filter_map(read_table)
+                .collect()
+        }
+        None => Vec::new(),
+    };
+    TABLES.initialize(tables);
+}
+
+/// Returns the first table with a signature, including its header
+pub fn find_table(signature: &[u8; 4]) -> Option<&'static [u8]> {
+    TABLES.get()?.iter()
+        .find(|table| &table[..4] == signature)
+        .map(|table| *table)
+}
+
+/// Reads a whole table given its physical address, validating its checksum
+fn read_table(paddr: usize) -> Option<&'static [u8]> {
+    let mut header = [0; HEADER_SIZE];
+    read_phys(paddr, &mut header);
+    let length = header[4..8].iter().rev().fold(0, |length, &byte| length << 8 | byte as usize);
+    if length < HEADER_SIZE {
+        return None;
+    }
+
+    let table: &'static [u8] = if paddr + length <= PHYS_WINDOW_SIZE {
+        unsafe { slice::from_raw_parts(phys_to_virt(paddr) as *const u8, length) }
+    } else {
+        let mut copy = alloc::vec![0; length];
+        read_phys(paddr, &mut copy);
+        if !checksum(&copy) {
+            return None;
+        }
+        Box::leak(copy.into_boxed_slice())
+    };
+    if checksum(table) { Some(table) } else { None }
+}
+
+/// Copies physical memory starting at `paddr` into `buf`
+fn read_phys(paddr: usize, buf: &mut [u8]) {
+    if paddr + buf.len() <= PHYS_WINDOW_SIZE {
+        unsafe {
+            ptr::copy_nonoverlapping(phys_to_virt(paddr) as *const u8, buf.as_mut_ptr(), buf.len());
+        }
+        return;
+    }
+    // beyond the window, map a page at a time
+    let mut done = 0;
+    while done < buf.len() {
+        let addr = paddr + done;
+        let count = (PAGE_SIZE - addr % PAGE_SIZE).min(buf.len() - done);
+        with_temporary_map(addr, |vaddr| unsafe {
+            ptr::copy_nonoverlapping(vaddr as *const u8, buf[done..].as_mut_ptr(), count);
+        });
+        done += count;
+    }
+}
+
+/// Do the bytes of a table sum to zero?
+fn checksum(bytes: &[u8]) -> bool {
+    bytes.iter().fold(0u8, |sum, &byte| sum.wrapping_add(byte)) == 0
+}
@@ -0,0 +1,268 @@
+//! Userspace Address Spaces
+//!
+//! Every process owns a separate set of page tables. The higher half of each
+//! address space is shared with the kernel (see `PT4::new_user()`), while the
+//! lower half only contains the mappings of that process. An `AddressSpace`
+//! records which user regions have been mapped so they may later be inspected
+//! or torn down.
+
+use core;
+use alloc::vec::Vec;
+use spin::{Mutex, MutexGuard};
+
+use super::frame_allocator::{frame_alloc_zeroed, frame_free, frame_incref, get_fallocator,
+                             Frame, PAGE_SIZE};
+use super::interrupts::{PageFaultError, PF_PRESENT, PF_WRITE};
+use super::paging::{with_temporary_map2, PageFlags, PT4, COPY_ON_WRITE, USER, WRITE};
+
+/// The first address (exclusive) above the lower half available to userspace
+pub const USER_END: usize = 0x0000_8000_0000_0000;
+
+/// The address space of the running process
+static CURRENT: Mutex<Option<AddressSpace>> = Mutex::new(None);
+
+/// A range of user memory mapped with the same flags
+#[derive(Copy, Clone, Debug)]
+pub struct Region {
+    /// The first byte of the region
+    pub start: usize,
+    /// The last byte of the region
+    pub end:   usize,
+    pub flags: PageFlags,
+}
+
+/// The page tables and mapped regions of a userspace process
+pub struct AddressSpace {
+    pt4: PT4,
+    regions: Vec<Region>,
+}
+
+/// The resolution of a page fault
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum FaultAction {
+    /// Map a new zeroed frame with these flags and retry the instruction
+    Map(PageFlags),
+    /// Give the process a private, writable copy of a shared page
+    CopyOnWrite,
+    /// The access is invalid and the process must be terminated
+    Kill,
+}
+
+impl Region {
+    /// Does this region contain the address?
+    pub fn contains(&self, addr: usize) -> bool {
+        addr >= self.start && addr <= self.end
+    }
+}
+
+impl AddressSpace {
+    /// Creates an address space sharing the current kernel mappings
+    pub fn new() -> AddressSpace {
+        AddressSpace {
+            pt4: PT4::new_user(),
+            regions: Vec::new(),
+        }
+    }
+
+    /// Maps newly allocated frames over `size` bytes starting at `start`
+    ///
+    /// The `USER` flag is always added. `start` must be page aligned.
+    pub fn map(&mut self, start: usize, size: usize, flags: PageFlags) {
+        let region = self.add_region(start, size, flags).expect("Invalid user region");
+        let mut page = region.start;
+        while page < region.end {
+            self.pt4.map_4k(page, region.flags);
+            page += PAGE_SIZE;
+        }
+    }
+
+    /// Records a user region without mapping any memory
+    ///
+    /// Returns `None` if the region is unaligned, empty, outside the lower
+    /// half, or overlaps an existing region.
+    pub fn add_region(&mut self, start: usize, size: usize, flags: PageFlags) -> Option<Region> {
+        if start & (PAGE_SIZE - 1) != 0 || size == 0
+            || start >= USER_END || size > USER_END - start {
+            return None;
+        }
+
+        let pages = (size + PAGE_SIZE - 1) / PAGE_SIZE;
+        let region = Region {
+            start: start,
+            end:   start + pages * PAGE_SIZE - 1,
+            flags: flags | USER,
+        };
+        if self.regions.iter().any(|r| r.start <= region.end && region.start <= r.end) {
+            return None;
+        }
+        self.regions.push(region);
+        Some(region)
+    }
+
+    /// Returns the user regions of this address space
+    pub fn regions(&self) -> &[Region] {
+        &self.regions
+    }
+
+    /// Returns the region containing the address, if any
+    pub fn find_region(&self, addr: usize) -> Option<&Region> {
+        self.regions.iter().find(|r| r.contains(addr))
+    }
+
+    /// Decides how to resolve a page fault at `addr`
+    ///
+    /// Regions are populated lazily, so a not-present fault inside a region
+    /// with sufficient permissions is resolved by mapping a frame. All other
+    /// faults are access violations.
+    pub fn resolve_fault(&self, addr: usize, error: PageFaultError) -> FaultAction {
+        let region = match self.find_region(addr) {
+            Some(region) => region,
+            None => return FaultAction::Kill,
+        };
+        if error.contains(PF_WRITE) && !region.flags.contains(WRITE) {
+            return FaultAction::Kill;
+        }
+        if error.contains(PF_PRESENT) {
+            let flags = self.pt4.translate_flags(addr).unwrap_or(PageFlags::empty());
+            if error.contains(PF_WRITE) && flags.contains(COPY_ON_WRITE) {
+                return FaultAction::CopyOnWrite;
+            }
+            return FaultAction::Kill;
+        }
+        FaultAction::Map(region.flags)
+    }
+
+    /// Creates a copy of this address space sharing all mapped user pages
+    ///
+    /// Writable pages are made read-only in both address spaces and marked
+    /// `COPY_ON_WRITE`, so the first write to such a page copies it. CR0.WP is
+    /// set, so writes by the kernel fault as well; see `usercopy`.
+    pub fn fork(&mut self) -> AddressSpace {
+        let mut child = AddressSpace {
+            pt4: PT4::new_user(),
+            regions: self.regions.clone(),
+        };
+
+        for region in &self.regions {
+            let mut page = region.start;
+            while page < region.end {
+                if let Some(paddr) = self.pt4.translate(page) {
+                    let mut flags = self.pt4.translate_flags(page).unwrap();
+                    if flags.contains(WRITE) {
+                        flags = (flags - WRITE) | COPY_ON_WRITE;
+                        self.pt4.update_flags(page, flags);
+                    }
+                    child.pt4.map_to_4k(page, paddr, flags);
+                    frame_incref(&Frame::containing(paddr));
+                }
+                page += PAGE_SIZE;
+            }
+        }
+        child
+    }
+
+    /// Returns the underlying page tables
+    pub fn pt4(&mut self) -> &mut PT4 {
+        &mut self.pt4
+    }
+
+    /// Loads these tables into CR3
+    pub fn activate(&self) {
+        self.pt4.activate();
+    }
+}
+
+impl Drop for AddressSpace {
+    /// Frees every user page and then the tables themselves
+    ///
+    /// The tables must not be loaded in CR3, so a process's own address space
+    /// can only be freed once another has been switched to.
+    fn drop(&mut self) {
+        assert!(!self.pt4.is_active(), "Freeing the active address space");
+        for region in &self.regions {
+            let mut page = region.start;
+            while page < region.end {
+                if let Some(paddr) = self.pt4.unmap(page) {
+                    frame_free(Frame::containing(paddr));
+                }
+                page += PAGE_SIZE;
+            }
+        }
+        // emptied lower half tables were reaped by unmap()
+        frame_free(Frame::containing(self.pt4.addr()));
+    }
+}
+
+/// Loads an address space and makes it the current one
+pub fn switch_to(space: AddressSpace) {
+    space.activate();
+    *CURRENT.lock() = Some(space);
+}
+
+/// Returns the address space of the running process
+pub fn current<'a>() -> MutexGuard<'a, Option<AddressSpace>> {
+    CURRENT.lock()
+}
+
+/// Attempts to resolve a page fault in the current address space
+///
+/// Returns false if the fault is an access violation. A fault taken while the
+/// current address space is locked, as by kernel code working on it, cannot
+/// be resolved without deadlocking, so is treated as a violation too.
+pub fn handle_page_fault(addr: usize, error: PageFaultError) -> bool {
+    let mut current = match CURRENT.try_lock() {
+        Some(current) => current,
+        None => return false,
+    };
+    let space = match current.as_mut() {
+        Some(space) => space,
+        None => return false,
+    };
+
+    match space.resolve_fault(addr, error) {
+        FaultAction::Map(flags) => {
+            let frame = frame_alloc_zeroed();
+            space.pt4.map_to_4k(addr & !(PAGE_SIZE - 1), frame.addr(), flags);
+            true
+        }
+        FaultAction::CopyOnWrite => {
+            copy_on_write(space, addr & !(PAGE_SIZE - 1));
+            true
+        }
+        FaultAction::Kill => false,
+    }
+}
+
+/// Replaces a shared copy-on-write page with a private writable one
+///
+/// If no other address space references the frame any longer, the frame is
+/// simply made writable rather than copied.
+fn copy_on_write(space: &mut AddressSpace, page: usize) {
+    let paddr = space.pt4.translate(page).unwrap();
+    let flags = (space.pt4.translate_flags(page).unwrap() - COPY_ON_WRITE) | WRITE;
+    let old = Frame::containing(paddr);
+
+    let new = {
+        let mut fallocator = get_fallocator();
+        if fallocator.refcount(&old) == 1 {
+            None
+        } else {
+            let new = fallocator.alloc();
+            // either frame may lie beyond the physical memory window
+            with_temporary_map2(old.addr(), new.addr(), |src, dst| unsafe {
+                core::ptr::copy_nonoverlapping(src as *const u8, dst as *mut u8, PAGE_SIZE);
+            });
+            fallocator.decref(old);
+            Some(new)
+        }
+    }; // release the allocator before mapping, which may allocate tables
+
+    match new {
+        Some(frame) => {
+            space.pt4.map_to_4k(page, frame.addr(), flags);
+        }
+        None => {
+            space.pt4.update_flags(page, flags);
+        }
+    }
+}
@@ -0,0 +1,127 @@
+//! Local APIC
+//!
+//! Every core has a local APIC, which accepts the interrupts delivered to
+//! that core and sends inter-processor interrupts (IPIs) to others. Its
+//! registers are memory mapped at the physical address held by the
+//! `IA32_APIC_BASE` MSR. Device interrupts are still routed through the PIC,
+//! so the local APIC is only used for IPIs.
+//!
+//! The local APIC may be left disabled with `noapic` on the command line, in
+//! which case `is_enabled()` is false and the kernel runs on a single core.
+
+use crate::sync::Once;
+
+use super::frame_allocator::PAGE_SIZE;
+use super::interrupts;
+use super::intrinsics::{rdmsr, wrmsr};
+use super::mmio::MmioRegion;
+use super::paging::map_mmio;
+
+/// MSR holding the physical address of the local APIC
+const APIC_BASE_MSR: u32 = 0x1b;
+const APIC_BASE_ENABLE: u64 = 1 << 11;
+const APIC_BASE_ADDR_MASK: u64 = 0x000f_ffff_ffff_f000;
+
+/// Register offsets
+const ID:       usize = 0x20;
+const EOI:      usize = 0xb0;
+const SPURIOUS: usize = 0xf0;
+const ICR_LOW:  usize = 0x300;
+const ICR_HIGH: usize = 0x310;
+
+/// Spurious interrupt register: accept interrupts
+const SOFTWARE_ENABLE: u32 = 1 << 8;
+/// Interrupt command register: delivery modes
+const DELIVERY_NMI:     u32 = 4 << 8;
+const DELIVERY_INIT:    u32 = 5 << 8;
+const DELIVERY_STARTUP: u32 = 6 << 8;
+/// Interrupt command register: the previous IPI has not been accepted yet
+const DELIVERY_PENDING: u32 = 1 << 12;
+/// Interrupt command register: assert, rather than deassert, the interrupt
+const LEVEL_ASSERT: u32 = 1 << 14;
+/// Interrupt command register: send to every core but the sender, ignoring
+/// the destination
+const ALL_EXCLUDING_SELF: u32 = 3 << 18;
+
+/// The vector the local APIC raises for spurious interrupts
+pub const SPURIOUS_VECTOR: usize = 0xff;
+
+static LAPIC: Once<MmioRegion> = Once::new();
+
+/// Maps and enables the local APIC of the bootstrap processor
+///
+/// Every core finds its own local APIC at the same address, so the mapping
+/// is shared by all of them.
+pub fn initialize() {
+    let paddr = (rdmsr(APIC_BASE_MSR) & APIC_BASE_ADDR_MASK) as usize;
+    let registers = unsafe { MmioRegion::new(map_mmio(paddr, PAGE_SIZE), PAGE_SIZE) };
+    LAPIC.initialize(registers);
+
+    let mut idt = interrupts::Idt::current().unwrap();
+    idt.register_isr(SPURIOUS_VECTOR, isr_spurious);
+    idt.load();
+    enable();
+}
+
+/// Enables the local APIC of the current core
+pub fn enable() {
+    wrmsr(APIC_BASE_MSR, rdmsr(APIC_BASE_MSR) | APIC_BASE_ENABLE);
+    registers().write::<u32>(SPURIOUS, SOFTWARE_ENABLE | SPURIOUS_VECTOR as u32);
+}
+
+/// Has the local APIC been initialized?
+pub fn is_enabled() -> bool {
+    LAPIC.get().is_some()
+}
+
+fn registers() -> &'static MmioRegion {
+    LAPIC.expect("Local APIC")
+}
+
+/// Returns the local APIC id of the current core
+pub fn id() -> u32 {
+    registers().read::<u32>(ID) >> 24
+}
+
+/// Signals the end of an interrupt delivered by the local APIC
+pub fn eoi() {
+    registers().write::<u32>(EOI, 0);
+}
+
+/// Sends a fixed interrupt of `vector` to the core with a local APIC id
+pub fn send_ipi(apic_id: u32, vector: usize) {
+    write_icr(apic_id, LEVEL_ASSERT | vector as u32);
+}
+
+/// Sends an NMI to every other core, which is delivered even to cores
+/// running with interrupts disabled
+pub fn send_nmi_to_others() {
+    write_icr(0, DELIVERY_NMI | LEVEL_ASSERT | ALL_EXCLUDING_SELF);
+}
+
+/// Sends an INIT IPI, resetting the core into its wait for a startup IPI
+pub fn send_init(apic_id: u32) {
+    write_icr(apic_id, DELIVERY_INIT | LEVEL_ASSERT);
+}
+
+/// Sends a startup IPI, starting the core in real mode at a physical address
+///
+/// The address must be page aligned and below 1 MiB.
+pub fn send_startup(apic_id: u32, paddr: usize) {
+    assert!(paddr % PAGE_SIZE == 0 && paddr < 0x10_0000, "Bad startup address {:#x}", paddr);
+    write_icr(apic_id, DELIVERY_STARTUP | LEVEL_ASSERT | (paddr / PAGE_SIZE) as u32);
+}
+
+/// Sends an IPI described by the low half of the interrupt command register,
+/// waiting until it has been accepted
+fn write_icr(apic_id: u32, command: u32) {
+    let registers = registers();
+    registers.write::<u32>(ICR_HIGH, apic_id << 24);
+    registers.write::<u32>(ICR_LOW, command); // sends the IPI
+    while registers.read::<u32>(ICR_LOW) & DELIVERY_PENDING != 0 { }
+}
+
+isr_plain! {
+    // no end of interrupt is signalled for spurious interrupts
+    0xff => fn isr_spurious(_state) { }
+}
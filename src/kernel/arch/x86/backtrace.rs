@@ -0,0 +1,59 @@
+//! Stack Backtraces
+//!
+//! With frame pointers, every function prologue pushes the caller's `rbp` and
+//! points `rbp` at that saved value, so the frames form a linked list:
+//! `[rbp]` is the caller's `rbp` and `[rbp + 8]` the return address into the
+//! caller. Walking this list yields the return addresses of every active
+//! call, which may be resolved offline against the kernel ELF (e.g. with
+//! `addr2line`).
+//!
+//! This requires the kernel to be built with `-C force-frame-pointers=yes`,
+//! see the Makefile. Without it the chain is garbage and the walk stops early.
+
+use super::stacks::STACK_SIZE;
+
+/// Most frames reported by a backtrace
+pub const MAX_FRAMES: usize = 32;
+
+/// Lowest address a kernel stack may occupy
+const KERNEL_HALF: usize = 0xffff_8000_0000_0000;
+
+/// Calls `f` with the return address of each frame, innermost first
+///
+/// No kernel stack is larger than `STACK_SIZE`, so every frame of the current
+/// stack lies within that many bytes above `rsp`.
+pub fn trace<F: FnMut(usize)>(f: F) {
+    let (rsp, rbp): (usize, usize);
+    unsafe {
+        asm!("mov $0, rsp; mov $1, rbp" : "=r"(rsp), "=r"(rbp) ::: "intel");
+        walk_frames(rbp, (rsp, rsp.saturating_add(STACK_SIZE)), f);
+    }
+}
+
+/// Calls `f` with the return address of each frame of a chain, starting at
+/// the frame pointed to by `rbp`
+///
+/// The walk stops at a null or implausible frame pointer, or after
+/// `MAX_FRAMES`. Frames must be 8 byte aligned, lie in the kernel half within
+/// `stack`, the bounds of the current kernel stack, and grow towards higher
+/// addresses, so a corrupt chain is never followed elsewhere nor loops.
+pub unsafe fn walk_frames<F: FnMut(usize)>(mut rbp: usize, stack: (usize, usize), mut f: F) {
+    let (low, high) = stack;
+    for _ in 0..MAX_FRAMES {
+        // the frame holds the caller's rbp and the return address
+        let inside = rbp >= low && rbp.checked_add(16).map_or(false, |end| end <= high);
+        if rbp < KERNEL_HALF || rbp & 7 != 0 || !inside {
+            return;
+        }
+        let frame = rbp as *const usize;
+        let (caller_rbp, return_addr) = (*frame, *frame.offset(1));
+        if return_addr == 0 {
+            return;
+        }
+        f(return_addr);
+        if caller_rbp <= rbp {
+            return;
+        }
+        rbp = caller_rbp;
+    }
+}
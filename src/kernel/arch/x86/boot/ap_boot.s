@@ -0,0 +1,94 @@
+; Application processor trampoline
+;
+; An application processor begins executing in real mode at the page given by
+; the startup IPI. `smp::initialize()` copies everything from
+; `ap_trampoline_start` to `ap_trampoline_end` to AP_TRAMPOLINE and fills in
+; the parameters at its end. The trampoline passes through protected mode
+; straight into long mode using the kernel page tables, which must identity
+; map it, then calls the entry point on the given stack.
+;
+; AP_TRAMPOLINE and the parameters must match `TRAMPOLINE` and
+; `TrampolineParams` in smp.rs.
+
+%define AP_TRAMPOLINE 0x8000
+; address of a label once the trampoline is copied
+%define ADDR(label) (AP_TRAMPOLINE + (label) - ap_trampoline_start)
+
+global ap_trampoline_start
+global ap_trampoline_end
+
+section .ap_trampoline
+bits 16
+ap_trampoline_start:
+    cli
+    cld
+    xor ax, ax
+    mov ds, ax
+    lgdt [ADDR(gdt.pointer)]
+
+    mov eax, cr0
+    or eax, 1 ; protection enable
+    mov cr0, eax
+    jmp dword gdt.code32:ADDR(protected_mode)
+
+bits 32
+protected_mode:
+    mov ax, gdt.data
+    mov ds, ax
+    mov es, ax
+    mov ss, ax
+
+    ; use the control registers of the bootstrap processor, which include
+    ; PAE, long mode and paging
+    mov eax, [ADDR(params.cr4)]
+    mov cr4, eax
+    mov eax, [ADDR(params.cr3)]
+    mov cr3, eax
+    mov ecx, 0xC0000080 ; EFER
+    mov eax, [ADDR(params.efer)]
+    mov edx, [ADDR(params.efer) + 4]
+    wrmsr
+    mov eax, [ADDR(params.cr0)]
+    mov cr0, eax ; enabling paging activates long mode
+    jmp gdt.code64:ADDR(long_mode)
+
+bits 64
+long_mode:
+    mov ax, gdt.data
+    mov ds, ax
+    mov es, ax
+    mov ss, ax
+
+    mov rsp, [ADDR(params.stack)]
+    mov rdi, [ADDR(params.arg)]
+    mov rax, [ADDR(params.entry)]
+    call rax
+.hang:
+    hlt
+    jmp .hang
+
+; the kernel segments are at the same offsets as in the kernel GDT, so they
+; remain valid once it is loaded
+align 8
+gdt:
+    dq 0
+.code64: equ $ - gdt
+    dq 0x00af9a000000ffff ; 64 bit code
+.data: equ $ - gdt
+    dq 0x00cf92000000ffff ; flat data
+.code32: equ $ - gdt
+    dq 0x00cf9a000000ffff ; flat 32 bit code
+.pointer:
+    dw $ - gdt - 1
+    dd ADDR(gdt)
+
+align 8
+params:
+.cr3:   dq 0
+.cr4:   dq 0
+.efer:  dq 0
+.cr0:   dq 0
+.stack: dq 0
+.entry: dq 0
+.arg:   dq 0
+ap_trampoline_end:
@@ -121,6 +121,11 @@ set_up_page_tables:
     mov [p4_table], eax
     mov [p4_table + 511*8], eax
 
+    ; map the level 4 table into itself (see RECURSIVE_INDEX in paging.rs)
+    mov eax, p4_table
+    or eax, 0b11 ; present + writable
+    mov [p4_table + 510*8], eax
+
     ; Because two entries in level 4 table point to this table,
     ; there are 4 extraneous mappings. The comments below describe
     ; the intended / useful mappings
@@ -0,0 +1,79 @@
+//! Buffers for Direct Memory Access
+//!
+//! Devices performing DMA address memory physically, while the driver
+//! programming them works with virtual addresses. A `DmaBuffer` owns a run of
+//! physically contiguous frames and knows both addresses of the buffer.
+//!
+//! The buffer is accessed through the physical memory window, which maps it
+//! write back like all RAM. DMA on x86 snoops the caches, so neither side
+//! sees stale data, and mapping the frames again with another memory type
+//! would be undefined.
+
+use core::slice;
+
+use super::frame_allocator::{frame_alloc_contiguous, frame_free, Frame, PAGE_SIZE};
+use super::paging::{phys_to_virt, PHYS_WINDOW_SIZE};
+
+/// Physically contiguous memory shared with a device
+pub struct DmaBuffer {
+    paddr: usize,
+    vaddr: usize,
+    pages: usize,
+}
+
+impl DmaBuffer {
+    /// Allocates a zeroed buffer of at least `size` bytes
+    ///
+    /// The size is rounded up to whole pages. Returns None if no run of
+    /// contiguous frames that long is free within the physical memory window.
+    pub fn new(size: usize) -> Option<DmaBuffer> {
+        assert!(size > 0, "Empty DMA buffer");
+        let pages = (size + PAGE_SIZE - 1) / PAGE_SIZE;
+        let paddr = frame_alloc_contiguous(pages, 1)?.next().unwrap().addr();
+        let mut buffer = DmaBuffer { paddr: paddr, vaddr: 0, pages: pages };
+        if paddr + pages * PAGE_SIZE > PHYS_WINDOW_SIZE {
+            // the lowest free run, so none lies within the window. Dropping
+            // the buffer frees it.
+            return None;
+        }
+        buffer.vaddr = phys_to_virt(paddr);
+        for byte in buffer.as_mut_slice() {
+            *byte = 0;
+        }
+        Some(buffer)
+    }
+
+    /// Returns the address devices access the buffer at
+    pub fn phys_addr(&self) -> usize {
+        self.paddr
+    }
+
+    /// Returns the address the kernel accesses the buffer at
+    pub fn virt_addr(&self) -> usize {
+        self.vaddr
+    }
+
+    /// Returns the size of the buffer in bytes
+    pub fn len(&self) -> usize {
+        self.pages * PAGE_SIZE
+    }
+
+    pub fn as_slice(&self) -> &[u8] {
+        unsafe { slice::from_raw_parts(self.vaddr as *const u8, self.len()) }
+    }
+
+    pub fn as_mut_slice(&mut self) -> &mut [u8] {
+        unsafe { slice::from_raw_parts_mut(self.vaddr as *mut u8, self.len()) }
+    }
+}
+
+impl Drop for DmaBuffer {
+    /// Frees the frames of the buffer
+    ///
+    /// The device must no longer be using the buffer.
+    fn drop(&mut self) {
+        for page in 0..self.pages {
+            frame_free(Frame::containing(self.paddr).offset(page));
+        }
+    }
+}
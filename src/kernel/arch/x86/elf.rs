@@ -0,0 +1,261 @@
+//! ELF Program Loading
+//!
+//! Userspace programs are distributed as ELF64 executables. Like the kernel
+//! image described by the multiboot ELF sections, an executable is split into
+//! sections, but the loader only needs its program headers. Every `PT_LOAD`
+//! segment is copied into freshly allocated frames of a new `AddressSpace`
+//! and mapped at the virtual address requested by the executable.
+//!
+//! The initial program is loaded by GRUB as the first boot module.
+
+use core;
+use core::mem::size_of;
+
+use super::address_space::{AddressSpace, USER_END};
+use super::frame_allocator::{frame_alloc_zeroed, PAGE_SIZE};
+use super::paging::{nx_enabled, with_temporary_map, PageFlags, NO_EXECUTE, USER, WRITE};
+use super::scheduler;
+use super::task::TaskId;
+
+/// The address just above the initial user stack
+pub const USER_STACK_TOP:  usize = 0x0000_7fff_ffff_0000;
+/// The size in bytes of the initial user stack
+pub const USER_STACK_SIZE: usize = 16 * 1024;
+
+const ELF_MAGIC: [u8; 4] = [0x7f, b'E', b'L', b'F'];
+const ELFCLASS64: u8 = 2;
+const ELFDATA2LSB: u8 = 1;
+const ET_EXEC: u16 = 2;
+const EM_X86_64: u16 = 0x3e;
+
+const PT_LOAD: u32 = 1;
+const PF_X: u32 = 1 << 0;
+const PF_W: u32 = 1 << 1;
+
+/// Reasons an executable may be rejected
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum ElfError {
+    /// The image does not begin with an ELF header
+    NotElf,
+    /// The image is not a little endian x86-64 binary
+    NotX86_64,
+    /// The image is not a statically linked executable
+    NotExecutable,
+    /// A header or segment extends past the end of the image
+    Truncated,
+    /// A segment cannot be mapped in userspace
+    BadSegment,
+}
+
+/// The ELF64 file header
+#[repr(C)]
+#[derive(Copy, Clone, Debug)]
+pub struct ElfHeader {
+    e_ident:     [u8; 16],
+    e_type:      u16,
+    e_machine:   u16,
+    e_version:   u32,
+    e_entry:     u64,
+    e_phoff:     u64,
+    e_shoff:     u64,
+    e_flags:     u32,
+    e_ehsize:    u16,
+    e_phentsize: u16,
+    e_phnum:     u16,
+    e_shentsize: u16,
+    e_shnum:     u16,
+    e_shstrndx:  u16,
+}
+
+/// The ELF64 program header describing a segment
+#[repr(C)]
+#[derive(Copy, Clone, Debug)]
+pub struct ProgramHeader {
+    p_type:   u32,
+    p_flags:  u32,
+    p_offset: u64,
+    p_vaddr:  u64,
+    p_paddr:  u64,
+    p_filesz: u64,
+    p_memsz:  u64,
+    p_align:  u64,
+}
+
+/// A validated ELF64 executable
+pub struct ElfFile<'a> {
+    image:  &'a [u8],
+    header: ElfHeader,
+}
+
+/// An executable loaded into its own address space, ready to run
+pub struct Program {
+    space: AddressSpace,
+    entry: usize,
+}
+
+impl<'a> ElfFile<'a> {
+    /// Validates the file header of an image
+    pub fn parse(image: &'a [u8]) -> Result<ElfFile<'a>, ElfError> {
+        if image.len() < size_of::<ElfHeader>() || image[0..4] != ELF_MAGIC {
+            return Err(ElfError::NotElf);
+        }
+        let header = unsafe { core::ptr::read_unaligned(image.as_ptr() as *const ElfHeader) };
+
+        if header.e_ident[4] != ELFCLASS64 || header.e_ident[5] != ELFDATA2LSB
+            || header.e_machine != EM_X86_64 {
+            return Err(ElfError::NotX86_64);
+        }
+        if header.e_type != ET_EXEC || header.e_entry as usize >= USER_END {
+            return Err(ElfError::NotExecutable);
+        }
+
+        let table_size = header.e_phnum as usize * size_of::<ProgramHeader>();
+        let table_end = (header.e_phoff as usize).checked_add(table_size);
+        if header.e_phentsize as usize != size_of::<ProgramHeader>()
+            || table_end.map(|end| end > image.len()).unwrap_or(true) {
+            return Err(ElfError::Truncated);
+        }
+
+        Ok(ElfFile { image: image, header: header })
+    }
+
+    /// Returns the address of the first instruction
+    pub fn entry(&self) -> usize {
+        self.header.e_entry as usize
+    }
+
+    /// Returns the number of program headers
+    pub fn num_program_headers(&self) -> usize {
+        self.header.e_phnum as usize
+    }
+
+    /// Returns the specified program header
+    pub fn program_header(&self, index: usize) -> ProgramHeader {
+        assert!(index < self.num_program_headers());
+        let offset = self.header.e_phoff as usize + index * size_of::<ProgramHeader>();
+        let ptr = self.image[offset..].as_ptr() as *const ProgramHeader;
+        unsafe { core::ptr::read_unaligned(ptr) }
+    }
+
+    /// Returns the program headers of loadable segments
+    pub fn load_segments<'b>(&'b self) -> impl Iterator<Item = ProgramHeader> + 'b {
+        (0..self.num_program_headers())
+            .map(move |i| self.program_header(i))
+            .filter(|ph| ph.p_type == PT_LOAD)
+    }
+}
+
+impl ProgramHeader {
+    /// Return virtual address of the segment
+    pub fn start(&self) -> usize {
+        self.p_vaddr as usize
+    }
+
+    /// Return size of the segment in memory
+    pub fn mem_size(&self) -> usize {
+        self.p_memsz as usize
+    }
+
+    /// Return size of the segment in the file
+    pub fn file_size(&self) -> usize {
+        self.p_filesz as usize
+    }
+
+    /// Return offset of the segment in the file
+    pub fn offset(&self) -> usize {
+        self.p_offset as usize
+    }
+
+    /// Translates the segment permissions into page flags
+    ///
+    /// Segments are always readable. Without NX support every segment is
+    /// also executable.
+    pub fn page_flags(&self) -> PageFlags {
+        let mut flags = USER;
+        if self.p_flags & PF_W != 0 {
+            flags.insert(WRITE);
+        }
+        if self.p_flags & PF_X == 0 && nx_enabled() {
+            flags.insert(NO_EXECUTE);
+        }
+        flags
+    }
+
+    /// Checks the segment lies within the image and the lower half
+    pub fn validate(&self, image_size: usize) -> Result<(), ElfError> {
+        let file_end = self.offset().checked_add(self.file_size());
+        if file_end.map(|end| end > image_size).unwrap_or(true) {
+            return Err(ElfError::Truncated);
+        }
+        if self.mem_size() == 0 || self.file_size() > self.mem_size()
+            || self.start() >= USER_END || self.mem_size() > USER_END - self.start() {
+            return Err(ElfError::BadSegment);
+        }
+        Ok(())
+    }
+}
+
+/// Loads an executable into a new address space
+pub fn load(image: &[u8]) -> Result<Program, ElfError> {
+    let elf = ElfFile::parse(image)?;
+    for segment in elf.load_segments() {
+        segment.validate(image.len())?;
+    }
+
+    let mut space = AddressSpace::new();
+    for segment in elf.load_segments() {
+        load_segment(&mut space, image, &segment)?;
+    }
+    // the stack is populated on demand by the page fault handler
+    space.add_region(USER_STACK_TOP - USER_STACK_SIZE, USER_STACK_SIZE, WRITE)
+         .expect("Stack overlaps program segments");
+
+    Ok(Program { space: space, entry: elf.entry() })
+}
+
+/// Copies a validated segment into newly allocated frames
+fn load_segment(space: &mut AddressSpace, image: &[u8], segment: &ProgramHeader)
+                -> Result<(), ElfError> {
+    let page_start = segment.start() & !(PAGE_SIZE - 1);
+    let mem_end  = segment.start() + segment.mem_size();
+    let file_end = segment.start() + segment.file_size();
+
+    let region = space.add_region(page_start, mem_end - page_start, segment.page_flags())
+                      .ok_or(ElfError::BadSegment)?;
+
+    let mut page = region.start;
+    while page < region.end {
+        let frame = frame_alloc_zeroed();
+
+        // copy the part of the file overlapping this page
+        let low  = page.max(segment.start());
+        let high = (page + PAGE_SIZE).min(file_end);
+        if low < high {
+            let offset = segment.offset() + (low - segment.start());
+            // the frame may lie beyond the physical memory window
+            with_temporary_map(frame.addr(), |vaddr| {
+                let contents = unsafe {
+                    core::slice::from_raw_parts_mut(vaddr as *mut u8, PAGE_SIZE)
+                };
+                contents[low - page..high - page]
+                    .copy_from_slice(&image[offset..offset + (high - low)]);
+            });
+        }
+
+        space.pt4().map_to_4k(page, frame.addr(), region.flags);
+        page += PAGE_SIZE;
+    }
+    Ok(())
+}
+
+impl Program {
+    /// Returns the address of the first instruction
+    pub fn entry(&self) -> usize {
+        self.entry
+    }
+
+    /// Adds the program to the run queue as a new task
+    pub fn spawn(self) -> TaskId {
+        scheduler::spawn(self.space, self.entry, USER_STACK_TOP)
+    }
+}
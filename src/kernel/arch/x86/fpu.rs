@@ -0,0 +1,117 @@
+//! Floating Point State
+//!
+//! The kernel itself uses SSE registers, as LLVM emits SSE instructions for
+//! ordinary copies, so the x87/MMX/SSE state of a task would be clobbered by
+//! any system call or interrupt. Every task owns an `FpuState`, and the one
+//! of the running task is pointed to by `USER_FPU`. Entry points from
+//! userspace save the state there with `fxsave` and restore it before
+//! returning, and switching tasks restores that of the next task.
+//!
+//! Interrupts of kernel code instead save the state on the stack, so kernel
+//! code interrupted while using SSE registers is unaffected as well.
+//!
+//! `xsave` would be needed to preserve AVX state, but the kernel never sets
+//! CR4.OSXSAVE, so userspace cannot use AVX and `fxsave` is sufficient.
+
+use core::ptr;
+
+use super::intrinsics::{fxrstor, fxsave, get_cpuid, read_cr0, read_cr4, write_cr0, write_cr4};
+
+/// Size in bytes of the area written by `fxsave`
+pub const FXSAVE_SIZE: usize = 512;
+/// Alignment in bytes required by `fxsave`
+pub const FXSAVE_ALIGN: usize = 16;
+
+/// Offset of the x87 control word in the `fxsave` area
+const FCW_OFFSET: usize = 0;
+/// Offset of MXCSR in the `fxsave` area
+const MXCSR_OFFSET: usize = 24;
+/// x87 control word after `fninit`, masking all exceptions
+const DEFAULT_FCW: u16 = 0x037f;
+/// MXCSR after reset, masking all exceptions
+const DEFAULT_MXCSR: u32 = 0x1f80;
+
+/// CR0.MP, making `wait` honour CR0.TS
+pub const CR0_MP: usize = 1 << 1;
+/// CR0.EM, making every x87 and SSE instruction raise #UD or #NM
+pub const CR0_EM: usize = 1 << 2;
+/// CR4.OSFXSR, enabling SSE and `fxsave`
+pub const CR4_OSFXSR: usize = 1 << 9;
+/// CR4.OSXMMEXCPT, reporting SSE exceptions with #XM rather than #UD
+pub const CR4_OSXMMEXCPT: usize = 1 << 10;
+
+/// The area the state of the running task is saved to on kernel entry
+///
+/// Set by `set_current()`. Points at a throwaway state from `initialize()`
+/// until the first task runs.
+pub static mut USER_FPU: *mut FpuState = 0 as *mut FpuState;
+
+/// Save area for entries from userspace before any task exists
+static mut BOOT_FPU: FpuState = FpuState::zero();
+
+/// A saved x87/MMX/SSE state
+#[repr(C, align(16))]
+pub struct FpuState {
+    area: [u8; FXSAVE_SIZE],
+}
+
+impl FpuState {
+    const fn zero() -> FpuState {
+        FpuState { area: [0; FXSAVE_SIZE] }
+    }
+
+    /// Returns the state of a newly initialized FPU, with all exceptions masked
+    pub fn new() -> FpuState {
+        let mut state = FpuState::zero();
+        unsafe {
+            let area = state.area.as_mut_ptr();
+            ptr::write_unaligned(area.offset(FCW_OFFSET as isize) as *mut u16, DEFAULT_FCW);
+            ptr::write_unaligned(area.offset(MXCSR_OFFSET as isize) as *mut u32, DEFAULT_MXCSR);
+        }
+        state
+    }
+
+    /// Saves the current state into this area
+    pub fn save(&mut self) {
+        unsafe { fxsave(self.area.as_mut_ptr()); }
+    }
+
+    /// Loads the state saved in this area
+    pub fn restore(&self) {
+        unsafe { fxrstor(self.area.as_ptr()); }
+    }
+}
+
+/// Returns CR0 adjusted to allow SSE
+pub fn sse_cr0(cr0: usize) -> usize {
+    (cr0 & !CR0_EM) | CR0_MP
+}
+
+/// Returns CR4 adjusted to allow SSE
+pub fn sse_cr4(cr4: usize) -> usize {
+    cr4 | CR4_OSFXSR | CR4_OSXMMEXCPT
+}
+
+/// Enables SSE instructions
+///
+/// LLVM emits SSE instructions in any function, so boot32.s already does this
+/// before entering Rust code. This repeats it with a CPUID check, for any
+/// other path into the kernel.
+pub fn enable_sse() {
+    let cpuid = get_cpuid();
+    assert!(cpuid.sse() && cpuid.sse2() && cpuid.fxsr(), "processor does not support SSE2");
+    write_cr0(sse_cr0(read_cr0()));
+    write_cr4(sse_cr4(read_cr4()));
+}
+
+/// Sets up the save area used before the first task runs
+pub fn initialize() {
+    unsafe { set_current(&mut BOOT_FPU); }
+}
+
+/// Makes `state` the area userspace state is saved to and restored from
+///
+/// The area must live until another is set. Used when switching tasks.
+pub fn set_current(state: &mut FpuState) {
+    unsafe { USER_FPU = state; }
+}
@@ -3,26 +3,46 @@
 //! A `Frame` contains the physical memory that may be mapped by a virtual
 //! page. We are given a memory map from the `MultibootInfo`. This defines the
 //! regions of memory that are safe for use. Currently we are only concerned
-//! with a unique allocation of frames. Reuse is unsupported.  A frame is valid
-//! if it is page aligned, in a free memory region, and it is does not overlap
-//! a protected region. Protected regions are used to avoid overwriting certain
-//! structures until a better memory mapping can be established.
+//! with a unique allocation of frames. A frame is valid if it is page aligned,
+//! in a free memory region, and it is does not overlap a protected region.
+//! Protected regions are used to avoid overwriting certain structures until a
+//! better memory mapping can be established.
+//!
+//! Which frames are in use is recorded in a `BitmapFrameAllocator`, which
+//! lives in free frames within the physical memory window, whether or not the
+//! managed region does. Frames are always handed out lowest first, and runs
+//! of frames are found by scanning it.
+//!
+//! Frames may be shared between several mappings, so each frame has a
+//! reference count. A frame is marked free once its last reference is
+//! dropped. The reference counts are stored alongside the bitmap, as a table
+//! of every managed frame would not fit on the heap.
 
 use core;
-use spin::{Mutex, MutexGuard};
-use super::multiboot::MMapEntry;
+use core::slice;
+
+use crate::sync::{Once, SpinLock, SpinLockGuard};
+use super::frame_bitmap::BitmapFrameAllocator;
+use super::multiboot::{self, MMapEntry};
+use super::paging::{phys_to_virt, with_temporary_map, PHYS_WINDOW_SIZE};
 
 /// The size in bytes of a normal page
 pub const PAGE_SIZE: usize = 4096;
+/// The size in bytes of a page mapped by a PT2 entry
+pub const HUGE_PAGE_SIZE: usize = 512 * PAGE_SIZE;
+
+/// Whether freed frames are zeroed by default, see `set_zero_on_free()`
+pub const ZERO_ON_FREE: bool = true;
 
 /// Defines a the first and last byte of a region
 pub type MemRegion = (usize, usize);
 
 /// Regions of physical memory which cannot be allocated
 ///
-/// This is intended to reserve physical memory from the kernel image and
-/// multiboot info structure. The relevant values must be supplied at run time.
-pub type ProtectedRegions = [MemRegion; 2];
+/// This is intended to reserve physical memory from the kernel image, the
+/// multiboot info structure, boot modules and the AP trampoline. The relevant
+/// values must be supplied at run time.
+pub type ProtectedRegions = [MemRegion; 4];
 
 /// A simplistic frame allocator that provides access to a supply of
 /// unique frames.
@@ -30,9 +50,38 @@ pub type ProtectedRegions = [MemRegion; 2];
 /// A list of "protected regions" may be supplied. No frames provided
 /// will overlap with these regions.
 pub struct FrameAllocator {
-    start: usize,
+    /// Address of the last frame managed by this allocator
     end:   usize,
+    /// Index of the first frame managed by this allocator
+    base:  usize,
     protected_regions: ProtectedRegions,
+    /// Which of the managed frames are in use
+    bitmap: BitmapFrameAllocator,
+    refcounts: FrameRefCount,
+    /// Scrub frames as they are freed
+    zero_on_free: bool,
+}
+
+/// The number of references to each frame managed by the allocator
+///
+/// Frames outside the table are not reference counted.
+pub struct FrameRefCount {
+    /// Index of the frame described by the first count
+    base:   usize,
+    counts: &'static mut [u16],
+}
+
+/// Totals of the physical memory managed by a `FrameAllocator`, in bytes
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct MemorySummary {
+    /// Memory in the region managed by the allocator
+    pub total: usize,
+    /// Memory in protected regions, which is never allocated
+    pub reserved: usize,
+    /// Memory currently allocated, including any of the allocator's own tables
+    pub allocated: usize,
+    /// Memory available for allocation
+    pub free: usize,
 }
 
 /// A unique reference to a physical memory page.
@@ -41,70 +90,323 @@ pub struct Frame {
     index: usize,
 }
 
+/// An inclusive range of frames, iterated in ascending order
+#[derive(Debug)]
+pub struct FrameRange {
+    start: usize,
+    end:   usize,
+}
+
 impl FrameAllocator {
     pub fn new(mem_regions: &'static [MMapEntry],
                protected_regions: ProtectedRegions) -> FrameAllocator {
-        let free_region = mem_regions.iter().filter(|r| r.is_free())
+        let free_region = multiboot::iter_free(mem_regions)
                                      .max_by_key(|r| r.size())
                                      .expect("No usable memory");
 
-        let allocator = FrameAllocator {
-            start: Frame::after(free_region.start()).addr(),
-            end: Frame::containing(free_region.end()).addr(),
+        // only whole frames within the region are managed
+        let start = Frame::after(free_region.start());
+        let frames = Frame::containing(free_region.end() + 1).index - start.index;
+        // the bitmap is followed by the reference counts
+        let bitmap_pages = BitmapFrameAllocator::storage_pages(frames);
+        let pages = bitmap_pages + FrameRefCount::storage_pages(frames);
+        let storage = find_storage(mem_regions, &protected_regions, pages)
+            .expect("No memory for the frame bitmap");
+        let bitmap = BitmapFrameAllocator::new(start.index, frames, storage, &protected_regions);
+        let refcounts = unsafe {
+            FrameRefCount::new(start.index, frames, storage.0 + bitmap_pages * PAGE_SIZE)
+        };
+        let mut allocator = FrameAllocator {
+            end: (start.index + frames - 1) * PAGE_SIZE,
+            base: start.index,
             protected_regions: protected_regions,
+            bitmap: bitmap,
+            refcounts: refcounts,
+            zero_on_free: ZERO_ON_FREE,
         };
+        // frames in use from the start have a single owner
+        for index in start.index..start.index + frames {
+            let frame = Frame { index: index };
+            if !allocator.bitmap.is_free(&frame) {
+                allocator.refcounts.set(&frame, 1);
+            }
+        }
         allocator
     }
 
     /// Allocate a unique Frame
     pub fn alloc(&mut self) -> Frame {
-        'verify_frame: loop {
-            let next_page = self.next_page().expect("Out of memory");
-            for region in &self.protected_regions {
-                let start = Frame::containing(region.0);
-                let end   = Frame::containing(region.1);
-
-                if next_page >= start && next_page <= end {
-                    continue 'verify_frame;
-                }
-            }
+        let frame = self.bitmap.alloc().expect("Out of memory");
+        self.refcounts.set(&frame, 1);
+        frame
+    }
+
+    /// Allocate a unique Frame lying entirely below `max_addr`
+    ///
+    /// For devices which cannot address all of physical memory, such as ISA
+    /// DMA below 16 MiB. Returns None if no such frame is free.
+    pub fn alloc_in_range(&mut self, max_addr: usize) -> Option<Frame> {
+        let frame = self.bitmap.alloc_below(max_addr / PAGE_SIZE)?;
+        self.refcounts.set(&frame, 1);
+        Some(frame)
+    }
+
+    /// Allocate `count` physically contiguous frames
+    ///
+    /// The first frame is aligned to `align` frames, which must be a power of
+    /// two. Returns None if no such run is free.
+    pub fn alloc_contiguous(&mut self, count: usize, align: usize) -> Option<FrameRange> {
+        let run = self.bitmap.alloc_contiguous(count, align)?;
+        for index in run.start..run.end + 1 {
+            self.refcounts.set(&Frame { index: index }, 1);
+        }
+        Some(run)
+    }
+
+    /// Allocate `HUGE_PAGE_SIZE` bytes of contiguous, aligned frames
+    ///
+    /// Returns the first frame of the run, or None if no such run is free.
+    pub fn alloc_huge(&mut self) -> Option<Frame> {
+        let pages = HUGE_PAGE_SIZE / PAGE_SIZE;
+        self.alloc_contiguous(pages, pages).and_then(|mut run| run.next())
+    }
+
+    /// Is this frame usable memory managed by this allocator?
+    ///
+    /// Valid frames lie within the free memory region selected at
+    /// initialization and do not overlap a protected region. Anything else may
+    /// be device memory, firmware data, or nonexistent.
+    pub fn is_valid(&self, frame: &Frame) -> bool {
+        frame.index >= self.base && frame.addr() <= self.end && !self.is_protected(frame)
+    }
+
+    /// Does this frame overlap a protected region?
+    fn is_protected(&self, frame: &Frame) -> bool {
+        self.protected_regions.iter().any(|region| {
+            let start = Frame::containing(region.0);
+            let end   = Frame::containing(region.1);
+            *frame >= start && *frame <= end
+        })
+    }
+
+    /// Deallocate every frame in a range with a single lock acquisition
+    ///
+    /// The caller must own every frame in the range. Reference counts are
+    /// ignored, as with `free()`.
+    pub fn free_range(&mut self, range: FrameRange) {
+        for frame in range {
+            self.free(frame);
+        }
+    }
+
+    /// Enables or disables zeroing frames when they are freed
+    ///
+    /// Zeroing prevents stale kernel data leaking into later allocations.
+    /// Frames beyond the physical memory window are zeroed through
+    /// `with_temporary_map()`, so none may be freed while it is in use.
+    pub fn set_zero_on_free(&mut self, enabled: bool) {
+        self.zero_on_free = enabled;
+    }
+
+    /// Deallocate a Frame, regardless of any remaining references
+    ///
+    /// Prefer `decref()` for frames which may be shared.
+    pub fn free(&mut self, mut frame: Frame) {
+        debug_assert!(self.is_valid(&frame), "Freeing invalid frame {:#x}", frame.addr());
+        if self.zero_on_free {
+            frame.zero();
+        }
+        self.refcounts.set(&frame, 0);
+        self.bitmap.free(&frame);
+    }
 
-            return next_page
+    /// Adds a reference to a frame shared by another mapping
+    pub fn incref(&mut self, frame: &Frame) {
+        self.refcounts.incref(frame)
+    }
+
+    /// Drops a reference to a frame
+    ///
+    /// Returns true if this was the last reference, in which case the frame
+    /// has been freed.
+    pub fn decref(&mut self, frame: Frame) -> bool {
+        if self.refcounts.decref(&frame) {
+            self.free(frame);
+            true
+        } else {
+            false
         }
     }
 
-    /// Deallocate a Frame. Currently NYI.
-    pub fn free(&mut self, _: Frame) {
-        // TODO NYI
+    /// Returns the number of references to a frame
+    pub fn refcount(&self, frame: &Frame) -> u16 {
+        self.refcounts.get(frame)
+    }
+
+    /// Returns the reference count table
+    pub fn refcounts(&self) -> &FrameRefCount {
+        &self.refcounts
     }
 
-    /// Approximate the remaining number of pages.
-    /// Does not consider protected regions.
+    /// Returns how the managed memory is currently used
+    pub fn memory_summary(&self) -> MemorySummary {
+        let first = self.base * PAGE_SIZE;
+        let total = self.end + PAGE_SIZE - first;
+        // frames of protected regions within the given range
+        let protected_in = |start: usize, end: usize| -> usize {
+            self.protected_regions.iter()
+                .filter(|region| region.1 >= region.0)
+                .map(|region| {
+                    let first = Frame::containing(region.0).addr().max(start);
+                    let last = Frame::containing(region.1).addr().min(end);
+                    if first <= last { (last - first) / PAGE_SIZE + 1 } else { 0 }
+                })
+                .sum()
+        };
+
+        let reserved = protected_in(first, self.end) * PAGE_SIZE;
+        let free = self.bitmap.free_frames() * PAGE_SIZE;
+        MemorySummary {
+            total: total,
+            reserved: reserved,
+            allocated: total.saturating_sub(reserved + free),
+            free: free,
+        }
+    }
+
+    /// Returns the number of free pages
     pub fn free_pages(&self) -> usize {
-        (self.end - self.start) / PAGE_SIZE + 1
+        self.bitmap.free_frames()
+    }
+}
+
+
+impl FrameRefCount {
+    /// Returns the number of pages of storage a table of `frames` frames needs
+    pub fn storage_pages(frames: usize) -> usize {
+        (frames * 2 + PAGE_SIZE - 1) / PAGE_SIZE
+    }
+
+    /// Creates a table of `frames` frames starting at the specified frame
+    /// index, none of which have references
+    ///
+    /// Unsafe since the table is stored at `paddr`, which must be followed by
+    /// `storage_pages()` pages of otherwise unused memory within the physical
+    /// memory window.
+    pub unsafe fn new(base: usize, frames: usize, paddr: usize) -> FrameRefCount {
+        let counts = slice::from_raw_parts_mut(phys_to_virt(paddr) as *mut u16, frames);
+        for count in counts.iter_mut() {
+            *count = 0;
+        }
+        FrameRefCount {
+            base:   base,
+            counts: counts,
+        }
+    }
+
+    /// Is this frame covered by the table?
+    pub fn is_tracked(&self, frame: &Frame) -> bool {
+        frame.index.wrapping_sub(self.base) < self.counts.len()
+    }
+
+    /// Returns the number of references to a frame, zero if untracked
+    pub fn get(&self, frame: &Frame) -> u16 {
+        let index = frame.index.wrapping_sub(self.base);
+        self.counts.get(index).cloned().unwrap_or(0)
+    }
+
+    /// Sets the number of references to a frame, ignoring untracked frames
+    pub fn set(&mut self, frame: &Frame, count: u16) {
+        let index = frame.index.wrapping_sub(self.base);
+        if let Some(c) = self.counts.get_mut(index) {
+            *c = count;
+        }
+    }
+
+    /// Adds a reference to an allocated frame
+    pub fn incref(&mut self, frame: &Frame) {
+        let index = frame.index.wrapping_sub(self.base);
+        let count = self.counts.get_mut(index).expect("Frame not reference counted");
+        assert!(*count > 0, "Reference to unallocated frame");
+        *count = count.checked_add(1).expect("Frame reference count overflow");
+    }
+
+    /// Drops a reference to a frame
+    ///
+    /// Returns true if no references remain and the frame should be freed.
+    /// Untracked frames are assumed to have a single owner.
+    pub fn decref(&mut self, frame: &Frame) -> bool {
+        let index = frame.index.wrapping_sub(self.base);
+        match self.counts.get_mut(index) {
+            Some(count) => {
+                assert!(*count > 0, "Reference to unallocated frame");
+                *count -= 1;
+                *count == 0
+            }
+            None => true,
+        }
     }
+}
 
-    fn next_page(&mut self) -> Option<Frame> {
-        if self.start >= self.end { return None; }
-        let addr = self.start;
-        self.start += PAGE_SIZE;
-        Some(Frame::containing(addr))
+impl FrameRange {
+    /// Returns the number of frames remaining in the range
+    pub fn len(&self) -> usize {
+        if self.start > self.end { 0 } else { self.end - self.start + 1 }
     }
 }
 
+impl Iterator for FrameRange {
+    type Item = Frame;
+
+    fn next(&mut self) -> Option<Frame> {
+        if self.start > self.end {
+            return None;
+        }
+        let frame = Frame { index: self.start };
+        self.start += 1;
+        Some(frame)
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        (self.len(), Some(self.len()))
+    }
+}
 
 impl Frame {
+    /// Returns the frames from `start` through `end` inclusive
+    pub fn range(start: Frame, end: Frame) -> FrameRange {
+        FrameRange { start: start.index, end: end.index }
+    }
+
+    /// Returns the frames overlapping a region of physical memory
+    pub fn range_containing(region: MemRegion) -> FrameRange {
+        Frame::range(Frame::containing(region.0), Frame::containing(region.1))
+    }
+
     /// Get address to the start of this frame
     pub fn addr(&self) -> usize {
         self.index * PAGE_SIZE
     }
 
-    /// Fills frame with zeros. Requires the memory pointed by this frame to be
-    /// identity-mapped.
+    /// Fills frame with zeros
+    ///
+    /// Frames beyond the physical memory window mapped at `KERNEL_BASE` are
+    /// reached through `paging::with_temporary_map()`. In debug builds, the frame is checked to be valid RAM so device memory
+    /// is never overwritten. The frame allocator must not be locked.
     pub fn clear(&mut self) {
-        let ptr = self.addr() as *mut u8;
-        unsafe {
-            core::ptr::write_bytes(ptr, 0, PAGE_SIZE);
+        debug_assert!(get_fallocator().is_valid(self), "Clearing invalid frame {:#x}", self.addr());
+        self.zero();
+    }
+
+    /// Fills frame with zeros without validating it
+    fn zero(&mut self) {
+        let zero = |vaddr: usize| unsafe {
+            core::ptr::write_bytes(vaddr as *mut u8, 0, PAGE_SIZE);
+        };
+        if self.addr() < PHYS_WINDOW_SIZE {
+            zero(phys_to_virt(self.addr()));
+        } else {
+            with_temporary_map(self.addr(), zero);
         }
     }
 
@@ -112,7 +414,7 @@ impl Frame {
     /// ```
     /// Frame::containing(0x00FF).addr() // 0x0000
     /// ```
-    fn containing(addr: usize) -> Frame {
+    pub fn containing(addr: usize) -> Frame {
         Frame { index: addr / PAGE_SIZE }
     }
 
@@ -125,29 +427,109 @@ impl Frame {
     /// Frame::after(0x1000).addr() // 0x1000
     /// Frame::after(0x1001).addr() // 0x2000
     /// ```
-    fn after(addr: usize) -> Frame {
+    pub fn after(addr: usize) -> Frame {
         const MASK: usize = PAGE_SIZE - 1;
         let addr_rounded_up = (addr + MASK) & !MASK;
         Frame::containing(addr_rounded_up)
     }
+
+    /// Get the Frame `count` frames after this one
+    /// ```
+    /// Frame::containing(0x1000).offset(2).addr() // 0x3000
+    /// ```
+    pub fn offset(&self, count: usize) -> Frame {
+        Frame { index: self.index + count }
+    }
+
+    /// Get the Frame directly after this one
+    /// ```
+    /// Frame::containing(0x1000).next().addr() // 0x2000
+    /// ```
+    pub fn next(&self) -> Frame {
+        self.offset(1)
+    }
+}
+
+/// Finds `pages` contiguous free frames for the allocator's own tables
+///
+/// The tables are needed before the kernel page tables exist, so the frames
+/// must lie within the physical memory window, though not necessarily in the
+/// region the allocator manages. The first MiB is left to firmware and the AP
+/// trampoline. Returns the region spanned by the frames.
+fn find_storage(mem_regions: &'static [MMapEntry], protected_regions: &[MemRegion],
+                pages: usize) -> Option<MemRegion> {
+    const LOW_MEMORY: usize = 0x10_0000;
+    let protected = |index: usize| protected_regions.iter().any(|region| {
+        region.1 >= region.0 && index >= region.0 / PAGE_SIZE && index <= region.1 / PAGE_SIZE
+    });
+    for region in multiboot::iter_free(mem_regions) {
+        let first = Frame::after(region.start().max(LOW_MEMORY)).index;
+        let end = Frame::containing(region.end().min(PHYS_WINDOW_SIZE - 1) + 1).index;
+        let mut run = first; // first frame of the current run of usable frames
+        for index in first..end {
+            if protected(index) {
+                run = index + 1;
+            } else if index + 1 - run == pages {
+                return Some((run * PAGE_SIZE, (index + 1) * PAGE_SIZE - 1));
+            }
+        }
+    }
+    None
 }
 
-pub static mut FALLOCATOR: Option<Mutex<FrameAllocator>> = None;
+/// The frame allocator, only reached through `get_fallocator()`
+static FALLOCATOR: Once<SpinLock<FrameAllocator>> = Once::new();
 
+/// Creates the frame allocator over the largest free region of memory
+///
+/// Unsafe since every frame outside the protected regions is handed out, so
+/// they must cover everything still in use. Panics if called twice, before a
+/// second bitmap is written over the memory of the first.
 pub unsafe fn initialize(mem_regions: &'static [MMapEntry],
                          protected_regions: ProtectedRegions) {
+    assert!(FALLOCATOR.get().is_none(), "Frame allocator initialized twice");
     let fallocator = FrameAllocator::new(mem_regions, protected_regions);
-    core::mem::replace(&mut FALLOCATOR, Some(Mutex::new(fallocator)));
+    FALLOCATOR.initialize(SpinLock::new(fallocator));
 }
 
-pub fn get_fallocator<'a>() -> MutexGuard<'a, FrameAllocator> {
-    unsafe { FALLOCATOR.as_ref().unwrap().lock() }
+/// Returns the locked frame allocator
+///
+/// Panics if called before `initialize()`.
+pub fn get_fallocator<'a>() -> SpinLockGuard<'a, FrameAllocator> {
+    FALLOCATOR.expect("Frame allocator").lock()
 }
 
 pub fn frame_alloc() -> Frame {
     get_fallocator().alloc()
 }
 
+/// Allocate a Frame filled with zeros
+///
+/// See `Frame::clear()`. The frame allocator must not be locked, nor may this
+/// be called while a temporary mapping is in use.
+pub fn frame_alloc_zeroed() -> Frame {
+    let mut frame = frame_alloc();
+    frame.zero();
+    frame
+}
+
+pub fn frame_alloc_contiguous(count: usize, align: usize) -> Option<FrameRange> {
+    get_fallocator().alloc_contiguous(count, align)
+}
+
+pub fn frame_free_range(range: FrameRange) {
+    get_fallocator().free_range(range)
+}
+
+/// Drops a reference to a frame, reclaiming it once no references remain
 pub fn frame_free(frame: Frame) {
-    get_fallocator().free(frame)
+    get_fallocator().decref(frame);
+}
+
+pub fn frame_incref(frame: &Frame) {
+    get_fallocator().incref(frame)
+}
+
+pub fn frame_decref(frame: Frame) -> bool {
+    get_fallocator().decref(frame)
 }
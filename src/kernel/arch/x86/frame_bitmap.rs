@@ -0,0 +1,206 @@
+//! Bitmap of Used Frames
+//!
+//! One bit per frame records whether it is in use, so whether a frame is free
+//! is answered in constant time and runs of free frames, such as aligned huge
+//! pages, are found by scanning the bitmap. Allocation scans for the first
+//! clear bit from a hint below which every frame is known to be used.
+//!
+//! The bitmap is needed before the heap exists, so it is stored in frames
+//! chosen by the caller, accessed through `phys_to_virt()`. These may lie
+//! outside the frames described, which need not be within the physical
+//! memory window. Any of the storage that is described, along with any
+//! protected regions, is marked used at creation and never freed.
+
+use core::iter;
+use core::slice;
+
+use super::frame_allocator::{Frame, FrameRange, MemRegion, PAGE_SIZE};
+use super::paging::{phys_to_virt, PHYS_WINDOW_SIZE};
+
+/// Bits per word of the bitmap
+const BITS: usize = 64;
+
+/// Tracks which frames of a contiguous region of physical memory are in use
+pub struct BitmapFrameAllocator {
+    /// Index of the frame described by the first bit
+    base: usize,
+    /// Number of frames described
+    frames: usize,
+    /// A set bit marks a used frame. Bits past `frames` are always set.
+    words: &'static mut [u64],
+    /// Number of clear bits
+    free: usize,
+    /// Index of the first word which may contain a clear bit
+    hint: usize,
+}
+
+impl BitmapFrameAllocator {
+    /// Returns the number of pages of storage a bitmap of `frames` frames needs
+    pub fn storage_pages(frames: usize) -> usize {
+        let words = (frames + BITS - 1) / BITS;
+        (words * 8 + PAGE_SIZE - 1) / PAGE_SIZE
+    }
+
+    /// Creates a bitmap of `frames` frames beginning at frame index `base`
+    ///
+    /// The bitmap is stored at the start of `storage`, which must be page
+    /// aligned, within the physical memory window, and at least
+    /// `storage_pages()` long. All of `storage` is marked used.
+    pub fn new(base: usize, frames: usize, storage: MemRegion, protected_regions: &[MemRegion])
+               -> BitmapFrameAllocator {
+        let words = (frames + BITS - 1) / BITS;
+        let size = BitmapFrameAllocator::storage_pages(frames) * PAGE_SIZE;
+        assert!(storage.0 % PAGE_SIZE == 0 && storage.1 < PHYS_WINDOW_SIZE
+                    && storage.1 >= storage.0 && storage.1 + 1 - storage.0 >= size,
+                "Bad frame bitmap storage {:#x}-{:#x}", storage.0, storage.1);
+
+        let addr = phys_to_virt(storage.0) as *mut u64;
+        let mut bitmap = BitmapFrameAllocator {
+            base: base,
+            frames: frames,
+            words: unsafe { slice::from_raw_parts_mut(addr, words) },
+            free: frames,
+            hint: 0,
+        };
+        for word in bitmap.words.iter_mut() {
+            *word = 0;
+        }
+        if frames % BITS != 0 {
+            bitmap.words[words - 1] = !0 << (frames % BITS);
+        }
+
+        let reserved = iter::once(&storage).chain(protected_regions.iter());
+        for region in reserved.filter(|region| region.1 >= region.0) {
+            let first = (region.0 / PAGE_SIZE).max(base) - base;
+            let end = (region.1 / PAGE_SIZE + 1).min(base + frames).saturating_sub(base);
+            bitmap.set_range(first, end, true);
+        }
+        bitmap
+    }
+
+    /// Returns the number of frames described, used or not
+    pub fn frames(&self) -> usize {
+        self.frames
+    }
+
+    /// Returns the number of free frames
+    pub fn free_frames(&self) -> usize {
+        self.free
+    }
+
+    /// Is this frame described by the bitmap and free?
+    pub fn is_free(&self, frame: &Frame) -> bool {
+        match self.bit(frame) {
+            Some(bit) => self.words[bit / BITS] & (1 << (bit % BITS)) == 0,
+            None => false,
+        }
+    }
+
+    /// Allocates the lowest free frame
+    pub fn alloc(&mut self) -> Option<Frame> {
+        let bit = self.first_free(self.hint * BITS)?;
+        self.set(bit, true);
+        self.hint = bit / BITS;
+        Some(self.frame(bit))
+    }
+
+    /// Allocates the lowest free frame below frame index `limit`
+    pub fn alloc_below(&mut self, limit: usize) -> Option<Frame> {
+        let bit = self.first_free(self.hint * BITS)?;
+        if self.base + bit >= limit {
+            return None;
+        }
+        self.set(bit, true);
+        self.hint = bit / BITS;
+        Some(self.frame(bit))
+    }
+
+    /// Allocates `count` contiguous frames, the first a multiple of `align`
+    /// frames into physical memory
+    ///
+    /// `align` must be a power of two. Returns the lowest such run, or None
+    /// if there is none.
+    pub fn alloc_contiguous(&mut self, count: usize, align: usize) -> Option<FrameRange> {
+        assert!(count > 0, "Empty contiguous allocation");
+        assert!(align.is_power_of_two(), "Alignment {} not a power of two", align);
+        let mut start = self.first_free(self.hint * BITS)?;
+        loop {
+            let aligned = ((self.base + start + align - 1) & !(align - 1)) - self.base;
+            let end = aligned.checked_add(count)?;
+            if end > self.frames {
+                return None;
+            }
+            match self.first_used(aligned, end) {
+                None => {
+                    self.set_range(aligned, end, true);
+                    return Some(Frame::range(self.frame(aligned), self.frame(end - 1)));
+                }
+                Some(used) => start = self.first_free(used + 1)?,
+            }
+        }
+    }
+
+    /// Marks a frame free
+    ///
+    /// Frames outside the bitmap are ignored.
+    pub fn free(&mut self, frame: &Frame) {
+        if let Some(bit) = self.bit(frame) {
+            debug_assert!(!self.is_free(frame), "Double free of frame {:#x}", frame.addr());
+            self.set(bit, false);
+            self.hint = self.hint.min(bit / BITS);
+        }
+    }
+
+    /// Returns the bit describing a frame, if any
+    fn bit(&self, frame: &Frame) -> Option<usize> {
+        let bit = (frame.addr() / PAGE_SIZE).wrapping_sub(self.base);
+        if bit < self.frames { Some(bit) } else { None }
+    }
+
+    fn frame(&self, bit: usize) -> Frame {
+        Frame::containing(self.base * PAGE_SIZE).offset(bit)
+    }
+
+    /// Returns the first clear bit at or after `from`
+    fn first_free(&self, from: usize) -> Option<usize> {
+        let mut bit = from;
+        while bit < self.frames {
+            let clear = !self.words[bit / BITS] >> (bit % BITS);
+            if clear != 0 {
+                let free = bit + clear.trailing_zeros() as usize;
+                return if free < self.frames { Some(free) } else { None };
+            }
+            bit = (bit / BITS + 1) * BITS;
+        }
+        None
+    }
+
+    /// Returns the first set bit in `from..to`
+    fn first_used(&self, from: usize, to: usize) -> Option<usize> {
+        let mut bit = from;
+        while bit < to {
+            let set = self.words[bit / BITS] >> (bit % BITS);
+            if set != 0 {
+                let used = bit + set.trailing_zeros() as usize;
+                return if used < to { Some(used) } else { None };
+            }
+            bit = (bit / BITS + 1) * BITS;
+        }
+        None
+    }
+
+    fn set(&mut self, bit: usize, used: bool) {
+        let word = &mut self.words[bit / BITS];
+        let mask = 1 << (bit % BITS);
+        if (*word & mask != 0) != used {
+            *word ^= mask;
+            if used { self.free -= 1 } else { self.free += 1 }
+        }
+    }
+
+    fn set_range(&mut self, from: usize, to: usize, used: bool) {
+        for bit in from..to {
+            self.set(bit, used);
+        }
+    }
+}
@@ -44,6 +44,14 @@ pub static mut GDT: Gdt = [
 
 /// Initialize new GDT with long mode segments
 pub fn initialize() {
+    load(unsafe { &GDT });
+}
+
+/// Loads a GDT into the GDT register of the current core
+///
+/// Application processors each load a copy of `GDT`, differing only in their
+/// TSS descriptor.
+pub fn load(gdt: &'static Gdt) {
     use core::mem::size_of;
 
     #[allow(dead_code)]
@@ -53,11 +61,9 @@ pub fn initialize() {
         ptr: &'static Gdt,
     }
 
-    unsafe {
-        let gdtp = GdtPointer {
-            size: size_of::<Gdt>() as u16 - 1,
-            ptr: &GDT,
-        };
-        asm!("lgdt [$0]" :: "r"(&gdtp) :: "intel");
-    }
+    let gdtp = GdtPointer {
+        size: size_of::<Gdt>() as u16 - 1,
+        ptr: gdt,
+    };
+    unsafe { asm!("lgdt [$0]" :: "r"(&gdtp) :: "intel"); }
 }
@@ -0,0 +1,106 @@
+//! High Precision Event Timer
+//!
+//! The HPET is a memory mapped counter running at a fixed frequency of at
+//! least 10 MHz, found through the ACPI `HPET` table. Its capabilities
+//! register gives the period of the main counter in femtoseconds. Once
+//! enabled, the main counter serves as a nanosecond resolution clock source.
+//!
+//! The main counter may be only 32 bits wide, wrapping within minutes at
+//! 10 MHz. Such an HPET is left unused, so the clock never jumps backwards.
+
+use crate::clock::{self, ClockSource};
+use crate::sync::Once;
+
+use super::acpi;
+use super::mmio::MmioRegion;
+use super::paging::map_mmio;
+
+/// Offset of the 64 bit physical base address in the ACPI table
+const TABLE_BASE_OFFSET: usize = 44;
+/// Size of the register block
+const REGISTERS_SIZE: usize = 0x400;
+
+/// Register offsets
+pub const CAPABILITIES: usize = 0x000;
+pub const CONFIG:       usize = 0x010;
+pub const MAIN_COUNTER: usize = 0x0f0;
+
+/// `CAPABILITIES` bit set if the main counter is 64 bits wide
+const COUNT_SIZE_CAP: u64 = 1 << 13;
+/// `CONFIG` bit running the main counter
+const ENABLE_CNF: u64 = 1 << 0;
+
+/// Femtoseconds per nanosecond
+const FS_PER_NS: u64 = 1_000_000;
+/// Femtoseconds per second
+const FS_PER_SEC: u64 = 1_000_000_000_000_000;
+
+static HPET: Once<Hpet> = Once::new();
+
+/// An enabled HPET
+pub struct Hpet {
+    registers: MmioRegion,
+    /// Period of the main counter in femtoseconds
+    period: u64,
+    /// Time reported by the previous clock source when the counter started
+    origin_ns: u64,
+}
+
+impl Hpet {
+    /// Starts the main counter of the HPET with the given registers
+    ///
+    /// The counter starts from zero, so `now_ns()` continues from `origin_ns`.
+    pub fn new(registers: MmioRegion, origin_ns: u64) -> Hpet {
+        let period = registers.read::<u64>(CAPABILITIES) >> 32;
+        assert!(period != 0, "HPET reports a zero period");
+
+        let config = registers.read::<u64>(CONFIG);
+        registers.write(CONFIG, config & !ENABLE_CNF);
+        registers.write(MAIN_COUNTER, 0u64);
+        registers.write(CONFIG, config | ENABLE_CNF);
+        Hpet { registers: registers, period: period, origin_ns: origin_ns }
+    }
+
+    /// Returns the raw value of the main counter
+    pub fn counter(&self) -> u64 {
+        self.registers.read(MAIN_COUNTER)
+    }
+}
+
+impl ClockSource for Hpet {
+    fn now_ns(&self) -> u64 {
+        let elapsed = self.counter() as u128 * self.period as u128 / FS_PER_NS as u128;
+        self.origin_ns + elapsed as u64
+    }
+
+    fn frequency(&self) -> u64 {
+        period_to_frequency(self.period)
+    }
+}
+
+/// Converts a counter period in femtoseconds to a frequency in Hz
+pub fn period_to_frequency(period: u64) -> u64 {
+    FS_PER_SEC / period
+}
+
+/// Enables the HPET and installs it as the clock source, if there is one
+/// with a 64 bit main counter
+///
+/// Requires the ACPI tables and the MMIO window.
+pub fn initialize() -> bool {
+    let table = match acpi::find_table(b"HPET") {
+        Some(table) if table.len() >= TABLE_BASE_OFFSET + 8 => table,
+        _ => return false,
+    };
+    let paddr = table[TABLE_BASE_OFFSET..TABLE_BASE_OFFSET + 8].iter().rev()
+        .fold(0, |addr, &byte| addr << 8 | byte as usize);
+    let registers = unsafe { MmioRegion::new(map_mmio(paddr, REGISTERS_SIZE), REGISTERS_SIZE) };
+    if registers.read::<u64>(CAPABILITIES) & COUNT_SIZE_CAP == 0 {
+        log_info!("ignoring the HPET, as its main counter is 32 bits");
+        return false;
+    }
+
+    let hpet = HPET.initialize(Hpet::new(registers, clock::now_ns()));
+    clock::install(hpet);
+    true
+}
@@ -11,18 +11,41 @@
 //! the ISR can either `panic!()` or call `isr::iret()`. See the `Isr` type
 //! alias.
 
+use super::Registers;
+
 /// Number of entries to allocate space for in the IDT
 pub const IDT_ENTRIES: usize = 256;
 /// Number of bytes occupied by the IDT minus 1
 pub const IDT_SIZE: u16      = IDT_ENTRIES as u16 * 16 - 1;
+/// IST index of the NMI stack. See `tss::initialize()`.
+pub const NMI_IST: u8        = 1;
+/// IST index of the machine check stack. See `tss::initialize()`.
+pub const MC_IST: u8         = 2;
 
 /// The correct function prototype of an interrupt service routine
 pub type Isr = unsafe fn();
 
+bitflags! {
+    /// The error code pushed by a page fault
+    pub flags PageFaultError: u32 {
+        /// A protection violation, rather than a not-present page
+        const PF_PRESENT     = 1 << 0,
+        const PF_WRITE       = 1 << 1,
+        /// The access originated in ring 3
+        const PF_USER        = 1 << 2,
+        /// A reserved bit was set in a page entry
+        const PF_RESERVED    = 1 << 3,
+        const PF_INSTRUCTION = 1 << 4,
+    }
+}
+
 /// Wrapper type of binary representation of an IDT entry
+///
+/// Entries are built from an ISR and adjusted with the builder methods, e.g.
+/// `IdtEntry::from(isr).dpl(3).gate_type(GateType::Trap)`.
 #[repr(C)]
 #[derive(Copy, Clone, Debug, Default)]
-struct IdtEntry {
+pub struct IdtEntry {
     ptr_low:  u16,
     selector: u16,
     options:  u16,
@@ -31,6 +54,9 @@ struct IdtEntry {
     reserved: u32,
 }
 
+/// The only interrupt descriptor table, shared by all `Idt` handles
+static mut IDT_TABLE: [IdtEntry; IDT_ENTRIES] = [IdtEntry::EMPTY; IDT_ENTRIES];
+
 /// A `(size, pointer)` pair pointing at an array of `IdtEntries`
 #[repr(packed)]
 pub struct Idt{
@@ -38,9 +64,39 @@ pub struct Idt{
     table: &'static mut [IdtEntry; IDT_ENTRIES]
 }
 
+/// Kinds of IDT gates
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum GateType {
+    /// Interrupts are disabled on entry
+    Interrupt = 0xe,
+    /// Interrupts are left as they were
+    Trap = 0xf,
+}
+
+/// Bits of `IdtEntry::options`
+const OPTION_PRESENT: u16 = 1 << 15;
+const OPTION_DPL_SHIFT: u16 = 13;
+const OPTION_DPL: u16 = 3 << OPTION_DPL_SHIFT;
+const OPTION_TYPE_SHIFT: u16 = 8;
+const OPTION_TYPE: u16 = 0xf << OPTION_TYPE_SHIFT;
+const OPTION_IST: u16 = 0x7;
+
 impl IdtEntry {
+    /// A non-present entry
+    pub const EMPTY: IdtEntry = IdtEntry {
+        ptr_low:  0,
+        selector: 0,
+        options:  0,
+        ptr_med:  0,
+        ptr_high: 0,
+        reserved: 0,
+    };
+
     /// Constructs an entry from a given interrupt service routine
-    fn from(isr: Isr) -> IdtEntry {
+    ///
+    /// The entry is a present interrupt gate in the kernel code segment,
+    /// which only the kernel may invoke and which uses no IST stack.
+    pub fn from(isr: Isr) -> IdtEntry {
         let ptr = isr as usize;
         IdtEntry {
             ptr_low:  (ptr & 0xffff) as u16,
@@ -51,38 +107,106 @@ impl IdtEntry {
             reserved: 0,
         }
     }
+
+    /// Marks the entry present or not
+    pub fn present(mut self, present: bool) -> IdtEntry {
+        self.options &= !OPTION_PRESENT;
+        if present {
+            self.options |= OPTION_PRESENT;
+        }
+        self
+    }
+
+    /// Sets the lowest privilege level which may invoke the gate with `int`
+    pub fn dpl(mut self, dpl: u8) -> IdtEntry {
+        assert!(dpl <= 3, "Invalid privilege level {}", dpl);
+        self.options = (self.options & !OPTION_DPL) | (dpl as u16) << OPTION_DPL_SHIFT;
+        self
+    }
+
+    pub fn gate_type(mut self, gate_type: GateType) -> IdtEntry {
+        self.options = (self.options & !OPTION_TYPE) | (gate_type as u16) << OPTION_TYPE_SHIFT;
+        self
+    }
+
+    /// Sets the IST stack switched to on entry, 0 meaning none
+    pub fn ist(mut self, ist: u8) -> IdtEntry {
+        assert!(ist <= 7, "Invalid IST index {}", ist);
+        self.options = (self.options & !OPTION_IST) | ist as u16;
+        self
+    }
+
+    /// Sets the code segment the ISR runs in
+    pub fn selector(mut self, selector: u16) -> IdtEntry {
+        self.selector = selector;
+        self
+    }
+
+    /// Returns the raw options field
+    pub fn options(&self) -> u16 {
+        self.options
+    }
 }
 
 impl Idt {
-    /// Creates an empty table
+    /// Clears the table and returns a handle to it
     ///
-    /// Allocate array on heap, then leak box so it's never freed.
+    /// There is a single static table, so this neither allocates nor leaks, but
+    /// any entries registered through other handles are lost.
     pub fn new() -> Idt {
-        use alloc::boxed::Box;
-        let table = box [Default::default(); IDT_ENTRIES];
+        let table = unsafe { &mut IDT_TABLE };
+        for entry in table.iter_mut() {
+            *entry = IdtEntry::EMPTY;
+        }
         Idt {
             size: IDT_SIZE,
-            table: unsafe { &mut *Box::into_raw(table) }
+            table: table,
         }
     }
 
     /// Returns the current table
+    ///
+    /// `sidt` stores a plain `(size, pointer)` pair, which is only turned into
+    /// a reference once it is known to describe a table made by `Idt::new()`.
     pub fn current() -> Option<Idt> {
-        use core::mem;
-        unsafe {
-            let mut idt: Idt = mem::uninitialized();
-            asm!("sidt [$0]" :: "r"(&mut idt) :: "intel");
-            if idt.size != IDT_SIZE {
-                // uninitialized IDT
-                return None;
-            }
-            Some(idt)
+        use core::mem::MaybeUninit;
+
+        #[repr(packed)]
+        struct Descriptor {
+            size: u16,
+            base: u64,
+        }
+
+        let descriptor = unsafe {
+            let mut descriptor = MaybeUninit::<Descriptor>::uninit();
+            asm!("sidt [$0]" :: "r"(descriptor.as_mut_ptr()) : "memory" : "intel");
+            descriptor.assume_init()
+        };
+        let (size, base) = (descriptor.size, descriptor.base);
+        if size != IDT_SIZE || base == 0 {
+            // uninitialized IDT
+            return None;
         }
+        Some(Idt {
+            size: size,
+            table: unsafe { &mut *(base as *mut [IdtEntry; IDT_ENTRIES]) },
+        })
     }
 
     /// Registers an interrupt service routine in this table
     pub fn register_isr(&mut self, index: usize, isr: Isr) {
-        self.table[index] = IdtEntry::from(isr);
+        self.register_entry(index, IdtEntry::from(isr));
+    }
+
+    /// Sets the ISR of an entry which code at privilege level `dpl` may invoke
+    /// with `int`
+    pub fn register_isr_dpl(&mut self, index: usize, isr: Isr, dpl: u8) {
+        self.register_entry(index, IdtEntry::from(isr).dpl(dpl));
+    }
+
+    /// Replaces an entry with one made by the `IdtEntry` builder
+    pub fn register_entry(&mut self, index: usize, entry: IdtEntry) {
+        self.table[index] = entry;
     }
 
     /// Loads the table into the IDT register
@@ -92,19 +216,30 @@ impl Idt {
 }
 
 /// Creates and loads a minimal interrupt descriptor table
+///
+/// Requires neither the heap nor the frame allocator, so this runs first.
 pub fn initialize() {
     let mut idt = Idt::new();
     for i in 0..256 {
         idt.register_isr(i, isr::ISR_UNKNOWN[i]);
     }
 
+    idt.register_isr(0x00, isr::isr_de);
+    idt.register_isr(0x03, isr::isr_bp);
     idt.register_isr(0x0e, isr::isr_pf);
 
-    // load rsp with ist1 from TSS. See boot/boot32.s
-    // TODO handle MCE/NMI
-    // idt.table[0x02].options |= 1;
-    // idt.table[0x12].options |= 1;
+    // NMIs and machine checks may arrive with any stack loaded, even a
+    // userspace one just after `sysret`, so they switch to stacks of their
+    // own. Either may interrupt the other, so the stacks must differ.
+    idt.register_entry(0x02, IdtEntry::from(isr::isr_nmi).ist(NMI_IST));
+    idt.register_entry(0x12, IdtEntry::from(isr::isr_mc).ist(MC_IST));
+
+    idt.load();
+}
 
+/// Loads the table made by `initialize()` on an application processor
+pub fn load_shared() {
+    let idt = Idt { size: IDT_SIZE, table: unsafe { &mut IDT_TABLE } };
     idt.load();
 }
 
@@ -118,6 +253,49 @@ pub fn disable() {
     unsafe { asm!("cli") }
 }
 
+/// Are interrupts enabled? Reads RFLAGS.IF.
+pub fn enabled() -> bool {
+    const RFLAGS_IF: u64 = 1 << 9;
+    let rflags: u64;
+    unsafe { asm!("pushfq; pop $0" : "=r"(rflags) ::: "intel", "volatile"); }
+    rflags & RFLAGS_IF != 0
+}
+
+/// Disables interrupts until dropped, then restores their previous state
+///
+/// Guards nest: only the outermost re-enables interrupts, and only if they
+/// were enabled when it was created.
+#[must_use]
+pub struct InterruptGuard {
+    was_enabled: bool,
+}
+
+impl InterruptGuard {
+    pub fn new() -> InterruptGuard {
+        let was_enabled = enabled();
+        disable();
+        InterruptGuard { was_enabled: was_enabled }
+    }
+}
+
+impl Drop for InterruptGuard {
+    fn drop(&mut self) {
+        if self.was_enabled {
+            enable();
+        }
+    }
+}
+
+/// Runs a function with interrupts disabled, restoring their previous state
+pub fn without_interrupts<R, F: FnOnce() -> R>(f: F) -> R {
+    let _guard = InterruptGuard::new();
+    f()
+}
+
+/// The stack built by an ISR, from the saved registers up to the frame
+/// pushed by the CPU
+///
+/// `error` is the error code pushed by the CPU, or 0 for vectors without one.
 #[repr(packed)]
 pub struct InterruptState {
     pub rax: u64,
@@ -152,6 +330,39 @@ pub struct InterruptState {
     _pad4:      u32,
 }
 
+impl InterruptState {
+    /// Overwrites the interrupted state, so the handler returns to `registers`
+    ///
+    /// The error code and vector are left alone.
+    pub fn load(&mut self, registers: &Registers) {
+        let r = *registers;
+        self.rax = r.rax; self.rbx = r.rbx; self.rcx = r.rcx; self.rdx = r.rdx;
+        self.rsi = r.rsi; self.rdi = r.rdi; self.rbp = r.rbp;
+        self.r8  = r.r8;  self.r9  = r.r9;  self.r10 = r.r10; self.r11 = r.r11;
+        self.r12 = r.r12; self.r13 = r.r13; self.r14 = r.r14; self.r15 = r.r15;
+        self.ds = r.ds; self.es = r.es; self.fs = r.fs; self.gs = r.gs;
+        self.rip = r.rip; self.cs = r.cs;
+        self.rflags = r.rflags;
+        self.rsp = r.rsp; self.ss = r.ss;
+    }
+}
+
+impl<'a> From<&'a InterruptState> for Registers {
+    /// Copies the registers of the interrupted code
+    fn from(state: &'a InterruptState) -> Registers {
+        Registers {
+            rax: state.rax, rbx: state.rbx, rcx: state.rcx, rdx: state.rdx,
+            rsi: state.rsi, rdi: state.rdi, rbp: state.rbp,
+            r8:  state.r8,  r9:  state.r9,  r10: state.r10, r11: state.r11,
+            r12: state.r12, r13: state.r13, r14: state.r14, r15: state.r15,
+            cs: state.cs, ss: state.ss,
+            ds: state.ds, es: state.es, fs: state.fs, gs: state.gs,
+            _pad: 0,
+            rip: state.rip, rflags: state.rflags, rsp: state.rsp,
+        }
+    }
+}
+
 
 use core::fmt;
 impl fmt::Debug for InterruptState {
@@ -183,24 +394,26 @@ impl fmt::Debug for InterruptState {
     }
 }
 
-#[inline(always)]
-pub unsafe fn entry_error() {
-}
-
-#[inline(always)]
-pub unsafe fn entry_plain() {
-    asm!("pushq $$0" :::: "volatile");
-}
-
 /// A collection of interrupt service routines
 #[macro_use]
 pub mod isr {
     use super::*;
 
+    /// Makes the stack look the same whether or not the CPU pushed an error
+    ///
+    /// Vectors without an error code push a zero in its place, so the error
+    /// slot is always directly below `rip`. Its upper half is then overwritten
+    /// with the vector, making the `error` and `vector` fields of
+    /// `InterruptState`. This must be expanded inline: a call to a function
+    /// pushing the dummy would return to it.
+    macro_rules! isr_entry {
+        (entry_plain) => { asm!("pushq $$0" :::: "volatile"); };
+        (entry_error) => { };
+    }
+
     macro_rules! isr_asm {
-        ($vector:expr, $entry:path, $action:path) => {
-            // should inline always
-            $entry(); // push an error code if necessary
+        ($vector:expr, $entry:ident, $action:path) => {
+            isr_entry!($entry); // push an error code if necessary
 
             // TODO reconsider pushing segments if we use %gs
             asm!("
@@ -226,7 +439,23 @@ pub mod isr {
             pushq %rbx
             pushq %rax
             movq %rsp, %rdi  // pass InterruptState to action
+            testb $$3, 144(%rsp)  // interrupted cs
+            jz 1f
+            movq ${2:c}, %rax  // save the FPU state of the task
+            fxsave64 (%rax)
             callq ${1:c}
+            movq ${2:c}, %rax
+            fxrstor64 (%rax)
+            jmp 2f
+        1:
+            movq %rsp, %rbx  // save the FPU state of the kernel on the stack
+            subq $$512, %rsp
+            andq $$-16, %rsp
+            fxsave64 (%rsp)
+            callq ${1:c}
+            fxrstor64 (%rsp)
+            movq %rbx, %rsp
+        2:
             popq %rax
             popq %rbx
             popq %rcx
@@ -248,12 +477,13 @@ pub mod isr {
             movw 0(%rsp), %ds
             addq $$16, %rsp   // selectors and remove error code
             iretq
-            " :: "n"($vector), "s"($action as u64) :: "volatile");
+            " :: "n"($vector), "s"($action as u64),
+                 "s"(&$crate::arch::x86::fpu::USER_FPU) :: "volatile");
         }
     }
 
     macro_rules! isr_expr {
-        ( $name:ident, $vector:expr, $entry:path, $action:path) => {
+        ( $name:ident, $vector:expr, $entry:ident, $action:path) => {
             {
                 #[naked]
                 pub unsafe fn $name() {
@@ -265,7 +495,7 @@ pub mod isr {
     }
 
     macro_rules! isr_action {
-        ($entry:path, $name:ident, $vector:expr, $s:ident, $block:block) => {
+        ($entry:ident, $name:ident, $vector:expr, $s:ident, $block:block) => {
             #[naked]
             pub unsafe fn $name() {
                 fn action($s: &mut $crate::arch::x86::interrupts::InterruptState) {
@@ -279,23 +509,81 @@ pub mod isr {
 
     macro_rules! isr_plain {
         ($($vector:expr => fn $name:ident ($s:ident) $block:block)*) => {$(
-            isr_action!($crate::arch::x86::interrupts::entry_plain, $name, $vector, $s, $block);
+            isr_action!(entry_plain, $name, $vector, $s, $block);
         )*}
     }
 
     macro_rules! isr_error {
         ($($vector:expr => fn $name:ident ($s:ident) $block:block)*) => {$(
-            isr_action!($crate::arch::x86::interrupts::entry_error, $name, $vector, $s, $block);
+            isr_action!(entry_error, $name, $vector, $s, $block);
         )*}
     }
 
+    isr_plain! {
+        0x00 => fn isr_de(state) {
+            let (rip, cs) = (state.rip, state.cs);
+            panic!("division error at 0x{:x} (cs 0x{:x})\n{:?}", rip, cs, state);
+        }
+
+        0x02 => fn isr_nmi(state) {
+            use core::fmt::Write;
+            use crate::arch::x86::intrinsics::{halt, halting_other_cores};
+            use crate::vestige::PanicWriter;
+            if halting_other_cores() {
+                halt(); // another core cannot continue, see halt_other_cores()
+            }
+            // cli does not mask NMIs, so the console may be locked by the
+            // interrupted code
+            let rip = state.rip;
+            let mut out = unsafe { PanicWriter::new() };
+            let _ = write!(out, "NMI at 0x{:x}\n", rip);
+        }
+
+        0x03 => fn isr_bp(state) {
+            use core::fmt::Write;
+            use crate::vestige::PanicWriter;
+            // the breakpoint may have been hit with the console locked
+            let rip = state.rip;
+            let mut out = unsafe { PanicWriter::new() };
+            let _ = write!(out, "Breakpoint at 0x{:x}\n", rip);
+        }
+
+        0x12 => fn isr_mc(state) {
+            use core::fmt::Write;
+            use crate::arch::x86::intrinsics::rdmsr;
+            use crate::vestige::PanicWriter;
+            const IA32_MCG_CAP:     u32 = 0x179;
+            const IA32_MCG_STATUS:  u32 = 0x17a;
+            const IA32_MC0_STATUS:  u32 = 0x401;
+            const MCI_STATUS_VALID: u64 = 1 << 63;
+
+            // like NMIs, machine checks are not masked by cli
+            let mut out = unsafe { PanicWriter::new() };
+            // every bank has four MSRs, CTL, STATUS, ADDR and MISC
+            let banks = rdmsr(IA32_MCG_CAP) & 0xff;
+            for bank in 0..banks as u32 {
+                let status = rdmsr(IA32_MC0_STATUS + bank * 4);
+                if status & MCI_STATUS_VALID != 0 {
+                    let addr = rdmsr(IA32_MC0_STATUS + bank * 4 + 1);
+                    let _ = write!(out, "MC{} status 0x{:016x} addr 0x{:x}\n",
+                                   bank, status, addr);
+                }
+            }
+            let rip = state.rip;
+            panic!("machine check at 0x{:x}, MCG_STATUS 0x{:x}", rip, rdmsr(IA32_MCG_STATUS));
+        }
+    }
+
     isr_error! {
         0x0e => fn isr_pf(state) {
-            unsafe {
-                let cr2: u64;
-                asm!("movq %cr2, %rax" :"={rax}"(cr2)::: );
-                println!("int #PF(0x{:x}) cs={:x} rip={:x} ss={:x} rsp={:x} cr2={:x}",
-                         state.error, state.cs, state.rip, state.ss, state.rsp, cr2);
+            use crate::arch::x86::address_space::handle_page_fault;
+            let cr2: u64;
+            unsafe { asm!("movq %cr2, %rax" :"={rax}"(cr2)::: ); }
+
+            let error = PageFaultError::from_bits_truncate(state.error);
+            if !handle_page_fault(cr2 as usize, error) {
+                // TODO terminate the offending process instead
+                panic!("int #PF(0x{:x}) {:?} cr2={:x}\n{:?}", state.error, error, cr2, state);
             }
         }
     }
@@ -326,7 +614,7 @@ pub mod isr {
         isr_expr!(isr_unknown_0x12, 0x12, entry_plain, isr_unknown),
         isr_expr!(isr_unknown_0x13, 0x13, entry_plain, isr_unknown),
         isr_expr!(isr_unknown_0x14, 0x14, entry_plain, isr_unknown),
-        isr_expr!(isr_unknown_0x15, 0x15, entry_plain, isr_unknown),
+        isr_expr!(isr_unknown_0x15, 0x15, entry_error, isr_unknown), // error
         isr_expr!(isr_unknown_0x16, 0x16, entry_plain, isr_unknown),
         isr_expr!(isr_unknown_0x17, 0x17, entry_plain, isr_unknown),
         isr_expr!(isr_unknown_0x18, 0x18, entry_plain, isr_unknown),
@@ -334,7 +622,7 @@ pub mod isr {
         isr_expr!(isr_unknown_0x1a, 0x1a, entry_plain, isr_unknown),
         isr_expr!(isr_unknown_0x1b, 0x1b, entry_plain, isr_unknown),
         isr_expr!(isr_unknown_0x1c, 0x1c, entry_plain, isr_unknown),
-        isr_expr!(isr_unknown_0x1d, 0x1d, entry_plain, isr_unknown),
+        isr_expr!(isr_unknown_0x1d, 0x1d, entry_error, isr_unknown), // error
         isr_expr!(isr_unknown_0x1e, 0x1e, entry_error, isr_unknown), // error
         isr_expr!(isr_unknown_0x1f, 0x1f, entry_plain, isr_unknown),
         isr_expr!(isr_unknown_0x20, 0x20, entry_plain, isr_unknown),
@@ -6,12 +6,24 @@
 //! specific to a single subsystem are better left safely wrapped in the
 //! relevant modules.
 
+use core::sync::atomic::{AtomicBool, Ordering};
+
+use crate::sync::Once;
+
+use super::apic;
+
 /// Transmits byte to port
 #[inline(always)]
 pub fn outb(port: u16, data: u8) {
     unsafe { asm!("out dx, al" :: "{dx}"(port),"{al}"(data) :: "volatile","intel") }
 }
 
+/// Transmits 2 bytes to port
+#[inline(always)]
+pub fn outw(port: u16, data: u16) {
+    unsafe { asm!("out dx, ax" :: "{dx}"(port),"{ax}"(data) :: "volatile","intel") }
+}
+
 /// Transmits 4 bytes to port
 #[inline(always)]
 pub fn outl(port: u16, data: u32) {
@@ -26,6 +38,14 @@ pub fn inb(port: u16) -> u8 {
     data
 }
 
+/// Receives 2 bytes from port
+#[inline(always)]
+pub fn inw(port: u16) -> u16 {
+    let data;
+    unsafe { asm!("in ax, dx" : "={ax}"(data) : "{dx}"(port) :: "volatile","intel") }
+    data
+}
+
 /// Receives 4 byte from port
 #[inline(always)]
 pub fn inl(port: u16) -> u32 {
@@ -56,6 +76,58 @@ pub fn stmsr(register: u32, offset: usize) {
     wrmsr(register, value | (1 << offset));
 }
 
+/// Saves the x87, MMX and SSE state into a 16 byte aligned 512 byte area
+#[inline(always)]
+pub unsafe fn fxsave(area: *mut u8) {
+    asm!("fxsave64 [$0]" :: "r"(area) : "memory" : "intel", "volatile");
+}
+
+/// Loads the x87, MMX and SSE state saved by `fxsave()`
+#[inline(always)]
+pub unsafe fn fxrstor(area: *const u8) {
+    asm!("fxrstor64 [$0]" :: "r"(area) : "memory" : "intel", "volatile");
+}
+
+/// Reads control register 0
+#[inline(always)]
+pub fn read_cr0() -> usize {
+    let value;
+    unsafe { asm!("mov $0, cr0" : "=r"(value) ::: "intel") }
+    value
+}
+
+/// Writes control register 0
+#[inline(always)]
+pub fn write_cr0(value: usize) {
+    unsafe { asm!("mov cr0, $0" :: "r"(value) : "memory" : "volatile","intel") }
+}
+
+/// Reads control register 4
+#[inline(always)]
+pub fn read_cr4() -> usize {
+    let value;
+    unsafe { asm!("mov $0, cr4" : "=r"(value) ::: "intel") }
+    value
+}
+
+/// Writes control register 4
+#[inline(always)]
+pub fn write_cr4(value: usize) {
+    unsafe { asm!("mov cr4, $0" :: "r"(value) : "memory" : "volatile","intel") }
+}
+
+/// Invalidates any TLB entries for the page containing the address
+#[inline(always)]
+pub fn invlpg(addr: usize) {
+    unsafe { asm!("invlpg [$0]" :: "r"(addr) : "memory" : "volatile","intel") }
+}
+
+/// Waits for the next interrupt with interrupts enabled
+#[inline(always)]
+pub fn wait_for_interrupt() {
+    unsafe { asm!("sti; hlt" :::: "volatile") }
+}
+
 /// Halts execution permanently for this core
 ///
 /// This disables interrupts then blocks indefinitely on the next interrupt.
@@ -66,17 +138,31 @@ pub fn halt() -> ! {
     loop { } // compiler hint about divergence
 }
 
+/// Set by `halt_other_cores()`, telling cores receiving an NMI to halt
+static HALTING: AtomicBool = AtomicBool::new(false);
+
+/// Stops every other core, for when the system cannot continue
+///
+/// An NMI is sent to every other core, so even those with interrupts
+/// disabled are stopped. Without the local APIC only this core runs.
+pub fn halt_other_cores() {
+    HALTING.store(true, Ordering::SeqCst);
+    if apic::is_enabled() {
+        apic::send_nmi_to_others();
+    }
+}
+
+/// Has `halt_other_cores()` been called? Checked by the NMI handler.
+pub fn halting_other_cores() -> bool {
+    HALTING.load(Ordering::SeqCst)
+}
+
 /// Permanent record of cpuid results
-static mut CPUID_RESULTS: Option<CpuidResults> = None;
+static CPUID_RESULTS: Once<CpuidResults> = Once::new();
 
-/// Returns a pointer the cpuid results
+/// Returns a pointer the cpuid results, querying them on first use
 pub fn get_cpuid() -> &'static CpuidResults {
-    unsafe { // smp safe because this should occur early on BSP
-        if CPUID_RESULTS.is_none() {
-            CPUID_RESULTS = Some(CpuidResults::new());
-        }
-        CPUID_RESULTS.as_ref().unwrap()
-    }
+    CPUID_RESULTS.call_once(|| unsafe { CpuidResults::new() })
 }
 
 /// Execute the cpuid instruction
@@ -113,11 +199,58 @@ impl CpuidRegs {
 pub struct CpuidResults {
     pub supported: bool,
     pub base:  [Option<CpuidRegs>; 0x18],
-    pub extra: [Option<CpuidRegs>; 0x08],
+    pub extra: [Option<CpuidRegs>; 0x09],
     vendor_id: Option<[u8; 12]>, // save demangled result
     vendor: Option<CpuVendor>,
 }
 
+/// Processor features reported by cpuid
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Feature {
+    X2apic,
+    Pse,
+    Msr,
+    Pae,
+    Apic,
+    Pat,
+    Rdpid,
+    Syscall,
+    Page1gb,
+    Rdtscp,
+    Nx,
+    La57,
+    Fxsr,
+    Xsave,
+}
+
+impl Feature {
+    /// Every feature, in the order `dump()` prints them
+    pub const ALL: [Feature; 14] = [
+        Feature::X2apic, Feature::Pse, Feature::Msr, Feature::Pae, Feature::Apic,
+        Feature::Pat, Feature::Rdpid, Feature::Syscall, Feature::Page1gb, Feature::Rdtscp,
+        Feature::Nx, Feature::La57, Feature::Fxsr, Feature::Xsave,
+    ];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Feature::X2apic  => "x2apic",
+            Feature::Pse     => "pse",
+            Feature::Msr     => "msr",
+            Feature::Pae     => "pae",
+            Feature::Apic    => "apic",
+            Feature::Pat     => "pat",
+            Feature::Rdpid   => "rdpid",
+            Feature::Syscall => "syscall",
+            Feature::Page1gb => "page1gb",
+            Feature::Rdtscp  => "rdtscp",
+            Feature::Nx      => "nx",
+            Feature::La57    => "la57",
+            Feature::Fxsr    => "fxsr",
+            Feature::Xsave   => "xsave",
+        }
+    }
+}
+
 #[derive(Copy, Clone, Debug, PartialEq, Eq)]
 pub enum CpuVendor {
     Intel,
@@ -148,7 +281,7 @@ impl CpuidResults {
         let mut c = CpuidResults {
             supported: true,
             base:  [None; 0x18],
-            extra: [None; 0x08],
+            extra: [None; 0x09],
             vendor_id: None,
             vendor: None,
         };
@@ -172,13 +305,14 @@ impl CpuidResults {
 
         c.query_base(0);
         let leaves = c.base[0].unwrap().eax;
-        for i in 1 .. leaves.min(c.base.len() as u32) {
+        // eax holds the highest leaf, which is itself valid
+        for i in 1 ..= leaves.min(c.base.len() as u32 - 1) {
             c.query_base(i);
         }
 
         c.query_extra(0);
         let leaves = c.extra[0].unwrap().eax;
-        for i in 1 .. leaves.wrapping_sub(CPUID_EXTRA).min(c.extra.len() as u32) {
+        for i in 1 ..= leaves.saturating_sub(CPUID_EXTRA).min(c.extra.len() as u32 - 1) {
             c.query_extra(i);
         }
 
@@ -216,10 +350,17 @@ impl CpuidResults {
     flag!(msr     = base[1].edx.5);
     flag!(pae     = base[1].edx.6);
     flag!(apic    = base[1].edx.9);
+    flag!(pat     = base[1].edx.16);
+    flag!(fxsr    = base[1].edx.24);
+    flag!(sse     = base[1].edx.25);
+    flag!(sse2    = base[1].edx.26);
+    flag!(xsave   = base[1].ecx.26);
 
+    flag!(la57    = base[7].ecx.16);
     flag!(rdpid   = base[7].ecx.22);
 
     flag!(syscall = extra[1].edx.11);
+    flag!(nx      = extra[1].edx.20);
     flag!(page1gb = extra[1].edx.26);
     flag!(rdtscp  = extra[1].edx.27);
 
@@ -228,6 +369,8 @@ impl CpuidResults {
     field!(family   = base[1].eax.11,8);
     field!(extended_model  = base[1].eax.19,16);
     field!(extended_family = base[1].eax.27,20);
+    field!(phys_addr_bits = extra[8].eax.8,0);
+    field!(virt_addr_bits = extra[8].eax.16,8);
     pub fn effective_model(&self) -> Option<u32> {
         let (f, m) = (self.family()?, self.model()?);
         match f {
@@ -242,4 +385,46 @@ impl CpuidResults {
             _  => Some(f),
         }
     }
+
+    /// Is the feature supported?
+    pub fn has(&self, feature: Feature) -> bool {
+        match feature {
+            Feature::X2apic  => self.x2apic(),
+            Feature::Pse     => self.pse(),
+            Feature::Msr     => self.msr(),
+            Feature::Pae     => self.pae(),
+            Feature::Apic    => self.apic(),
+            Feature::Pat     => self.pat(),
+            Feature::Rdpid   => self.rdpid(),
+            Feature::Syscall => self.syscall(),
+            Feature::Page1gb => self.page1gb(),
+            Feature::Rdtscp  => self.rdtscp(),
+            Feature::Nx      => self.nx(),
+            Feature::La57    => self.la57(),
+            Feature::Fxsr    => self.fxsr(),
+            Feature::Xsave   => self.xsave(),
+        }
+    }
+
+    /// Checks that every feature is supported, returning the first which is not
+    pub fn require(&self, features: &[Feature]) -> Result<(), Feature> {
+        match features.iter().find(|&&feature| !self.has(feature)) {
+            Some(&missing) => Err(missing),
+            None => Ok(()),
+        }
+    }
+
+    /// Prints the vendor, model and supported features
+    pub fn dump(&self) {
+        println!("cpu: {} family {:02x} model {:02x} stepping {:x}",
+                 self.vendor_id().unwrap_or("unknown"),
+                 self.effective_family().unwrap_or(0),
+                 self.effective_model().unwrap_or(0),
+                 self.stepping().unwrap_or(0));
+        print!("cpu features:");
+        for &feature in Feature::ALL.iter().filter(|&&feature| self.has(feature)) {
+            print!(" {}", feature.name());
+        }
+        println!("");
+    }
 }
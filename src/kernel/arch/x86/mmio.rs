@@ -0,0 +1,77 @@
+//! Memory Mapped I/O
+//!
+//! Device registers must be accessed with volatile reads and writes, lest the
+//! compiler merge, reorder or elide accesses it considers redundant. Drivers
+//! should go through these helpers rather than plain pointers.
+//!
+//! Registers are either accessed at an offset of a `MmioRegion`, or declared
+//! as `Mmio<T>` fields of a `#[repr(C)]` struct overlaid on the region.
+
+use core::ptr;
+
+/// Reads a value from a device register
+pub unsafe fn read_volatile<T: Copy>(addr: usize) -> T {
+    ptr::read_volatile(addr as *const T)
+}
+
+/// Writes a value to a device register
+pub unsafe fn write_volatile<T: Copy>(addr: usize, value: T) {
+    ptr::write_volatile(addr as *mut T, value)
+}
+
+/// A device register holding a `T`
+#[repr(transparent)]
+pub struct Mmio<T: Copy> {
+    value: T,
+}
+
+impl<T: Copy> Mmio<T> {
+    /// Returns the register at a virtual address
+    ///
+    /// The address must be mapped uncacheable for as long as the register
+    /// is used.
+    pub unsafe fn at<'a>(addr: usize) -> &'a mut Mmio<T> {
+        &mut *(addr as *mut Mmio<T>)
+    }
+
+    pub fn read(&self) -> T {
+        unsafe { ptr::read_volatile(&self.value) }
+    }
+
+    pub fn write(&mut self, value: T) {
+        unsafe { ptr::write_volatile(&mut self.value, value) }
+    }
+}
+
+/// A block of device registers, addressed by offset
+#[derive(Copy, Clone, Debug)]
+pub struct MmioRegion {
+    base: usize,
+    size: usize,
+}
+
+impl MmioRegion {
+    /// Wraps `size` bytes of registers mapped at `base`, see `paging::map_mmio()`
+    pub unsafe fn new(base: usize, size: usize) -> MmioRegion {
+        MmioRegion { base: base, size: size }
+    }
+
+    pub fn base(&self) -> usize {
+        self.base
+    }
+
+    /// Returns the address of the register at an offset
+    pub fn addr<T>(&self, offset: usize) -> usize {
+        assert!(offset + core::mem::size_of::<T>() <= self.size,
+                "MMIO offset 0x{:x} outside of region", offset);
+        self.base + offset
+    }
+
+    pub fn read<T: Copy>(&self, offset: usize) -> T {
+        unsafe { read_volatile(self.addr::<T>(offset)) }
+    }
+
+    pub fn write<T: Copy>(&self, offset: usize, value: T) {
+        unsafe { write_volatile(self.addr::<T>(offset), value) }
+    }
+}
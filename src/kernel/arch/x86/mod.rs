@@ -1,61 +1,163 @@
+use crate::cmdline::CmdLine;
+use crate::console::{self, Console};
+use crate::drivers::serial;
+use crate::fb;
+use crate::fs::ramfs;
+use crate::log;
 use crate::main;
 
+pub mod acpi;
+pub mod address_space;
+pub mod backtrace;
+pub mod dma;
+pub mod elf;
+pub mod fpu;
 pub mod frame_allocator;
+pub mod frame_bitmap;
 #[macro_use]
 pub mod interrupts;
 pub mod intrinsics;
+pub mod apic;
+pub mod mmio;
 pub mod gdt;
+pub mod hpet;
 pub mod multiboot;
 pub mod paging;
 pub mod pic;
+pub mod pit;
+pub mod port;
+pub mod scheduler;
+pub mod smp;
 pub mod stacks;
 pub mod syscall;
+pub mod task;
+pub mod tlb;
 pub mod tss;
+pub mod usercopy;
+pub mod watchdog;
 
 pub const KERNEL_BASE: usize = 0xffffffff80000000;
 
 use self::multiboot::MultibootTags;
-use self::frame_allocator::{frame_alloc, get_fallocator};
+use self::frame_allocator::{frame_alloc, get_fallocator, PAGE_SIZE};
+use self::intrinsics::Feature;
 
 #[no_mangle]
 pub unsafe extern fn kstart(multiboot_tags: &MultibootTags) {
-    assert_minimum_cpuid();
-
+    // the IDT is static, so exceptions are reported from here on, even while
+    // the frame allocator and heap are set up
+    interrupts::initialize();
+    stacks::initialize(); // before the timer checks the canaries
+    serial::initialize();
     let multiboot_info = multiboot_tags.parse();
 
+    // select the console first, so a headless boot reports everything below
+    let cmdline = CmdLine::new(multiboot_info.cmd_line.unwrap_or(""));
+    if let Some(name) = cmdline.get("console") {
+        match Console::from_name(name) {
+            Some(selected) => console::set(selected),
+            None => log_warn!("unknown console {}", name),
+        }
+    }
+
+    assert_minimum_cpuid();
+    fpu::enable_sse();
+
     // protect some memory regions from frame allocator
-    let elf_sections = multiboot_info.elf_sections.unwrap();
-    let (k_begin, k_end) = (elf_sections.image_start(), elf_sections.image_end() - KERNEL_BASE);
+    let elf_sections = multiboot_info.elf_sections.expect("boot loader provided no ELF sections");
+    let (k_begin, k_end) = elf_sections.image_phys_range()
+        .expect("ELF sections describe no allocated kernel image");
     let (m_begin, m_end) = (multiboot_tags.start(), multiboot_tags.end());
+    let (mod_begin, mod_end) = multiboot_info.modules_region().unwrap_or((0, 0));
     let protected_regions = [
-        (k_begin, k_end), // kernel image
-        (m_begin, m_end), // multiboot data
+        (k_begin, k_end),     // kernel image
+        (m_begin, m_end),     // multiboot data
+        (mod_begin, mod_end), // boot modules
+        (smp::TRAMPOLINE, smp::TRAMPOLINE + PAGE_SIZE - 1), // AP trampoline
     ];
     let mmap = multiboot_info.mem_map.unwrap();
     frame_allocator::initialize(mmap, protected_regions);
 
     println!("boot loader: {}", &multiboot_info.boot_loader_name.unwrap_or("none"));
     println!("cmd line: {}", &multiboot_info.cmd_line.unwrap_or("none"));
+    if let Some(name) = cmdline.get("loglevel") {
+        match log::Level::from_name(name) {
+            Some(level) => log::set_level(level),
+            None => log_warn!("unknown log level {}", name),
+        }
+    }
+    let use_apic = !cmdline.has_flag("noapic");
+    if !use_apic {
+        log_info!("APIC disabled");
+    }
     println!("");
     println!("protected memory regions");
     println!("  kernel:    ({:#x}, {:#x}) size {} KiB", k_begin, k_end, (k_end - k_begin) / 1024);
     println!("  multiboot: ({:#x}, {:#x}) size {} KiB", m_begin, m_end, (m_end - m_begin) / 1024);
     println!("first free page 0x{:x}", frame_alloc().addr());
-    let free_pages = get_fallocator().free_pages();
-    println!("free pages {} ({} MiB)", free_pages, free_pages / 256);
+    let summary = get_fallocator().memory_summary();
+    println!("physical memory: {} MiB, {} KiB reserved, {} KiB allocated, {} MiB free",
+             summary.total >> 20, summary.reserved >> 10, summary.allocated >> 10,
+             summary.free >> 20);
 
-    let _ = paging::initialize();
+    paging::initialize();
+    crate::vga::get_vgabuffer().enable_scrollback(); // requires heap
+    // a graphical boot has no text mode, so draw the console instead
+    if let Some(info) = multiboot_info.framebuffer {
+        let installed = info.ty == multiboot::FRAMEBUFFER_RGB
+            && fb::initialize(info.addr as usize, info.width as usize, info.height as usize,
+                              info.pitch as usize, info.bpp);
+        if installed {
+            console::use_framebuffer();
+        }
+    }
     // set up interrupt handlers
-    interrupts::initialize();
+    pit::initialize();
+    crate::clock::install(&pit::PIT_CLOCK);
+    if let Some(rsdp) = multiboot_info.rsdp {
+        acpi::initialize(rsdp);
+        if hpet::initialize() {
+            log_info!("using the HPET as clock source");
+        }
+    }
     pic::initialize();
+    watchdog::arm(watchdog::DEFAULT_TIMEOUT);
+    if use_apic {
+        apic::initialize();
+        tlb::initialize();
+        smp::initialize(mmap); // requires the identity map
+    }
     gdt::initialize();
     tss::initialize();
     syscall::initialize();
+    fpu::initialize();
+    scheduler::initialize();
+
+    // a boot module named "initrd" holds the root filesystem
+    let initrd = multiboot_info.modules.iter()
+        .filter_map(|module| *module)
+        .find(|module| module.cmd_line == Some("initrd"));
+    if let Some(initrd) = initrd {
+        ramfs::initialize(initrd.bytes());
+    }
+
+    // the initial userspace program is /bin/init, or else the first boot module
+    let init = ramfs::open("/bin/init")
+        .or_else(|| multiboot_info.modules[0].map(|module| module.bytes()))
+        .map(|image| elf::load(image).expect("Unable to load init").spawn());
+
+    // multiboot_info must not be used past this point
+    paging::remove_identity_map();
+
+    if init.is_some() {
+        scheduler::start();
+    }
 
     main::kmain();
 }
 
 #[repr(packed)]
+#[derive(Copy, Clone)]
 pub struct Registers {
     pub rax: u64,
     pub rbx: u64,
@@ -101,21 +203,21 @@ impl Registers {
     }
 }
 
+/// Features the kernel cannot run without
+///
+/// Presumably some of these requirements could be eliminated with extra work.
+const REQUIRED_FEATURES: [Feature; 7] = [
+    Feature::Pse, Feature::Pae, Feature::Page1gb, Feature::Msr, Feature::Apic, Feature::Syscall,
+    Feature::Fxsr,
+];
+
 fn assert_minimum_cpuid() {
     let cpuid = intrinsics::get_cpuid();
     assert!(cpuid.supported, "minimum processor requirements unmet");
+    cpuid.dump();
 
-    // presumably the rest of these requirements could be eliminated with extra work
-    assert!(cpuid.pse());
-    assert!(cpuid.pae());
-    assert!(cpuid.page1gb());
-    assert!(cpuid.msr());
-    assert!(cpuid.apic());
-    assert!(cpuid.syscall());
-    assert!(cpuid.rdpid() || cpuid.rdtscp()); // read processor id
-
-    println!("running on {} (family {:02x}, model {:02x})", cpuid.vendor_id().unwrap_or("unknown"),
-             cpuid.effective_family().unwrap(),
-             cpuid.effective_model().unwrap(),
-    );
+    if let Err(missing) = cpuid.require(&REQUIRED_FEATURES) {
+        panic!("processor does not support {}", missing.name());
+    }
+    assert!(cpuid.rdpid() || cpuid.rdtscp(), "processor cannot read its id");
 }
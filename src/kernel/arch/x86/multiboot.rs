@@ -12,6 +12,14 @@
 use core;
 use core::fmt;
 
+use super::acpi::Rsdp;
+use super::frame_allocator::MemRegion;
+use super::paging::phys_to_virt;
+use super::KERNEL_BASE;
+
+/// Maximum number of boot modules recorded in `MultibootInfo`
+pub const MAX_MODULES: usize = 4;
+
 /// Pointer to the Multiboot tag structure
 #[repr(C)]
 pub struct MultibootTags {
@@ -28,6 +36,9 @@ pub struct MultibootInfo {
     pub bios_boot_dev:    Option<&'static BiosBootDevice>,
     pub mem_map:          Option<&'static [MMapEntry]>,
     pub elf_sections:     Option<ElfSections>,
+    pub modules:          [Option<Module>; MAX_MODULES],
+    pub framebuffer:      Option<FramebufferInfo>,
+    pub rsdp:             Option<Rsdp>,
 }
 
 /// Helper to parse individual multiboot tags
@@ -43,6 +54,7 @@ impl MultibootTags {
     /// MultibootInfo struct are currently supported.
     pub unsafe fn parse(&self) -> MultibootInfo {
         let mut info = MultibootInfo::default();
+        let mut num_modules = 0;
         let mut tag: *const Tag = self.start() as *const Tag;
         let limit = (self.end() + 1) as *const Tag; // point just past the last valid tag
 
@@ -58,6 +70,20 @@ impl MultibootTags {
                     // Boot command line
                     info.cmd_line = parse_tag_str(data, data_size, 1);
                 }
+                3 => {
+                    // Modules
+                    let module = Module {
+                        mod_start: *(data as *const u32),
+                        mod_end:   *((data + 4) as *const u32),
+                        cmd_line:  parse_tag_str(data + 8, data_size - 8, 3),
+                    };
+                    // modules beyond MAX_MODULES are ignored, as are empty or
+                    // malformed ones, so every module holds at least a byte
+                    if num_modules < MAX_MODULES && module.mod_end > module.mod_start {
+                        info.modules[num_modules] = Some(module);
+                        num_modules += 1;
+                    }
+                }
                 2 => {
                     // Boot loader name
                     info.boot_loader_name = parse_tag_str(data, data_size, 2);
@@ -100,16 +126,33 @@ impl MultibootTags {
                         shndx:   shndx,
                     });
                 }
+                8 => {
+                    // framebuffer
+                    info.framebuffer = Some(FramebufferInfo {
+                        addr:   *(data as *const u64),
+                        pitch:  *((data + 8) as *const u32),
+                        width:  *((data + 12) as *const u32),
+                        height: *((data + 16) as *const u32),
+                        bpp:    *((data + 20) as *const u8),
+                        ty:     *((data + 21) as *const u8),
+                    });
+                }
+                14 => {
+                    // ACPI 1.0 RSDP, unless a newer one was found
+                    if info.rsdp.is_none() {
+                        info.rsdp = Rsdp::from_ptr(data as *const u8);
+                    }
+                }
+                15 => {
+                    // ACPI 2.0+ RSDP
+                    info.rsdp = Rsdp::from_ptr(data as *const u8).or(info.rsdp);
+                }
                 // TODO unhandled Mutliboot tags
-                3 => { } // NYI Modules
                 7 => { } // VBE
-                8 => { } // framebuffer
                 10 => { } // APM
                 11 => { } // EFI32
                 12 => { } // EFI64
                 13 => { } // SMBIOS
-                14 => { } // ACPI Old
-                15 => { } // ACPI New
                 16 => { } // Network
                 17 => { } // EFI MMap
                 18 => { } // EFI BS
@@ -139,6 +182,16 @@ impl MultibootTags {
     }
 }
 
+impl MultibootInfo {
+    /// Returns the region spanning all boot modules, if any were loaded
+    pub fn modules_region(&self) -> Option<MemRegion> {
+        let modules = self.modules.iter().filter_map(|m| m.as_ref());
+        let start = modules.clone().map(|m| m.start()).min()?;
+        let end = modules.map(|m| m.end()).max()?;
+        Some((start, end))
+    }
+}
+
 /// Parses a null-terminated string from a tag
 unsafe fn parse_tag_str(data: usize, data_size: usize, tag: usize) -> Option<&'static str> {
     let ptr = data as *const u8;
@@ -155,6 +208,32 @@ unsafe fn parse_tag_str(data: usize, data_size: usize, tag: usize) -> Option<&'s
 }
 
 
+/// A file loaded into physical memory alongside the kernel
+#[derive(Copy, Clone, Debug)]
+pub struct Module {
+    mod_start: u32,
+    mod_end:   u32, // one past the last byte
+    pub cmd_line: Option<&'static str>,
+}
+
+/// Framebuffer set up by the bootloader
+#[derive(Copy, Clone, Debug)]
+pub struct FramebufferInfo {
+    /// Physical address of the first pixel
+    pub addr:   u64,
+    /// Bytes per row of pixels
+    pub pitch:  u32,
+    pub width:  u32,
+    pub height: u32,
+    /// Bits per pixel
+    pub bpp:    u8,
+    /// 0 for indexed color, 1 for direct RGB, 2 for EGA text
+    pub ty:     u8,
+}
+
+/// `FramebufferInfo::ty` of a linear framebuffer of direct RGB pixels
+pub const FRAMEBUFFER_RGB: u8 = 1;
+
 #[repr(C)]
 pub struct BiosBootDevice {
     pub biosdev: u32,
@@ -214,18 +293,42 @@ pub struct ElfSection {
 
 impl ElfSections {
     /// Return pointer to start of kernel image
-    pub fn image_start(&self) -> usize {
-        self.list.iter().filter(|s| s.is_allocated()).map(|s| s.start()).min().unwrap()
+    ///
+    /// This and the other image methods return `None` if no section is
+    /// allocated, which only a malformed tag would describe.
+    pub fn image_start(&self) -> Option<usize> {
+        self.list.iter().filter(|s| s.is_allocated()).map(|s| s.start()).min()
     }
 
     /// Return size of kernel image
-    pub fn image_size(&self) -> usize {
-        self.list.iter().filter(|s| s.is_allocated()).map(|s| s.size()).sum()
+    pub fn image_size(&self) -> Option<usize> {
+        self.image_start()?;
+        Some(self.list.iter().filter(|s| s.is_allocated()).map(|s| s.size()).sum())
     }
 
     /// Return pointer to the last byte of kernel image
-    pub fn image_end(&self) -> usize {
-        self.list.iter().filter(|s| s.is_allocated()).map(|s| s.end()).max().unwrap()
+    pub fn image_end(&self) -> Option<usize> {
+        self.list.iter().filter(|s| s.is_allocated()).map(|s| s.end()).max()
+    }
+
+    /// Return physical addresses of the first and last byte of kernel image
+    ///
+    /// The boot code is linked at its physical address while everything else
+    /// is linked in the higher half, `KERNEL_BASE` above its physical address.
+    /// See `ElfSection::phys_start()`.
+    pub fn image_phys_range(&self) -> Option<(usize, usize)> {
+        let allocated = || self.list.iter().filter(|s| s.is_allocated());
+        Some((allocated().map(|s| s.phys_start()).min()?,
+              allocated().map(|s| s.phys_end()).max()?))
+    }
+
+    /// Return virtual addresses of the first and last byte of the higher half
+    /// part of kernel image
+    pub fn image_virt_range(&self) -> Option<(usize, usize)> {
+        let higher = || self.list.iter()
+            .filter(|s| s.is_allocated() && s.start() >= KERNEL_BASE);
+        Some((higher().map(|s| s.start()).min()?,
+              higher().map(|s| s.end()).max()?))
     }
 }
 
@@ -249,6 +352,49 @@ impl ElfSection {
     pub fn end(&self) -> usize {
         self.start() + self.size() - 1
     }
+
+    /// Return physical address of section
+    ///
+    /// Assumes higher half sections are loaded `KERNEL_BASE` below their
+    /// address, and any others at their address, as arranged by the linker
+    /// script.
+    pub fn phys_start(&self) -> usize {
+        if self.start() >= KERNEL_BASE {
+            self.start() - KERNEL_BASE
+        } else {
+            self.start()
+        }
+    }
+
+    /// Return physical address of the last byte of section
+    pub fn phys_end(&self) -> usize {
+        self.phys_start() + self.size() - 1
+    }
+}
+
+impl Module {
+    /// Return physical address of the module
+    pub fn start(&self) -> usize {
+        self.mod_start as usize
+    }
+
+    /// Return size of the module
+    pub fn size(&self) -> usize {
+        self.mod_end.checked_sub(self.mod_start).unwrap_or(0) as usize
+    }
+
+    /// Return physical address of the last byte of the module
+    ///
+    /// Modules are never empty, see `MultibootTags::parse()`.
+    pub fn end(&self) -> usize {
+        self.mod_end as usize - 1
+    }
+
+    /// Returns the contents of the module
+    pub fn bytes(&self) -> &'static [u8] {
+        let ptr = phys_to_virt(self.start()) as *const u8;
+        unsafe { core::slice::from_raw_parts(ptr, self.size()) }
+    }
 }
 
 impl BiosBootDevice {
@@ -272,19 +418,34 @@ impl MMapEntry {
         self.ty == MMapEntryType::Free
     }
 
+    /// Does this entry describe zero bytes?
+    pub fn is_empty(&self) -> bool {
+        self.length == 0
+    }
+
+    /// Return address of the first byte of the region
     pub fn start(&self) -> usize {
         self.base_addr as usize
     }
 
+    /// Return size of the region
     pub fn size(&self) -> usize {
         self.length as usize
     }
 
+    /// Return address of the last byte of the region
+    ///
+    /// Meaningless for empty entries, see `is_empty()`.
     pub fn end(&self) -> usize {
-        self.start() + self.size() - 1
+        (self.start() + self.size()).wrapping_sub(1)
     }
 }
 
+/// Returns the non-empty free regions of a memory map
+pub fn iter_free(entries: &'static [MMapEntry]) -> impl Iterator<Item = &'static MMapEntry> {
+    entries.iter().filter(|entry| entry.is_free() && !entry.is_empty())
+}
+
 impl fmt::Debug for BiosBootDevice {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         write!(f, "BiosBootDevice {{ biosdev: 0x{:x}, partition: 0x{:x}, sub_partition: 0x{:x} }}",
@@ -1,16 +1,67 @@
 use core;
+use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+
+use spin::{Mutex, MutexGuard};
+
+use crate::sync::Once;
 
 use kalloc::{HEAP_SIZE, HEAP_START};
 
-use super::frame_allocator::{frame_alloc, PAGE_SIZE};
+use super::frame_allocator::{frame_alloc, frame_alloc_zeroed, frame_free, get_fallocator, Frame,
+                             HUGE_PAGE_SIZE, PAGE_SIZE};
+use super::intrinsics::{get_cpuid, invlpg, read_cr0, read_cr4, stmsr, write_cr0, wrmsr};
+use super::tlb;
+
+/// Start of the virtual window reserved for device memory. See `map_mmio()`
+pub const MMIO_START: usize = 0xffff_d000_0000_0000;
+/// Size of the virtual window reserved for device memory
+pub const MMIO_SIZE:  usize = 1024 * 1024 * 1024; // 1GiB
+
+/// Size of the physical memory mapped at `KERNEL_BASE`
+pub const PHYS_WINDOW_SIZE: usize = 2 * 1024 * 1024 * 1024; // 2GiB
+
+/// Pages reserved at the end of the MMIO window. See `with_temporary_map()`
+///
+/// `with_temporary_map2()` also uses the page following this one.
+pub const TEMPORARY_PAGE: usize = MMIO_START + MMIO_SIZE - 2 * PAGE_SIZE;
+
+/// Serializes use of the temporary pages
+static TEMPORARY_LOCK: Mutex<()> = Mutex::new(());
+
+/// Next unused virtual address in the MMIO window
+static MMIO_NEXT: AtomicUsize = AtomicUsize::new(MMIO_START);
 
 pub const PTE_ADDR_MASK: usize = 0x000f_ffff_ffff_f000;
 
+/// Physical address width assumed when cpuid does not report one
+const DEFAULT_PHYS_ADDR_BITS: u32 = 36;
+
+/// Can the processor address this physical memory?
+///
+/// Page entries hold up to 52 bits of address, but bits beyond the width the
+/// processor supports are reserved.
+pub fn paddr_supported(paddr: usize) -> bool {
+    let bits = get_cpuid().phys_addr_bits().unwrap_or(DEFAULT_PHYS_ADDR_BITS);
+    bits >= 64 || paddr >> bits == 0
+}
+
 pub const PT1_INDEX: usize = 0x1ff << (0 * 9 + 12);
 pub const PT2_INDEX: usize = 0x1ff << (1 * 9 + 12);
 pub const PT3_INDEX: usize = 0x1ff << (2 * 9 + 12);
 pub const PT4_INDEX: usize = 0x1ff << (3 * 9 + 12);
 
+/// PT4 entry mapping the PT4 itself, making every table of the active address
+/// space reachable at a fixed virtual address. See `table_vaddr_l4()` etc.
+///
+/// The last entry is taken by the kernel image at `KERNEL_BASE`, so the one
+/// before it is used instead.
+pub const RECURSIVE_INDEX: usize = 510;
+/// PT4 entry of the active tables used to temporarily reach inactive tables
+pub const FOREIGN_INDEX: usize = 509;
+
+/// Serializes use of `FOREIGN_INDEX`
+static FOREIGN_LOCK: Mutex<()> = Mutex::new(());
+
 bitflags! {
     pub flags PageFlags: usize {
         const NONE          = 0,
@@ -22,17 +73,97 @@ bitflags! {
         const ACCESSED      = 1 << 5,
         const DIRTY         = 1 << 6,
         const HUGE          = 1 << 7,
+        const PAT           = 1 << 7, // only in PT1 entries, where HUGE is unused
         const GLOBAL        = 1 << 8,
+        // bits 9-11 are available for software use
+        const COPY_ON_WRITE = 1 << 9,
         const NO_EXECUTE    = 1 << 63,
     }
 }
 
+/// Has PAT entry 4 been reprogrammed as write combining? See `init_pat()`
+static PAT_ENABLED: AtomicBool = AtomicBool::new(false);
+
+/// Memory types selectable through the PAT, NO_CACHE and WRITE_THROUGH bits
+///
+/// The bits index into the Page Attribute Table MSR. The default table
+/// selects the following types:
+///   - WB `NONE`
+///   - WT `WRITE_THROUGH`
+///   - UC- `NO_CACHE` (may be overridden by MTRRs)
+///   - UC `NO_CACHE | WRITE_THROUGH`
+///
+/// Entries 4-7 (`PAT` set) mirror entries 0-3 by default. `init_pat()`
+/// changes entry 4 to WC, selected by `PAT` alone. If the processor lacks PAT,
+/// write combining falls back to UC.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum CacheType {
+    WriteBack,
+    WriteThrough,
+    Uncacheable,
+    WriteCombining,
+}
+
+impl CacheType {
+    /// Returns the page entry bits selecting this memory type
+    pub fn flags(self) -> PageFlags {
+        match self {
+            CacheType::WriteBack      => NONE,
+            CacheType::WriteThrough   => WRITE_THROUGH,
+            CacheType::Uncacheable    => NO_CACHE | WRITE_THROUGH,
+            CacheType::WriteCombining => {
+                if PAT_ENABLED.load(Ordering::Relaxed) {
+                    PAT
+                } else {
+                    NO_CACHE | WRITE_THROUGH
+                }
+            }
+        }
+    }
+}
+
+/// Programs PAT entry 4 as write combining, leaving the others at defaults
+///
+/// Must be done before any mappings use the `PAT` bit, and on every core, as
+/// mismatched memory types across cores are undefined. See `smp::ap_entry()`.
+pub fn init_pat() {
+    const IA32_PAT: u32 = 0x277;
+    // PA0-3 and PA5-7 hold the power-on defaults (WB, WT, UC-, UC)
+    const PAT_VALUE: u64 = 0x0007_0401_0007_0406;
+    if get_cpuid().pat() {
+        wrmsr(IA32_PAT, PAT_VALUE);
+        PAT_ENABLED.store(true, Ordering::Relaxed);
+    }
+}
+
+/// Has EFER.NXE been set? See `init_nx()`
+static NX_ENABLED: AtomicBool = AtomicBool::new(false);
+
+/// Allows `NO_EXECUTE` in page entries, if the processor supports it
+///
+/// Without EFER.NXE bit 63 of page entries is reserved and setting it faults.
+fn init_nx() {
+    const IA32_EFER: u32 = 0xC0000080;
+    const NXE: usize = 11;
+    if get_cpuid().nx() {
+        stmsr(IA32_EFER, NXE);
+        NX_ENABLED.store(true, Ordering::Relaxed);
+    }
+}
+
+/// May page entries use `NO_EXECUTE`?
+pub fn nx_enabled() -> bool {
+    NX_ENABLED.load(Ordering::Relaxed)
+}
+
 struct PageEntry<L: PageLevel> {
     pub value: usize,
     level: core::marker::PhantomData<L>,
 }
 
 pub const NUM_ENTRIES: usize = 512;
+/// Index of the first PT4 entry belonging to the higher half (kernel) addresses
+pub const KERNEL_PT4_START: usize = NUM_ENTRIES / 2;
 struct PageTable<L: PageLevel> {
     entries: [PageEntry<L>; NUM_ENTRIES],
 }
@@ -66,7 +197,11 @@ impl NextPageLevel for Level4  { type Next = Level3; }
 
 impl<L: PageLevel> PageEntry<L> {
     fn set_addr(&mut self, addr: usize) {
-        self.value = addr & PTE_ADDR_MASK;
+        self.value = (self.value & !PTE_ADDR_MASK) | (addr & PTE_ADDR_MASK);
+    }
+
+    fn clear(&mut self) {
+        self.value = 0;
     }
 
     fn get_addr(&self) -> usize {
@@ -94,90 +229,468 @@ impl<L: PageLevel> PageEntry<L> {
     fn points_to_table(&self) -> bool {
         self.present() && !self.terminal()
     }
+
+    /// Prints the entry found at an index of its table, see `PT4::dump()`
+    fn dump(&self, index: usize) {
+        println!("PT{}[{:3}] 0x{:016x} present={} addr=0x{:x} {:?}",
+                 L::LEVEL, index, self.value, self.present(), self.get_addr(), self.flags());
+    }
 }
 
 impl<L: PageLevel> PageTable<L> {
-    fn new() ->  *mut PageTable<L> {
-        let mut frame = frame_alloc();
-        frame.clear();
-        frame.addr() as *mut PageTable<L>
+    fn zero(&mut self) {
+        for entry in self.entries.iter_mut() {
+            entry.clear();
+        }
+    }
+
+    fn is_empty(&self) -> bool {
+        self.entries.iter().all(|entry| !entry.present())
+    }
+
+    /// Fills a new table with the mappings of the huge page it replaces
+    ///
+    /// `huge` is the entry of the huge page, one level up. Stale TLB entries
+    /// for the huge page translate identically, so need not be flushed.
+    fn split_from(&mut self, huge: usize) {
+        const HUGE_PAT: usize = 1 << 12; // PAT bit of huge page entries
+        let page_size = PAGE_SIZE << (9 * (L::LEVEL - 1));
+        let base = huge & PTE_ADDR_MASK & !(page_size * NUM_ENTRIES - 1);
+        let mut flags = huge & !PTE_ADDR_MASK;
+        if L::LEVEL == 1 {
+            // PT1 entries have no HUGE bit and keep PAT in its place
+            flags &= !HUGE.bits();
+            if huge & HUGE_PAT != 0 {
+                flags |= PAT.bits();
+            }
+        } else {
+            flags |= huge & HUGE_PAT;
+        }
+        for (i, entry) in self.entries.iter_mut().enumerate() {
+            entry.value = (base + i * page_size) | flags;
+        }
     }
 }
 
 impl<L: MappableLevel> PageTable<L> {
-    fn map_mem(&mut self, index: usize, paddr: usize, flags: PageFlags) {
-        self.entries[index].set_addr(paddr);
-        self.entries[index].value |= flags.bits();
-        self.entries[index].value |= PRESENT.bits();
+    /// Maps memory at the index, replacing any previous mapping
+    ///
+    /// Returns true if a present mapping was replaced, in which case stale
+    /// TLB entries must be flushed.
+    fn map_mem(&mut self, index: usize, paddr: usize, flags: PageFlags) -> bool {
+        assert!(paddr_supported(paddr),
+                "physical address 0x{:x} exceeds the width supported by the processor", paddr);
+        assert!(!flags.contains(NO_EXECUTE) || nx_enabled(), "NO_EXECUTE used without NX");
+        let ref mut entry = self.entries[index];
+        let replaced = entry.present();
+        entry.clear(); // discard the old address and flags
+        entry.set_addr(paddr);
+        entry.value |= flags.bits();
+        entry.value |= PRESENT.bits();
         if L::can_be_huge() { // allow 2MB / 1GB pages
-            self.entries[index].value |= HUGE.bits();
+            entry.value |= HUGE.bits();
         }
+        replaced
+    }
+}
+
+impl PageTable<Level4> {
+    /// Points an entry at a PT4, which is then reachable recursively
+    ///
+    /// Unlike `map_table()` the entry is not USER, keeping the page tables
+    /// out of reach of userspace.
+    fn map_self(&mut self, index: usize, paddr: usize) {
+        self.entries[index].clear();
+        self.entries[index].set_addr(paddr);
+        self.entries[index].value |= (PRESENT | WRITE).bits();
     }
 }
 
 impl<L: NextPageLevel> PageTable<L> {
-    fn map_table<'a>(&mut self, index: usize, table: *const PageTable<L::Next>) {
-        self.entries[index].set_addr(table as usize);
+    fn map_table(&mut self, index: usize, paddr: usize) {
+        self.entries[index].set_addr(paddr);
         // if the entry in PT4 is not marked USER, then none of the pages mapped
         // in any lower tables (PT3-1) can be USER. Thus, mark all entries
         // pointing to tables as USER. Similar problem for WRITE.
-        // Note: ring0 ignores WRITE flag unless CR0.WP is set
-        self.entries[index].value |= (PRESENT | USER | WRITE).bits();
+        // Note: ring0 ignores WRITE flag unless CR0.WP is set, see
+        // `enable_write_protect()`
+        // The higher half is shared with every address space, so its PT4
+        // entries are never USER, keeping all kernel memory out of reach of
+        // userspace whatever the lower tables say.
+        let kernel_half = L::LEVEL == 4 && index >= KERNEL_PT4_START;
+        let flags = if kernel_half { PRESENT | WRITE } else { PRESENT | USER | WRITE };
+        self.entries[index].value |= flags.bits();
+    }
+
+    /// Returns the virtual address of the table referenced by an entry
+    ///
+    /// Only meaningful if this table was itself reached through the
+    /// recursive mapping: one more trip through the recursive entry shifts
+    /// the indices down a level.
+    fn table_addr(&self, index: usize) -> usize {
+        sign_extend(((self as *const _ as usize) << 9) | (index << 12))
+    }
+
+    fn get_table(&self, index: usize) -> Option<&PageTable<L::Next>> {
+        let ref entry = self.entries[index];
+        if !entry.points_to_table() { return None; }
+
+        unsafe { Some(&*(self.table_addr(index) as *const PageTable<_>)) }
     }
 
     fn get_table_mut(&mut self, index: usize) -> Option<&mut PageTable<L::Next>> {
         let ref entry = self.entries[index];
         if !entry.points_to_table() { return None; }
 
-        unsafe { Some(&mut *(entry.get_addr() as *mut PageTable<_>)) }
+        unsafe { Some(&mut *(self.table_addr(index) as *mut PageTable<_>)) }
+    }
+
+    /// Frees the table at the index if it no longer maps anything
+    fn reap_table(&mut self, index: usize) {
+        let empty = self.get_table(index).map(|table| table.is_empty()).unwrap_or(false);
+        if empty {
+            let addr = self.entries[index].get_addr();
+            self.entries[index].clear();
+            invlpg(self.table_addr(index)); // forget the recursive mapping
+            frame_free(Frame::containing(addr));
+        }
     }
 
+    /// Returns the table at the index, creating it if necessary
+    ///
+    /// A huge page mapped at the index is split into a table of smaller
+    /// pages mapping the same memory.
     fn get_new_table(&mut self, index: usize) -> &mut PageTable<L::Next> {
-        if self.entries[index].present() {
-            self.get_table_mut(index).expect("Memory already mapped to")
+        if self.entries[index].points_to_table() {
+            return self.get_table_mut(index).unwrap();
+        }
+
+        let huge = self.entries[index].value;
+        let split = self.entries[index].present(); // and so terminal
+        self.entries[index].clear();
+        self.map_table(index, frame_alloc().addr());
+        if split {
+            invlpg(self.table_addr(index)); // was a mapping of the huge page
+        }
+        let table = self.get_table_mut(index).unwrap();
+        if split {
+            table.split_from(huge);
         } else {
-            let pt = PageTable::new();
-            self.map_table(index, pt);
-            self.get_table_mut(index).unwrap()
+            table.zero();
+        }
+        table
+    }
+}
+
+/// The kernel's tables, which every address space shares the higher half of
+static KERNEL_PT4: Once<Mutex<PT4>> = Once::new();
+
+/// Returns the kernel's tables, for mappings made after boot
+///
+/// Mapping may allocate frames, so the frame allocator must not be locked
+/// while calling this. Only the higher half entries present when an address
+/// space was created are shared with it, so kernel mappings should stay
+/// within regions whose PT3 already exists, such as the MMIO window.
+///
+/// The holder may be waiting for a TLB shootdown to be acknowledged, so
+/// shootdowns targeting this core are acknowledged while the lock is busy.
+/// This holds even with interrupts disabled.
+pub fn kernel_tables<'a>() -> MutexGuard<'a, PT4> {
+    let tables = KERNEL_PT4.expect("Kernel page tables");
+    loop {
+        if let Some(guard) = tables.try_lock() {
+            return guard;
         }
+        tlb::acknowledge();
     }
 }
 
-pub unsafe fn initialize() -> PT4 {
+/// Builds and loads the kernel tables
+///
+/// Only 4-level paging is supported: every table walk here assumes the PT4
+/// is the root. Booting with 5-level paging enabled is refused, even though
+/// the processor may report `la57` support.
+pub unsafe fn initialize() {
     use super::KERNEL_BASE;
     const G: usize = 0x40000000;
+    const CR4_LA57: usize = 1 << 12;
 
-    let mut pt4 = PT4::new();
-    pt4.map_to_1g(KERNEL_BASE,         0, USER | WRITE);
-    pt4.map_to_1g(KERNEL_BASE + 1*G, 1*G, USER | WRITE);
+    assert!(read_cr4() & CR4_LA57 == 0, "5-level paging is not supported");
 
-    // map heap
-    for i in 0..HEAP_SIZE / PAGE_SIZE {
-        let addr = i * PAGE_SIZE + HEAP_START;
+    init_pat();
+    init_nx();
+
+    let mut pt4 = PT4::new();
+    pt4.map_to_1g(KERNEL_BASE,         0, WRITE);
+    pt4.map_to_1g(KERNEL_BASE + 1*G, 1*G, WRITE);
+
+    // keep the identity map of the boot tables until `remove_identity_map()`,
+    // since the multiboot tags are still referenced by physical address
+    pt4.map_to_1g(  0,   0, WRITE);
+    pt4.map_to_1g(1*G, 1*G, WRITE);
+
+    // map heap, using 2MiB pages where alignment and size permit
+    let mut addr = HEAP_START;
+    let heap_end = HEAP_START + HEAP_SIZE;
+    while addr < heap_end {
+        if addr % HUGE_PAGE_SIZE == 0 && heap_end - addr >= HUGE_PAGE_SIZE {
+            let huge = get_fallocator().alloc_huge();
+            if let Some(frame) = huge {
+                pt4.map_to_2m(addr, frame.addr(), WRITE);
+                addr += HUGE_PAGE_SIZE;
+                continue;
+            }
+        }
         pt4.map_4k(addr, WRITE);
+        addr += PAGE_SIZE;
     }
 
+    // create the PT3 of the MMIO window now so every address space shares it,
+    // and the PT1 of the temporary pages so mapping them never allocates
+    pt4.with_tables(|table| {
+        table.get_new_table(get_pt4_index(MMIO_START));
+        table.get_new_table(get_pt4_index(TEMPORARY_PAGE))
+            .get_new_table(get_pt3_index(TEMPORARY_PAGE))
+            .get_new_table(get_pt2_index(TEMPORARY_PAGE));
+    });
+
     pt4.activate(); // flushes TLB
-    pt4
+    enable_write_protect();
+    KERNEL_PT4.initialize(Mutex::new(pt4));
 }
 
+/// Makes the kernel honor read-only mappings by setting CR0.WP
+///
+/// Without it, ring0 writes ignore the WRITE flag. Must only be enabled once
+/// every page the kernel writes to is mapped WRITE.
+fn enable_write_protect() {
+    const WP: usize = 1 << 16;
+    write_cr0(read_cr0() | WP);
+}
+
+/// Removes the identity map of low memory set up by `initialize()`
+///
+/// Must not be called while anything still refers to low memory by its
+/// physical address, such as the parsed multiboot tags.
+pub fn remove_identity_map() {
+    let pt4 = kernel_tables();
+    pt4.with_tables(|pt4| {
+        if let Some(pt3) = pt4.get_table_mut(0) {
+            pt3.entries[0].clear();
+            pt3.entries[1].clear();
+        }
+        pt4.reap_table(0);
+    });
+    flush_tlb();
+}
+
+/// Returns a virtual address through which physical memory may be accessed
+///
+/// Both the boot tables and `initialize()` map the first `PHYS_WINDOW_SIZE`
+/// bytes of physical memory at `KERNEL_BASE`.
+pub fn phys_to_virt(paddr: usize) -> usize {
+    use super::KERNEL_BASE;
+    assert!(paddr < PHYS_WINDOW_SIZE, "Physical address 0x{:x} outside kernel window", paddr);
+    KERNEL_BASE + paddr
+}
+
+/// Maps a region of device memory into the MMIO window of the kernel tables
+///
+/// The region is expanded to page boundaries and mapped uncacheable, since
+/// device registers must observe every access. Returns the virtual address
+/// corresponding to `paddr`.
+pub fn map_mmio(paddr: usize, size: usize) -> usize {
+    map_device(paddr, size, CacheType::Uncacheable)
+}
+
+/// Maps a region of device memory into the MMIO window with a memory type
+///
+/// Like `map_mmio()`, but for memory which tolerates caching, such as a
+/// framebuffer mapped `WriteCombining`.
+pub fn map_device(paddr: usize, size: usize, cache: CacheType) -> usize {
+    assert!(size > 0, "Empty MMIO region");
+    let offset = paddr & (PAGE_SIZE - 1);
+    let pages = (offset + size + PAGE_SIZE - 1) / PAGE_SIZE;
+
+    let vaddr = reserve_mmio(pages);
+    kernel_tables().map_device(vaddr + offset, paddr, size, cache);
+    vaddr + offset
+}
+
+/// Reserves `pages` pages of the MMIO window, returning the first
+///
+/// Nothing is mapped. The window is never reclaimed, so addresses are not
+/// reused even once unmapped.
+pub fn reserve_mmio(pages: usize) -> usize {
+    let vaddr = MMIO_NEXT.fetch_add(pages * PAGE_SIZE, Ordering::SeqCst);
+    assert!(vaddr + pages * PAGE_SIZE <= TEMPORARY_PAGE, "MMIO window exhausted");
+    vaddr
+}
+
+/// Returns the flags with which device memory is mapped
+pub fn mmio_flags() -> PageFlags {
+    device_flags(CacheType::Uncacheable)
+}
+
+/// Returns the flags with which device memory of a memory type is mapped
+fn device_flags(cache: CacheType) -> PageFlags {
+    let mut flags = WRITE | cache.flags();
+    if nx_enabled() {
+        flags.insert(NO_EXECUTE);
+    }
+    flags
+}
+
+/// Runs a function with the frame containing `paddr` temporarily mapped
+///
+/// Unlike `phys_to_virt()` this reaches frames anywhere in physical memory.
+/// The function is given the virtual address corresponding to `paddr`, which
+/// is only valid until it returns.
+///
+/// The tables of the temporary pages exist from `initialize()` on, so nothing
+/// is allocated and this may be used with the frame allocator locked. It must
+/// not be nested, nor used by the function.
+pub fn with_temporary_map<T, F: FnOnce(usize) -> T>(paddr: usize, f: F) -> T {
+    with_temporary_map2(paddr, paddr, |vaddr, _| f(vaddr))
+}
+
+/// Runs a function with the frames containing two addresses temporarily mapped
+///
+/// Like `with_temporary_map()`, but the function is given a virtual address
+/// for each, such as to copy one frame to another.
+pub fn with_temporary_map2<T, F>(first: usize, second: usize, f: F) -> T
+    where F: FnOnce(usize, usize) -> T
+{
+    let (first_offset, second_offset) = (first & (PAGE_SIZE - 1), second & (PAGE_SIZE - 1));
+    let second_page = TEMPORARY_PAGE + PAGE_SIZE;
+    let _lock = TEMPORARY_LOCK.lock();
+    // the window's PT3 is shared by every address space, so any tables will do
+    let mut pt4 = unsafe { PT4::current() };
+    pt4.map_to_4k(TEMPORARY_PAGE, first - first_offset, WRITE);
+    pt4.map_to_4k(second_page, second - second_offset, WRITE);
+    let result = f(TEMPORARY_PAGE + first_offset, second_page + second_offset);
+    pt4.unmap(TEMPORARY_PAGE);
+    pt4.unmap(second_page);
+    result
+}
+
+/// Returns the virtual address reached by walking the given PT4-PT1 indices
+fn recursive_addr(pt4: usize, pt3: usize, pt2: usize, pt1: usize) -> usize {
+    sign_extend((pt4 << 39) | (pt3 << 30) | (pt2 << 21) | (pt1 << 12))
+}
+
+/// Copies bit 47 of an address into bits 48-63, making it canonical
+fn sign_extend(addr: usize) -> usize {
+    const LOW_HALF: usize = 0x0000_ffff_ffff_ffff;
+    if addr & (1 << 47) != 0 {
+        addr | !LOW_HALF
+    } else {
+        addr & LOW_HALF
+    }
+}
+
+/// Returns the virtual address of the active PT4
+///
+/// Identical for every address; the argument is taken for symmetry with the
+/// lower levels.
+pub fn table_vaddr_l4(_vaddr: usize) -> usize {
+    recursive_addr(RECURSIVE_INDEX, RECURSIVE_INDEX, RECURSIVE_INDEX, RECURSIVE_INDEX)
+}
+
+/// Returns the virtual address of the active PT3 covering an address
+pub fn table_vaddr_l3(vaddr: usize) -> usize {
+    recursive_addr(RECURSIVE_INDEX, RECURSIVE_INDEX, RECURSIVE_INDEX, get_pt4_index(vaddr))
+}
+
+/// Returns the virtual address of the active PT2 covering an address
+pub fn table_vaddr_l2(vaddr: usize) -> usize {
+    recursive_addr(RECURSIVE_INDEX, RECURSIVE_INDEX, get_pt4_index(vaddr),
+                   get_pt3_index(vaddr))
+}
+
+/// Returns the virtual address of the active PT1 covering an address
+pub fn table_vaddr_l1(vaddr: usize) -> usize {
+    recursive_addr(RECURSIVE_INDEX, get_pt4_index(vaddr), get_pt3_index(vaddr),
+                   get_pt2_index(vaddr))
+}
+
+/// Reloads CR3, flushing all non-global TLB entries
+fn flush_tlb() {
+    unsafe { asm!("mov cr3, $0" :: "r"(read_cr3()) : "memory" : "intel", "volatile"); }
+}
+
+/// A page mapped by a set of tables, see `PT4::query()`
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct MappingInfo {
+    /// Start of the page
+    pub vaddr: usize,
+    /// Start of the memory it maps
+    pub paddr: usize,
+    /// Size of the page: `PAGE_SIZE`, `HUGE_PAGE_SIZE` or 1 GiB
+    pub size: usize,
+    pub flags: PageFlags,
+}
+
+/// A set of page tables, identified by the physical address of its PT4
+///
+/// Tables are never accessed at their physical address. The active tables
+/// are reached through `RECURSIVE_INDEX`; any others are temporarily mapped
+/// at `FOREIGN_INDEX` of the active PT4.
 pub struct PT4 {
-    table: core::ptr::Unique<PageTable<Level4>>,
+    frame: usize,
 }
 
 impl PT4 {
     pub fn new() -> PT4 {
+        let pt4 = PT4 { frame: frame_alloc_zeroed().addr() };
+        pt4.with_tables(|table| table.map_self(RECURSIVE_INDEX, pt4.frame));
+        pt4
+    }
+
+    /// Creates a new set of tables for a userspace address space
+    ///
+    /// The higher half entries are copied from the active tables, so all
+    /// kernel mappings are shared. Kernel tables created after this call will
+    /// not be visible in the new address space. The lower half is left empty
+    /// for user mappings.
+    pub fn new_user() -> PT4 {
+        let pt4 = PT4::new();
+        let kernel = unsafe { PT4::current() };
+        kernel.with_tables(|kernel| pt4.with_tables(|table| {
+            for i in KERNEL_PT4_START..NUM_ENTRIES {
+                if i != RECURSIVE_INDEX && i != FOREIGN_INDEX {
+                    table.entries[i].value = kernel.entries[i].value;
+                }
+            }
+        }));
+        pt4
+    }
+
+    /// Wraps the tables currently loaded in CR3
+    ///
+    /// The flag bits of CR3 are masked off. The returned handle borrows rather
+    /// than owns the tables, so it must not outlive them.
+    pub unsafe fn current() -> PT4 {
         PT4 {
-            table: unsafe { core::ptr::Unique::new_unchecked(PageTable::new()) },
+            frame: read_cr3(),
         }
     }
 
-    fn get(&self) -> &PageTable<Level4> {
-        unsafe { self.table.as_ref() }
-    }
+    /// Runs a function on the PT4, reached through the recursive mapping
+    fn with_tables<T, F: FnOnce(&mut PageTable<Level4>) -> T>(&self, f: F) -> T {
+        let active = unsafe { &mut *(table_vaddr_l4(0) as *mut PageTable<Level4>) };
+        if self.is_active() {
+            return f(active);
+        }
 
-    fn get_mut(&mut self) -> &mut PageTable<Level4> {
-        unsafe { self.table.as_mut() }
+        let _lock = FOREIGN_LOCK.lock();
+        active.map_self(FOREIGN_INDEX, self.frame);
+        let addr = recursive_addr(RECURSIVE_INDEX, RECURSIVE_INDEX, RECURSIVE_INDEX,
+                                  FOREIGN_INDEX);
+        let result = f(unsafe { &mut *(addr as *mut PageTable<Level4>) });
+        active.entries[FOREIGN_INDEX].clear();
+        flush_tlb(); // everything reached through the foreign entry is stale
+        result
     }
 
     pub fn map_4k(&mut self, vaddr: usize, flags: PageFlags) {
@@ -185,29 +698,239 @@ impl PT4 {
     }
 
     pub fn map_to_4k(&mut self, vaddr: usize, paddr: usize, flags: PageFlags) {
-        self.get_mut()
-            .get_new_table(get_pt4_index(vaddr))
-            .get_new_table(get_pt3_index(vaddr))
-            .get_new_table(get_pt2_index(vaddr))
-            .map_mem(get_pt1_index(vaddr), paddr, flags);
+        let replaced = self.with_tables(|pt4| {
+            pt4.get_new_table(get_pt4_index(vaddr))
+                .get_new_table(get_pt3_index(vaddr))
+                .get_new_table(get_pt2_index(vaddr))
+                .map_mem(get_pt1_index(vaddr), paddr, flags)
+        });
+        if replaced {
+            tlb::shootdown(vaddr);
+        }
+    }
+
+    /// Maps a 4k page using the specified memory type
+    ///
+    /// Any caching bits in `flags` are replaced by those of `cache`.
+    pub fn map_to_4k_cached(&mut self, vaddr: usize, paddr: usize, flags: PageFlags,
+                            cache: CacheType) {
+        let flags = (flags - (PAT | NO_CACHE | WRITE_THROUGH)) | cache.flags();
+        self.map_to_4k(vaddr, paddr, flags)
     }
 
     pub fn map_to_2m(&mut self, vaddr: usize, paddr: usize, flags: PageFlags) {
-        self.get_mut()
-            .get_new_table(get_pt4_index(vaddr))
-            .get_new_table(get_pt3_index(vaddr))
-            .map_mem(get_pt2_index(vaddr), paddr, flags);
+        let replaced = self.with_tables(|pt4| {
+            pt4.get_new_table(get_pt4_index(vaddr))
+                .get_new_table(get_pt3_index(vaddr))
+                .map_mem(get_pt2_index(vaddr), paddr, flags)
+        });
+        if replaced {
+            tlb::shootdown(vaddr);
+        }
     }
 
     pub fn map_to_1g(&mut self, vaddr: usize, paddr: usize, flags: PageFlags) {
-        self.get_mut()
-            .get_new_table(get_pt4_index(vaddr))
-            .map_mem(get_pt3_index(vaddr), paddr, flags);
+        let replaced = self.with_tables(|pt4| {
+            pt4.get_new_table(get_pt4_index(vaddr))
+                .map_mem(get_pt3_index(vaddr), paddr, flags)
+        });
+        if replaced {
+            tlb::shootdown(vaddr);
+        }
+    }
+
+    /// Maps device memory at `vaddr`, uncacheable and never executable
+    ///
+    /// Both addresses must have the same offset within a page. The range is
+    /// expanded to page boundaries, using 2MiB pages where both addresses are
+    /// suitably aligned.
+    pub fn map_mmio(&mut self, vaddr: usize, paddr: usize, size: usize) {
+        self.map_device(vaddr, paddr, size, CacheType::Uncacheable);
+    }
+
+    /// Maps device memory at `vaddr` with a memory type, never executable
+    ///
+    /// See `map_mmio()`.
+    pub fn map_device(&mut self, vaddr: usize, paddr: usize, size: usize, cache: CacheType) {
+        assert!(size > 0, "Empty MMIO region");
+        let offset = paddr & (PAGE_SIZE - 1);
+        assert!(vaddr & (PAGE_SIZE - 1) == offset, "MMIO addresses misaligned");
+
+        let flags = device_flags(cache);
+        let (mut vaddr, mut paddr) = (vaddr - offset, paddr - offset);
+        let end = paddr + offset + size;
+        while paddr < end {
+            let huge = vaddr % HUGE_PAGE_SIZE == 0 && paddr % HUGE_PAGE_SIZE == 0
+                && end - paddr >= HUGE_PAGE_SIZE;
+            let page_size = if huge { HUGE_PAGE_SIZE } else { PAGE_SIZE };
+            if huge {
+                self.map_to_2m(vaddr, paddr, flags);
+            } else {
+                self.map_to_4k(vaddr, paddr, flags);
+            }
+            vaddr += page_size;
+            paddr += page_size;
+        }
+    }
+
+    /// Removes the 4k page mapped at an address
+    ///
+    /// Returns the physical address that was mapped; freeing that frame is
+    /// the responsibility of the caller. Page tables left empty are returned
+    /// to the frame allocator.
+    pub fn unmap(&mut self, vaddr: usize) -> Option<usize> {
+        let paddr = self.with_tables(|pt4| {
+            let paddr = {
+                let entry = &mut pt4
+                    .get_table_mut(get_pt4_index(vaddr))?
+                    .get_table_mut(get_pt3_index(vaddr))?
+                    .get_table_mut(get_pt2_index(vaddr))?
+                    .entries[get_pt1_index(vaddr)];
+                if !entry.present() {
+                    return None;
+                }
+                let paddr = entry.get_addr();
+                entry.clear();
+                paddr
+            };
+            PT4::reap_empty_tables(pt4, vaddr);
+            Some(paddr)
+        })?;
+
+        tlb::shootdown(vaddr); // also flushes cached upper level entries
+        Some(paddr)
+    }
+
+    /// Frees the tables along the walk to an address that have become empty
+    ///
+    /// The PT4 itself is never freed, nor are tables in the higher half since
+    /// those are shared with every address space.
+    fn reap_empty_tables(pt4: &mut PageTable<Level4>, vaddr: usize) {
+        let index = get_pt4_index(vaddr);
+        if index >= KERNEL_PT4_START {
+            return;
+        }
+
+        if let Some(pt3) = pt4.get_table_mut(index) {
+            if let Some(pt2) = pt3.get_table_mut(get_pt3_index(vaddr)) {
+                pt2.reap_table(get_pt2_index(vaddr)); // PT1
+            }
+            pt3.reap_table(get_pt3_index(vaddr)); // PT2
+        }
+        pt4.reap_table(index); // PT3
+    }
+
+    /// Finds the terminal entry mapping an address
+    ///
+    /// Returns the raw entry and the size of the page it maps.
+    fn walk(&self, vaddr: usize) -> Option<(usize, usize)> {
+        self.with_tables(|pt4| {
+            let pt3 = pt4.get_table(get_pt4_index(vaddr))?;
+            let entry = &pt3.entries[get_pt3_index(vaddr)];
+            if entry.present() && entry.terminal() {
+                return Some((entry.value, 1024 * 1024 * 1024));
+            }
+
+            let pt2 = pt3.get_table(get_pt3_index(vaddr))?;
+            let entry = &pt2.entries[get_pt2_index(vaddr)];
+            if entry.present() && entry.terminal() {
+                return Some((entry.value, HUGE_PAGE_SIZE));
+            }
+
+            let pt1 = pt2.get_table(get_pt2_index(vaddr))?;
+            let entry = &pt1.entries[get_pt1_index(vaddr)];
+            if entry.present() {
+                return Some((entry.value, PAGE_SIZE));
+            }
+            None
+        })
+    }
+
+    /// Prints every entry the processor would use to translate an address
+    ///
+    /// The walk stops at the first entry which is not present or maps a page.
+    pub fn dump(&self, vaddr: usize) {
+        println!("page walk of 0x{:x} in tables at 0x{:x}", vaddr, self.frame);
+        self.with_tables(|pt4| {
+            pt4.entries[get_pt4_index(vaddr)].dump(get_pt4_index(vaddr));
+            let pt3 = pt4.get_table(get_pt4_index(vaddr))?;
+            pt3.entries[get_pt3_index(vaddr)].dump(get_pt3_index(vaddr));
+            let pt2 = pt3.get_table(get_pt3_index(vaddr))?;
+            pt2.entries[get_pt2_index(vaddr)].dump(get_pt2_index(vaddr));
+            let pt1 = pt2.get_table(get_pt2_index(vaddr))?;
+            pt1.entries[get_pt1_index(vaddr)].dump(get_pt1_index(vaddr));
+            Some(())
+        });
+    }
+
+    /// Returns the physical address mapped at a virtual address
+    pub fn translate(&self, vaddr: usize) -> Option<usize> {
+        let (value, size) = self.walk(vaddr)?;
+        Some((value & PTE_ADDR_MASK & !(size - 1)) | (vaddr & (size - 1)))
+    }
+
+    /// Returns the flags of the page mapped at a virtual address
+    pub fn translate_flags(&self, vaddr: usize) -> Option<PageFlags> {
+        self.walk(vaddr).map(|(value, _)| PageFlags::from_bits_truncate(value))
+    }
+
+    /// Describes the page mapped at a virtual address, whatever its size
+    pub fn query(&self, vaddr: usize) -> Option<MappingInfo> {
+        let (value, size) = self.walk(vaddr)?;
+        Some(MappingInfo {
+            vaddr: vaddr & !(size - 1),
+            paddr: value & PTE_ADDR_MASK & !(size - 1),
+            size: size,
+            flags: PageFlags::from_bits_truncate(value),
+        })
+    }
+
+    /// Replaces the flags of a mapped 4k page, keeping its frame
+    ///
+    /// Returns false if no 4k page is mapped at this address.
+    pub fn update_flags(&mut self, vaddr: usize, flags: PageFlags) -> bool {
+        assert!(!flags.contains(NO_EXECUTE) || nx_enabled(), "NO_EXECUTE used without NX");
+        let updated = self.with_tables(|pt4| {
+            let entry = pt4
+                .get_table_mut(get_pt4_index(vaddr))
+                .and_then(|pt3| pt3.get_table_mut(get_pt3_index(vaddr)))
+                .and_then(|pt2| pt2.get_table_mut(get_pt2_index(vaddr)))
+                .map(|pt1| &mut pt1.entries[get_pt1_index(vaddr)]);
+
+            match entry {
+                Some(entry) if entry.present() => {
+                    entry.value = entry.get_addr() | (flags | PRESENT).bits();
+                    true
+                }
+                _ => false,
+            }
+        });
+        if updated {
+            tlb::shootdown(vaddr);
+        }
+        updated
     }
 
     pub fn activate(&self) {
-        unsafe { asm!("mov cr3, $0" :: "r"(self.get()) :: "intel"); }
+        unsafe { asm!("mov cr3, $0" :: "r"(self.frame) :: "intel"); }
+    }
+
+    /// Returns the physical address of the PT4
+    pub fn addr(&self) -> usize {
+        self.frame
     }
+
+    /// Are these the tables loaded in CR3?
+    pub fn is_active(&self) -> bool {
+        self.frame == read_cr3()
+    }
+}
+
+/// Returns the physical address of the active PT4
+pub fn read_cr3() -> usize {
+    let cr3: usize;
+    unsafe { asm!("mov $0, cr3" : "=r"(cr3) ::: "intel"); }
+    cr3 & PTE_ADDR_MASK
 }
 
 pub fn get_pt1_index(val: usize) -> usize {
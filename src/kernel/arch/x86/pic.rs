@@ -13,12 +13,19 @@
 /// use interrupts  0x70-0x77. Upon initialization we remap the PICs to use
 /// interrupts 0x20-0x2f.
 ///
-/// Currently we process the following IRQs:
+/// All IRQs enter through a shared ISR which dispatches to the handler
+/// registered with `register_irq()`. By default we process the following
+/// IRQs:
 ///   - IRQ0 System Timer
 ///   - IRQ1 PS/2 Keyboard Input
 
-use super::interrupts;
-use super::intrinsics::{inb, outb};
+use super::interrupts::{self, InterruptState};
+use super::pit;
+use super::port::Port;
+use super::scheduler;
+use super::smp;
+use super::stacks;
+use super::watchdog;
 
 /// Interrupt vector offset of the master PIC
 pub const PIC1_OFFSET: u8 = 0x20;
@@ -30,30 +37,50 @@ static PIC1: Pic = Pic::new(0x20);
 /// Wrapper for slave PIC
 static PIC2: Pic = Pic::new(0xa0);
 
+/// Command ending the interrupt in service
+const EOI: u8 = 0x20;
+/// Command selecting the in-service register for the next read
+const READ_ISR: u8 = 0x0b;
+/// The master IRQ the slave PIC cascades through
+const CASCADE_IRQ: u8 = 2;
+
+/// Number of IRQs served by both PICs
+pub const NUM_IRQS: usize = 16;
+
+/// Handlers for each IRQ. See `register_irq()`
+static mut IRQ_HANDLERS: [Option<IrqHandler>; NUM_IRQS] = [None; NUM_IRQS];
+
+/// A function handling an IRQ, given the state of the interrupted code
+pub type IrqHandler = fn(&InterruptState);
+
 /// Wrapper around a PIC
 struct Pic {
-    port: u16,
+    command: Port<u8>,
+    data: Port<u8>,
 }
 
 impl Pic {
     /// Creates a wrapper around the PIC on the specified port
     const fn new(port: u16) -> Pic {
-        Pic { port: port }
+        Pic {
+            command: Port::new(port),
+            data: Port::new(port + 1),
+        }
     }
 
     /// Writes byte to command port of PIC
     fn write_command(&self, b: u8) {
-        outb(self.port, b)
+        self.command.write(b)
     }
 
     /// Writes byte to data port of PIC
     fn write_data(&self, b: u8) {
-        outb(self.port + 1, b)
+        self.data.write(b)
     }
 
     /// Reads input from PIC
     fn read(&self) -> u8 {
-        inb(self.port)
+        self.command.read()
     }
 }
 
@@ -70,6 +97,18 @@ pub fn initialize() {
 
     const ICW4_8086: u8 = 0x01; // x86 compatibility mode
 
+    // the PICs raise IRQs as soon as they are initialized, so the handlers
+    // must be in place first
+    unsafe {
+        IRQ_HANDLERS[0] = Some(system_timer);
+        IRQ_HANDLERS[1] = Some(keyboard_input);
+    }
+    let mut idt = interrupts::Idt::current().unwrap();
+    for i in 0..NUM_IRQS {
+        idt.register_isr(PIC1_OFFSET as usize + i, irq_dispatch);
+    }
+    idt.load();
+
     // initialize master
     PIC1.write_command(ICW1_INIT);
     PIC1.write_data(PIC1_OFFSET);
@@ -82,22 +121,32 @@ pub fn initialize() {
     PIC2.write_data(ICW3_PIC2);
     PIC2.write_data(ICW4_8086);
 
-    let mut idt = interrupts::Idt::current().unwrap();
-    idt.register_isr(0x20, system_timer);
-    idt.register_isr(0x21, keyboard_input);
-    idt.load();
     interrupts::enable();
 }
 
+/// Registers the function to be called when an IRQ is raised
+///
+/// Replaces any previous handler. The interrupt is acknowledged after the
+/// handler returns, so handlers must not send an EOI themselves.
+pub fn register_irq(irq: u8, handler: IrqHandler) {
+    assert!((irq as usize) < NUM_IRQS, "Invalid IRQ {}", irq);
+    interrupts::without_interrupts(|| unsafe {
+        IRQ_HANDLERS[irq as usize] = Some(handler);
+    });
+}
+
+/// Reads the in-service register of a PIC, the IRQs being handled
+fn read_isr(pic: &Pic) -> u8 {
+    pic.write_command(READ_ISR);
+    pic.read()
+}
+
 /// Determines the IRQ number that was triggered
-#[allow(dead_code)]
 fn get_irq() -> Option<u8> {
-    // read service registers
-    PIC1.write_command(0x0b);
-    PIC2.write_command(0x0b);
-    let sr1 = PIC1.read() as u16;
-    let sr2 = PIC2.read() as u16;
-    let mut flags = (sr2 << 8) | sr1;
+    let sr1 = read_isr(&PIC1) as u16;
+    let sr2 = read_isr(&PIC2) as u16;
+    // IRQ2 is in service whenever the slave is, so ignore it
+    let mut flags = (sr2 << 8) | (sr1 & !(1 << CASCADE_IRQ));
 
     // convert bitmask to IRQ number
     for i in 0..16 {
@@ -112,7 +161,6 @@ fn get_irq() -> Option<u8> {
 
 /// Informs the PIC that we have finished processing an interrupt
 fn send_eoi(irq: u8) {
-    const EOI: u8 = 0x20;
     if irq >= 8 {
         PIC2.write_command(EOI);
     }
@@ -120,15 +168,39 @@ fn send_eoi(irq: u8) {
 }
 
 isr_plain! {
-    // TODO re-enable pic timer
-    0x20 => fn system_timer(_state) {
-        // println!("timer");
-        send_eoi(0);
-    }
-    // TODO re-enable pic keyboard input
-    0x21 => fn keyboard_input(_state) {
-        // let sc = inb(0x60);
-        // println!("keyboard {:#x}", sc);
-        send_eoi(1);
+    // the vector recorded in the interrupt state is meaningless, see get_irq()
+    0x20 => fn irq_dispatch(state) {
+        let irq = match get_irq() {
+            Some(irq) => irq,
+            None => {
+                // a spurious IRQ15 is not in service on the slave, but the
+                // cascade through the master is, and blocks IRQ2-15 until ended
+                if read_isr(&PIC1) & (1 << CASCADE_IRQ) != 0 {
+                    PIC1.write_command(EOI);
+                }
+                return;
+            }
+        };
+        if let Some(handler) = unsafe { IRQ_HANDLERS[irq as usize] } {
+            handler(state);
+        }
+        send_eoi(irq);
+        scheduler::preempt(state);
     }
 }
+
+// TODO re-enable pic timer
+fn system_timer(state: &InterruptState) {
+    // println!("timer");
+    stacks::check_canaries();
+    smp::check_canaries();
+    pit::tick();
+    watchdog::tick(state);
+    scheduler::tick();
+}
+
+// TODO re-enable pic keyboard input
+fn keyboard_input(_state: &InterruptState) {
+    // let sc = inb(0x60);
+    // println!("keyboard {:#x}", sc);
+}
@@ -0,0 +1,70 @@
+//! Programmable Interval Timer
+//!
+//! Channel 0 of the PIT raises IRQ0 at a programmable rate, derived from its
+//! 1.193182 MHz input clock. Every such interrupt is a system timer tick. The
+//! tick counter wraps on overflow, so ticks must be compared with `reached()`.
+
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+use crate::clock::{ClockSource, NS_PER_SEC};
+
+use super::intrinsics::outb;
+
+/// Frequency in Hz of the clock driving the PIT
+pub const PIT_FREQUENCY: usize = 1_193_182;
+/// Number of timer ticks per second
+pub const TICK_HZ: usize = 100;
+
+const CHANNEL0: u16 = 0x40;
+const COMMAND:  u16 = 0x43;
+
+/// Number of ticks since `initialize()`
+static TICKS: AtomicUsize = AtomicUsize::new(0);
+
+/// The tick counter as a clock source, with a resolution of one tick
+pub struct PitClock;
+
+pub static PIT_CLOCK: PitClock = PitClock;
+
+impl ClockSource for PitClock {
+    fn now_ns(&self) -> u64 {
+        ticks() as u64 * (NS_PER_SEC / TICK_HZ as u64)
+    }
+
+    fn frequency(&self) -> u64 {
+        TICK_HZ as u64
+    }
+}
+
+/// Programs channel 0 to tick at `TICK_HZ`
+pub fn initialize() {
+    const CHANNEL0_SQUARE_WAVE: u8 = 0x36; // low then high byte, mode 3
+
+    let divisor = PIT_FREQUENCY / TICK_HZ;
+    outb(COMMAND, CHANNEL0_SQUARE_WAVE);
+    outb(CHANNEL0, divisor as u8);
+    outb(CHANNEL0, (divisor >> 8) as u8);
+}
+
+/// Counts a timer tick. Called by the IRQ0 handler.
+pub fn tick() {
+    TICKS.fetch_add(1, Ordering::Relaxed);
+}
+
+/// Returns the number of ticks since `initialize()`
+pub fn ticks() -> usize {
+    TICKS.load(Ordering::Relaxed)
+}
+
+/// Returns the number of ticks covering a duration, rounding up
+pub fn ms_to_ticks(ms: usize) -> usize {
+    (ms.saturating_mul(TICK_HZ) + 999) / 1000
+}
+
+/// Has the counter reached `tick` at `now`?
+///
+/// Correct across the counter wrapping, provided the two are less than half
+/// the range of the counter apart.
+pub fn reached(now: usize, tick: usize) -> bool {
+    now.wrapping_sub(tick) as isize >= 0
+}
@@ -0,0 +1,60 @@
+//! Port Mapped I/O
+//!
+//! Many legacy devices are reached through the separate 16 bit I/O port
+//! address space rather than memory. A port is accessed with an `in` or `out`
+//! of a fixed width, and reading a register at the wrong width silently
+//! returns garbage. Drivers declare each register as a `Port<T>` of its width
+//! instead of passing raw port numbers to `inb()`, `outw()` and friends.
+
+use core::marker::PhantomData;
+
+use super::intrinsics::{inb, inl, inw, outb, outl, outw};
+
+/// A value which may be transferred through an I/O port
+pub trait PortValue: Copy {
+    fn read_port(port: u16) -> Self;
+    fn write_port(port: u16, value: Self);
+}
+
+impl PortValue for u8 {
+    fn read_port(port: u16) -> u8 { inb(port) }
+    fn write_port(port: u16, value: u8) { outb(port, value) }
+}
+
+impl PortValue for u16 {
+    fn read_port(port: u16) -> u16 { inw(port) }
+    fn write_port(port: u16, value: u16) { outw(port, value) }
+}
+
+impl PortValue for u32 {
+    fn read_port(port: u16) -> u32 { inl(port) }
+    fn write_port(port: u16, value: u32) { outl(port, value) }
+}
+
+/// An I/O port transferring a `T` at a time
+#[derive(Debug)]
+pub struct Port<T> {
+    port: u16,
+    value: PhantomData<T>,
+}
+
+impl<T> Port<T> {
+    pub const fn new(port: u16) -> Port<T> {
+        Port { port: port, value: PhantomData }
+    }
+
+    /// Returns the port number
+    pub fn port(&self) -> u16 {
+        self.port
+    }
+}
+
+impl<T: PortValue> Port<T> {
+    pub fn read(&self) -> T {
+        T::read_port(self.port)
+    }
+
+    pub fn write(&self, value: T) {
+        T::write_port(self.port, value)
+    }
+}
@@ -0,0 +1,362 @@
+//! Round-robin Task Scheduling
+//!
+//! All tasks are kept in a single run queue and take turns on the CPU. A task
+//! runs until it gives up the CPU, or until it has been running for
+//! `TIMESLICE` timer ticks, after which it is preempted the next time the
+//! timer interrupts it in userspace. Tasks are never preempted inside the
+//! kernel.
+//!
+//! Switching tasks saves the registers of the outgoing task, moves the
+//! address space of the incoming task into `address_space::current()`, points
+//! the TSS and `syscall` entry at its kernel stack and finally `iretq`s into
+//! it. Whatever was on the kernel stack at the time of the switch is
+//! abandoned, so no locks may be held across `schedule()`. Tasks may switch
+//! either from the timer interrupt or from a system call such as `yield`.
+//!
+//! Exited tasks remain in the queue as zombies until reaped, since the exit
+//! runs on the kernel stack of the task itself. No task waits for another, so
+//! each exit reaps the zombies left by earlier ones.
+
+use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use alloc::vec::Vec;
+use spin::{Mutex, MutexGuard};
+
+use crate::clock;
+use crate::sync::Once;
+
+use super::address_space::{self, AddressSpace};
+use super::fpu;
+use super::interrupts::InterruptState;
+use super::intrinsics::{halt, wait_for_interrupt};
+use super::syscall;
+use super::task::{Task, TaskId, TaskState};
+use super::tss;
+use super::watchdog;
+use super::Registers;
+
+/// Number of timer ticks a task may run before it is preempted
+pub const TIMESLICE: usize = 5;
+
+static SCHEDULER: Once<Mutex<Scheduler>> = Once::new();
+
+/// Ticks left in the timeslice of the running task
+static TICKS_LEFT: AtomicUsize = AtomicUsize::new(TIMESLICE);
+/// Set once the running task has used up its timeslice
+static NEED_RESCHED: AtomicBool = AtomicBool::new(false);
+
+/// The run queue
+pub struct Scheduler {
+    tasks: Vec<Task>,
+    /// Index into `tasks` of the running task
+    current: Option<usize>,
+    next_id: TaskId,
+}
+
+impl Scheduler {
+    pub fn new() -> Scheduler {
+        Scheduler {
+            tasks: Vec::new(),
+            current: None,
+            next_id: 1,
+        }
+    }
+
+    /// Adds a new runnable task to the queue
+    pub fn spawn(&mut self, space: AddressSpace, entry: usize, stack: usize) -> TaskId {
+        let id = self.next_id;
+        self.next_id += 1;
+        self.tasks.push(Task::new(id, space, entry, stack));
+        id
+    }
+
+    /// Returns the running task
+    pub fn current(&mut self) -> Option<&mut Task> {
+        match self.current {
+            Some(index) => Some(&mut self.tasks[index]),
+            None => None,
+        }
+    }
+
+    /// Makes every blocked task whose wake time has been reached runnable
+    pub fn wake(&mut self, now_ns: u64) {
+        for task in self.tasks.iter_mut() {
+            if let TaskState::Blocked { wake_ns } = task.state {
+                if now_ns >= wake_ns {
+                    task.state = TaskState::Runnable;
+                }
+            }
+        }
+    }
+
+    /// Are there tasks which have not exited?
+    pub fn has_live_tasks(&self) -> bool {
+        self.tasks.iter().any(|task| match task.state {
+            TaskState::Zombie { .. } => false,
+            _ => true,
+        })
+    }
+
+    /// Removes an exited task, returning its exit code
+    ///
+    /// Returns None if the task does not exist or has not exited.
+    pub fn reap(&mut self, id: TaskId) -> Option<usize> {
+        let index = self.tasks.iter().position(|task| task.id == id)?;
+        let code = match self.tasks[index].state {
+            TaskState::Zombie { code } => code,
+            _ => return None,
+        };
+        if self.current == Some(index) {
+            return None; // still running on its kernel stack
+        }
+        self.tasks.remove(index);
+        if let Some(current) = self.current {
+            if current > index {
+                self.current = Some(current - 1);
+            }
+        }
+        Some(code)
+    }
+
+    /// Reaps every exited task other than the running one
+    pub fn reap_zombies(&mut self) {
+        let zombies: Vec<TaskId> = self.tasks.iter()
+            .filter(|task| match task.state {
+                TaskState::Zombie { .. } => true,
+                _ => false,
+            })
+            .map(|task| task.id)
+            .collect();
+        for id in zombies {
+            if let Some(code) = self.reap(id) {
+                log_debug!("reaped task {} with exit code {}", id, code);
+            }
+        }
+    }
+
+    /// Picks the index of the next task to run
+    ///
+    /// Tasks are considered in turn following the running task, which itself
+    /// is considered last.
+    fn pick_next(&self) -> Option<usize> {
+        let count = self.tasks.len();
+        let first = self.current.map(|index| index + 1).unwrap_or(0);
+        (0..count).map(|i| (first + i) % count)
+                  .find(|&index| self.tasks[index].is_runnable())
+    }
+
+    /// Makes the next task the running one
+    ///
+    /// `saved` holds the registers of the running task, if any. Returns the
+    /// registers to resume the next task with, or None if the running task
+    /// should continue, along with the address space of the running task if
+    /// it has exited. That is freed by the caller once the run queue is
+    /// unlocked, as freeing it may shoot down TLB entries on other cores.
+    fn switch(&mut self, saved: Option<Registers>) -> (Option<Registers>, Option<AddressSpace>) {
+        let next = match self.pick_next() {
+            Some(next) => next,
+            None => return (None, None),
+        };
+        if Some(next) == self.current {
+            self.tasks[next].state = TaskState::Running;
+            return (None, None);
+        }
+
+        // the address space of an exited task, freed once no longer loaded
+        let mut dead = None;
+        if let Some(index) = self.current {
+            let task = &mut self.tasks[index];
+            if let Some(registers) = saved {
+                task.registers = registers;
+            }
+            if task.state == TaskState::Running {
+                task.state = TaskState::Runnable;
+            }
+            match task.state {
+                TaskState::Zombie { .. } => dead = address_space::current().take(),
+                _ => task.space = address_space::current().take(),
+            }
+        }
+
+        let task = &mut self.tasks[next];
+        task.state = TaskState::Running;
+        tss::set_kernel_stack(task.kernel_stack.top());
+        syscall::set_kernel_stack(task.kernel_stack.top());
+        fpu::set_current(&mut task.fpu);
+        address_space::switch_to(task.space.take().expect("Runnable task without address space"));
+        self.current = Some(next);
+        TICKS_LEFT.store(TIMESLICE, Ordering::Relaxed);
+        (Some(task.registers), dead)
+    }
+}
+
+/// Creates the empty run queue
+pub fn initialize() {
+    SCHEDULER.initialize(Mutex::new(Scheduler::new()));
+}
+
+/// Returns the run queue. It must not be locked by interrupt handlers.
+pub fn get_scheduler<'a>() -> MutexGuard<'a, Scheduler> {
+    SCHEDULER.expect("Scheduler").lock()
+}
+
+/// Adds a new userspace task beginning at `entry` with the given stack
+pub fn spawn(space: AddressSpace, entry: usize, stack: usize) -> TaskId {
+    get_scheduler().spawn(space, entry, stack)
+}
+
+/// Enters the first task
+pub fn start() -> ! {
+    let (registers, _) = get_scheduler().switch(None);
+    enter(&registers.expect("No tasks to run"))
+}
+
+/// Switches to the next task, saving the registers of the running one
+///
+/// Only returns if the running task should continue.
+pub fn schedule(saved: Registers) {
+    let (next, dead) = get_scheduler().switch(Some(saved)); // releases the lock
+    drop(dead);
+    if let Some(registers) = next {
+        enter(&registers);
+    }
+}
+
+/// Blocks the running task until `clock::now_ns()` reaches `wake_ns`
+///
+/// Returns once the task may run again, which may be without switching tasks
+/// if no other task is runnable in the meantime.
+pub fn sleep_until(saved: Registers, wake_ns: u64) {
+    {
+        let mut scheduler = get_scheduler();
+        let task = scheduler.current().expect("No running task");
+        task.state = TaskState::Blocked { wake_ns: wake_ns };
+    }
+    loop {
+        schedule(saved);
+        let woken = get_scheduler().current().map_or(false, |task| task.is_runnable());
+        if woken {
+            return;
+        }
+        watchdog::pet(); // idle rather than hung
+        wait_for_interrupt(); // until the timer wakes a task
+    }
+}
+
+/// Ends the running task and switches to the next
+///
+/// The task stays a zombie until reaped by the next exit, keeping its exit
+/// code and the kernel stack this runs on. Its address space is freed after
+/// the switch. Halts once every task has exited.
+pub fn exit(code: usize) -> ! {
+    {
+        let mut scheduler = get_scheduler();
+        scheduler.reap_zombies();
+        let task = scheduler.current().expect("No running task");
+        task.state = TaskState::Zombie { code: code };
+    }
+    loop {
+        let (next, dead) = get_scheduler().switch(None); // releases the lock
+        drop(dead);
+        if let Some(registers) = next {
+            enter(&registers);
+        }
+        if !get_scheduler().has_live_tasks() {
+            println!("All tasks have exited");
+            halt();
+        }
+        watchdog::pet(); // idle rather than hung
+        wait_for_interrupt(); // until the timer wakes a task
+    }
+}
+
+/// Accounts a timer tick to the running task and wakes sleeping tasks
+pub fn tick() {
+    let left = TICKS_LEFT.load(Ordering::Relaxed);
+    if left <= 1 {
+        NEED_RESCHED.store(true, Ordering::Relaxed);
+    } else {
+        TICKS_LEFT.store(left - 1, Ordering::Relaxed);
+    }
+
+    // if the kernel holds the run queue, try again next tick
+    let scheduler = SCHEDULER.get().and_then(|scheduler| scheduler.try_lock());
+    if let Some(mut scheduler) = scheduler {
+        scheduler.wake(clock::now_ns());
+    }
+}
+
+/// Preempts the interrupted task if its timeslice is used up
+///
+/// Called on the way out of IRQ handlers, after the interrupt has been
+/// acknowledged.
+pub fn preempt(state: &InterruptState) {
+    if state.cs & 3 != 3 {
+        return; // only preempt userspace
+    }
+    watchdog::pet(); // the kernel got back to userspace, so is not hung
+    if NEED_RESCHED.swap(false, Ordering::Relaxed) {
+        schedule(Registers::from(state));
+    }
+}
+
+/// The stack layout popped by `enter()`
+#[repr(C)]
+struct IretFrame {
+    ds: u16,
+    es: u16,
+    fs: u16,
+    gs: u16,
+    /// rax through r15, in the order of `Registers`
+    gprs: [u64; 15],
+    rip:    u64,
+    cs:     u64,
+    rflags: u64,
+    rsp:    u64,
+    ss:     u64,
+}
+
+/// Enters userspace with every register restored
+///
+/// Unlike `syscall::sysret()`, `rcx` and `r11` survive, so this may resume a
+/// task which was interrupted. The floating point state is restored from
+/// `fpu::USER_FPU`.
+fn enter(registers: &Registers) -> ! {
+    let r = *registers;
+    let frame = IretFrame {
+        ds: r.ds, es: r.es, fs: r.fs, gs: r.gs,
+        gprs: [r.rax, r.rbx, r.rcx, r.rdx, r.rsi, r.rdi, r.rbp,
+               r.r8, r.r9, r.r10, r.r11, r.r12, r.r13, r.r14, r.r15],
+        rip: r.rip,
+        cs: r.cs as u64 | 3, // requested privilege level
+        rflags: r.rflags,
+        rsp: r.rsp,
+        ss: r.ss as u64 | 3,
+    };
+    unsafe {
+        asm!("
+        fxrstor64 (%rdi)
+        movw 0(%rsp), %ds
+        movw 2(%rsp), %es
+        movw 4(%rsp), %fs
+        movw 6(%rsp), %gs
+        addq $$8, %rsp
+        popq %rax
+        popq %rbx
+        popq %rcx
+        popq %rdx
+        popq %rsi
+        popq %rdi
+        popq %rbp
+        popq %r8
+        popq %r9
+        popq %r10
+        popq %r11
+        popq %r12
+        popq %r13
+        popq %r14
+        popq %r15
+        iretq
+        " :: "{rsp}"(&frame), "{rdi}"(fpu::USER_FPU) :: "volatile");
+    }
+    loop { } // hint about diverging
+}
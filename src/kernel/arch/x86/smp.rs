@@ -0,0 +1,295 @@
+//! Application Processor Startup
+//!
+//! Firmware only starts the bootstrap processor (BSP). Every other core, an
+//! application processor (AP), waits until the BSP sends it an INIT IPI then
+//! startup IPIs giving the page at which to begin executing in real mode. The
+//! ACPI `APIC` table, or MADT, lists the local APIC of every core.
+//!
+//! The trampoline in `boot/ap_boot.s` is copied to `TRAMPOLINE` along with
+//! the kernel page tables, control registers and a stack for the AP. The
+//! frame allocator never hands out that page, and APs are only started if
+//! the memory map reports it usable. The trampoline
+//! enters long mode and calls `ap_entry()`, which loads a GDT and TSS of the
+//! AP's own, the shared IDT, programs the PAT as on the BSP, and enables its
+//! local APIC. APs are started one at a time, since they share the
+//! trampoline, which must be done before the identity map of low memory is
+//! removed.
+//!
+//! The scheduler only runs on the BSP so far, so started APs idle with
+//! interrupts enabled, answering TLB shootdowns.
+
+use alloc::boxed::Box;
+use alloc::vec::Vec;
+use core::mem::size_of;
+use core::ptr;
+use core::slice;
+use core::sync::atomic::{fence, AtomicUsize, Ordering};
+
+use crate::clock;
+use crate::sync::Once;
+
+use super::acpi;
+use super::apic;
+use super::frame_allocator::{frame_alloc_contiguous, PAGE_SIZE};
+use super::gdt::{self, Gdt, GDT};
+use super::interrupts;
+use super::intrinsics::{rdmsr, read_cr0, read_cr4, wait_for_interrupt};
+use super::multiboot::{self, MMapEntry};
+use super::paging::{init_pat, phys_to_virt, read_cr3, PHYS_WINDOW_SIZE};
+use super::stacks::{StaticStack, STACK_SIZE};
+use super::tlb;
+use super::tss::{self, Tss};
+
+/// Physical address the trampoline is copied to, below 1 MiB
+pub const TRAMPOLINE: usize = 0x8000;
+
+/// Offset of the first entry of the MADT, after its header, the local APIC
+/// address and flags
+const MADT_ENTRIES: usize = acpi::HEADER_SIZE + 8;
+/// MADT entry type of a processor local APIC
+const ENTRY_LOCAL_APIC: u8 = 0;
+/// Local APIC entry flags: the processor is usable
+const LOCAL_APIC_ENABLED: u32 = 1 << 0;
+
+const IA32_EFER: u32 = 0xC0000080;
+/// EFER bit reporting long mode is active, which cannot be written
+const EFER_LMA: u64 = 1 << 10;
+/// CR4 bit enabling PCIDs, which faults outside long mode
+const CR4_PCIDE: usize = 1 << 17;
+
+/// How long an AP may take to come online
+const STARTUP_TIMEOUT_NS: u64 = 100 * 1000 * 1000;
+
+/// Number of APs which have come online
+static ONLINE: AtomicUsize = AtomicUsize::new(0);
+
+/// The state of every AP which was sent the startup IPIs, see `check_canaries()`
+static CPUS: Once<Vec<&'static Cpu>> = Once::new();
+
+extern {
+    static ap_trampoline_start: u8;
+    static ap_trampoline_end: u8;
+}
+
+/// The parameters at the end of the trampoline, see `boot/ap_boot.s`
+#[repr(C)]
+struct TrampolineParams {
+    cr3:   u64,
+    cr4:   u64,
+    efer:  u64,
+    cr0:   u64,
+    stack: u64,
+    entry: u64,
+    arg:   u64,
+}
+
+/// A processor listed in the MADT
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct LocalApic {
+    pub processor_id: u8,
+    pub apic_id: u8,
+}
+
+/// Iterator over the enabled local APICs of a MADT
+pub struct LocalApics<'a> {
+    entries: &'a [u8],
+}
+
+/// Returns the enabled local APICs of a MADT, including its header
+pub fn local_apics(madt: &[u8]) -> LocalApics {
+    LocalApics { entries: madt.get(MADT_ENTRIES..).unwrap_or(&[]) }
+}
+
+impl<'a> Iterator for LocalApics<'a> {
+    type Item = LocalApic;
+
+    fn next(&mut self) -> Option<LocalApic> {
+        loop {
+            // entries begin with their type and length
+            if self.entries.len() < 2 {
+                return None;
+            }
+            let (kind, length) = (self.entries[0], self.entries[1] as usize);
+            if length < 2 || length > self.entries.len() {
+                return None; // malformed
+            }
+            let entry = &self.entries[..length];
+            self.entries = &self.entries[length..];
+
+            if kind == ENTRY_LOCAL_APIC && length >= 8 {
+                let flags = entry[4..8].iter().rev()
+                    .fold(0, |flags, &byte| flags << 8 | byte as u32);
+                if flags & LOCAL_APIC_ENABLED != 0 {
+                    return Some(LocalApic { processor_id: entry[2], apic_id: entry[3] });
+                }
+            }
+        }
+    }
+}
+
+/// The state owned by an application processor
+///
+/// The stacks are allocated from frames, see `alloc_stack()`.
+struct Cpu {
+    stack: &'static StaticStack,
+    nmi_stack: &'static StaticStack,
+    mc_stack: &'static StaticStack,
+    apic_id: u32,
+    gdt: Gdt,
+    tss: Tss,
+}
+
+/// Returns the number of cores running, including the BSP
+pub fn cpu_count() -> usize {
+    ONLINE.load(Ordering::SeqCst) + 1
+}
+
+/// Starts every enabled application processor listed in the MADT
+///
+/// Requires the local APIC, interrupts enabled for the clock, and the identity
+/// map of low memory. `mem_regions` is the memory map, which must report the
+/// trampoline page usable.
+pub fn initialize(mem_regions: &'static [MMapEntry]) {
+    if !apic::is_enabled() {
+        return;
+    }
+    let madt = match acpi::find_table(b"APIC") {
+        Some(madt) => madt,
+        None => return,
+    };
+    let usable = multiboot::iter_free(mem_regions)
+        .any(|region| region.start() <= TRAMPOLINE && TRAMPOLINE + PAGE_SIZE - 1 <= region.end());
+    if !usable {
+        log_warn!("trampoline page {:#x} is not usable memory, not starting APs", TRAMPOLINE);
+        return;
+    }
+    let params = install_trampoline();
+    let bsp = apic::id();
+    let mut cpus = Vec::new();
+
+    for lapic in local_apics(madt).filter(|lapic| lapic.apic_id as u32 != bsp) {
+        let apic_id = lapic.apic_id as u32;
+        if apic_id as usize >= tlb::MAX_CPUS {
+            log_warn!("ignoring cpu with APIC id {}", apic_id);
+            continue;
+        }
+
+        let mut cpu = box Cpu {
+            stack: alloc_stack(),
+            nmi_stack: alloc_stack(),
+            mc_stack: alloc_stack(),
+            apic_id: apic_id,
+            gdt: unsafe { GDT },
+            tss: Tss::zero(),
+        };
+        let kernel_stack = cpu.stack.top();
+        let nmi_stack = cpu.nmi_stack.top();
+        let mc_stack = cpu.mc_stack.top();
+        cpu.tss.set_stacks(kernel_stack, nmi_stack, mc_stack);
+        tss::set_descriptor(&mut cpu.gdt, &cpu.tss);
+
+        let cpu: &'static Cpu = Box::leak(cpu);
+        cpus.push(cpu);
+        params.stack = kernel_stack as u64;
+        params.arg = cpu as *const Cpu as u64;
+        if !start(apic_id) {
+            // it might still start later, with the parameters of the next
+            log_warn!("cpu with APIC id {} did not start, giving up on the rest", apic_id);
+            break;
+        }
+    }
+    CPUS.initialize(cpus);
+    log_info!("{} cores online", cpu_count());
+}
+
+/// Panics if any stack of an AP has overflowed, like `stacks::check_canaries()`
+pub fn check_canaries() {
+    for cpu in CPUS.get().into_iter().flat_map(|cpus| cpus.iter()) {
+        assert!(cpu.stack.check_canary() && cpu.nmi_stack.check_canary()
+                    && cpu.mc_stack.check_canary(),
+                "stack overflow on cpu with APIC id {}", cpu.apic_id);
+    }
+}
+
+/// Allocates a stack of an AP from contiguous frames
+///
+/// Three stacks per core would soon exhaust the kernel heap. The frames are
+/// reached through the physical memory window and never freed.
+fn alloc_stack() -> &'static StaticStack {
+    let paddr = frame_alloc_contiguous(STACK_SIZE / PAGE_SIZE, 1)
+        .expect("No memory for an AP stack")
+        .next().unwrap().addr();
+    assert!(paddr + STACK_SIZE <= PHYS_WINDOW_SIZE, "AP stack outside kernel window");
+    let stack = unsafe { &mut *(phys_to_virt(paddr) as *mut StaticStack) };
+    stack.init();
+    stack
+}
+
+/// Copies the trampoline to low memory, returning its parameters
+///
+/// The parameters common to all APs are filled in.
+fn install_trampoline() -> &'static mut TrampolineParams {
+    let code = unsafe {
+        let start = &ap_trampoline_start as *const u8;
+        let end = &ap_trampoline_end as *const u8;
+        slice::from_raw_parts(start, end as usize - start as usize)
+    };
+    assert!(code.len() <= PAGE_SIZE, "AP trampoline larger than a page");
+
+    let base = phys_to_virt(TRAMPOLINE);
+    let params = unsafe {
+        ptr::copy_nonoverlapping(code.as_ptr(), base as *mut u8, code.len());
+        &mut *((base + code.len() - size_of::<TrampolineParams>()) as *mut TrampolineParams)
+    };
+
+    // the trampoline loads CR3 in 32 bit mode
+    let cr3 = read_cr3();
+    assert!(cr3 >> 32 == 0, "kernel page tables above 4 GiB");
+    params.cr3 = cr3 as u64;
+    params.cr4 = (read_cr4() & !CR4_PCIDE) as u64;
+    params.efer = rdmsr(IA32_EFER) & !EFER_LMA;
+    params.cr0 = read_cr0() as u64;
+    params.entry = ap_entry as u64;
+    params
+}
+
+/// Sends INIT then up to two startup IPIs to a core, returning whether it
+/// came online
+fn start(apic_id: u32) -> bool {
+    let online = ONLINE.load(Ordering::SeqCst);
+    fence(Ordering::SeqCst); // parameters are written before the IPIs
+
+    apic::send_init(apic_id);
+    delay_ns(10 * 1000 * 1000);
+    for _ in 0..2 {
+        apic::send_startup(apic_id, TRAMPOLINE);
+        let deadline = clock::now_ns() + STARTUP_TIMEOUT_NS;
+        while clock::now_ns() < deadline {
+            if ONLINE.load(Ordering::SeqCst) != online {
+                return true;
+            }
+        }
+    }
+    false
+}
+
+/// Waits at least `ns` nanoseconds
+fn delay_ns(ns: u64) {
+    let deadline = clock::now_ns() + ns;
+    while clock::now_ns() < deadline { }
+}
+
+/// Where application processors arrive from the trampoline
+extern "C" fn ap_entry(cpu: &'static Cpu) -> ! {
+    gdt::load(&cpu.gdt);
+    unsafe { tss::load(); }
+    interrupts::load_shared();
+    init_pat();
+    apic::enable();
+    tlb::set_active(cpu.apic_id, true);
+    ONLINE.fetch_add(1, Ordering::SeqCst);
+
+    loop {
+        wait_for_interrupt();
+    }
+}
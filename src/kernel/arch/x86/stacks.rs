@@ -5,6 +5,11 @@
 //! It should be noted that `kstart` still utilizes the stack defined in
 //! boot/boot32.s. Upon transitioning back from userspace to kernelspace, we
 //! begin using the DEFAULT stack.
+//!
+//! The stacks are adjacent statics, so an overflow silently corrupts whatever
+//! lies below. To detect this, the lowest bytes of each stack are filled with
+//! a canary which is verified periodically. A guard page would catch overflows
+//! immediately, but requires unmapping a page below each stack.
 
 /// The default stack used by the kernel when transitioning from userspace to
 /// kernelspace.
@@ -14,11 +19,19 @@ pub static mut DEFAULT: StaticStack = StaticStack::zero();
 /// slim chance of handling a NMI after loading the userspace stack
 /// but just before calling `sysret`.
 pub static mut NMI: StaticStack = StaticStack::zero();
+/// The stack used when handling machine checks, which like NMIs may occur
+/// during any instruction. It is separate from the NMI stack, since either
+/// may interrupt the handler of the other.
+pub static mut MACHINE_CHECK: StaticStack = StaticStack::zero();
 
 /// A byte array which allocates space for a stack
 pub struct StaticStack([u8; STACK_SIZE]);
 /// The size in bytes of the various kernel stacks
 pub const STACK_SIZE: usize = 16 * 1024;
+/// The byte filling the canary at the bottom of each stack
+pub const CANARY: u8 = 0xa5;
+/// The size in bytes of the canary at the bottom of each stack
+pub const CANARY_SIZE: usize = 64;
 
 impl StaticStack {
     /// Returns a zero initialized stack
@@ -26,6 +39,18 @@ impl StaticStack {
         StaticStack([0; STACK_SIZE])
     }
 
+    /// Writes the canary at the bottom of the stack
+    pub fn init(&mut self) {
+        for byte in &mut self.0[..CANARY_SIZE] {
+            *byte = CANARY;
+        }
+    }
+
+    /// Is the canary intact? Returns false if the stack has overflowed.
+    pub fn check_canary(&self) -> bool {
+        self.0[..CANARY_SIZE].iter().all(|&byte| byte == CANARY)
+    }
+
     /// Returns the starting address of the stack (which traditionally grows down)
     pub fn top(&self) -> usize {
         self as *const _ as usize + STACK_SIZE
@@ -37,3 +62,21 @@ impl StaticStack {
         asm!("mov rsp, $0" :: "r"(self.top()) :: "intel")
     }
 }
+
+/// Writes the canaries of all static stacks
+///
+/// Must be called before the stacks are used.
+pub unsafe fn initialize() {
+    DEFAULT.init();
+    NMI.init();
+    MACHINE_CHECK.init();
+}
+
+/// Panics if any static stack has overflowed
+pub fn check_canaries() {
+    unsafe {
+        assert!(DEFAULT.check_canary(), "DEFAULT stack overflow");
+        assert!(NMI.check_canary(), "NMI stack overflow");
+        assert!(MACHINE_CHECK.check_canary(), "MACHINE_CHECK stack overflow");
+    }
+}
@@ -15,12 +15,28 @@
 //! `rip` in `rcx` and `rflags` in `r11`. Thus, these registers are never
 //! preserved. We must preserve the other registers.
 //!
+//! Unlike interrupts, `syscall` does not switch stacks. `syscall_enter` moves
+//! to the kernel stack set by `set_kernel_stack()` itself. Until it has, an
+//! interrupt would be pushed onto the user stack, so `syscall` clears the
+//! interrupt flag (see `SFMASK`) and they are only re-enabled once on the
+//! kernel stack.
+//!
 //! Some initialization must be done to enable these instructions. See the
 //! `initialize()` function. See the `sysret()` instruction to manually
 //! enter userspace.
+//!
+//! Userspace may also make system calls with `int 0x80`, which are handled
+//! by the same dispatcher.
+
+use crate::clock;
 
-use super::gdt::{SYS_CODE_OFFSET, USR_SYSC_OFFSET};
+use super::fpu;
+use super::gdt::{SYS_CODE_OFFSET, USR_CODE_OFFSET, USR_DATA_OFFSET, USR_SYSC_OFFSET};
+use super::interrupts;
 use super::intrinsics::{stmsr, wrmsr};
+use super::scheduler;
+use super::stacks::DEFAULT;
+use super::usercopy::Fault;
 use super::Registers;
 
 /// Syscall Target flags
@@ -28,7 +44,9 @@ pub const STAR: u64 = (SYS_CODE_OFFSET << 32 | USR_SYSC_OFFSET << 48) as u64;
 /// The address loaded into the `rip` register by `syscall`
 pub const LSTAR: unsafe fn() = syscall_enter;
 /// The bits of `rflags` register that should be cleared by `syscall`
-pub const SFMASK: u64 = 0;
+///
+/// Only the interrupt flag, see `syscall_enter`.
+pub const SFMASK: u64 = 0x200;
 
 /// Default value for the `rflags` register for the `sysret()` function
 ///
@@ -36,24 +54,149 @@ pub const SFMASK: u64 = 0;
 /// interrupts such as IRQs.
 pub const SYSRET_RFLAGS: usize = 0x200;
 
+/// The vector of the legacy `int 0x80` system call entry
+pub const INT_VECTOR: usize = 0x80;
+
+/// System call numbers, passed in `rax`
+pub const SYS_YIELD: u64 = 0;
+pub const SYS_NANOSLEEP: u64 = 1;
+pub const SYS_EXIT: u64 = 2;
+
+/// The stack `syscall_enter` switches to
+static mut KERNEL_RSP: usize = 0;
+/// Scratch space for the user stack pointer while switching stacks
+static mut USER_RSP: usize = 0;
+
+/// Error codes returned by system calls
+///
+/// Values follow Linux and are returned negated in `rax`.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum Errno {
+    EPERM  = 1,
+    ENOENT = 2,
+    EIO    = 5,
+    EBADF  = 9,
+    ENOMEM = 12,
+    EFAULT = 14,
+    EINVAL = 22,
+    ENOSYS = 38,
+}
+
+impl Errno {
+    /// Returns the negated error code, as placed in `rax`
+    pub fn as_isize(self) -> isize {
+        -(self as isize)
+    }
+}
+
+impl From<Fault> for Errno {
+    fn from(_: Fault) -> Errno {
+        Errno::EFAULT
+    }
+}
+
+/// The outcome of a system call handler
+pub type SyscallResult = Result<usize, Errno>;
+
+/// Converts the outcome of a system call into the value returned in `rax`
+pub fn result_to_rax(result: SyscallResult) -> isize {
+    match result {
+        Ok(n) => n as isize,
+        Err(errno) => errno.as_isize(),
+    }
+}
+
 /// Enables the `syscall` and `sysret` instructions
 pub fn initialize() {
+    set_kernel_stack(unsafe { DEFAULT.top() });
+
     // set model specific registers
     wrmsr(0xC0000081, STAR);
     wrmsr(0xC0000082, LSTAR as u64);
     wrmsr(0xC0000084, SFMASK);
     // enable syscall instructions in EFER
     stmsr(0xC0000080, 0); // set the SCE bit
+
+    // legacy entry through `int 0x80`, which userspace must be able to invoke
+    let mut idt = interrupts::Idt::current().unwrap();
+    idt.register_isr_dpl(INT_VECTOR, isr_syscall, 3);
+    idt.load();
+}
+
+/// Sets the stack used by the kernel to handle system calls
+pub fn set_kernel_stack(top: usize) {
+    unsafe { KERNEL_RSP = top; }
+}
+
+/// Handles a system call, returning the result in `rax`
+///
+/// Used by both `syscall_enter` and `int 0x80`.
+fn dispatch(registers: &mut Registers) {
+    let result = match registers.rax {
+        SYS_YIELD => sys_yield(registers),
+        SYS_NANOSLEEP => sys_nanosleep(registers, registers.rdi as usize),
+        SYS_EXIT => sys_exit(registers.rdi as usize),
+        _ => Err(Errno::ENOSYS),
+    };
+    registers.rax = result_to_rax(result) as u64;
+}
+
+/// Gives up the CPU to the next runnable task
+///
+/// The task is resumed from the registers pushed by `syscall_enter`, see
+/// `resume_registers()`. These were saved in kernelspace, so the selectors
+/// must be replaced by those of userspace. As with `sysret`, `rcx` and `r11`
+/// hold the return `rip` and `rflags`.
+fn sys_yield(registers: &Registers) -> SyscallResult {
+    scheduler::schedule(resume_registers(registers)); // only returns if no other task is runnable
+    Ok(0)
+}
+
+/// Blocks the calling task for at least `ms` milliseconds
+///
+/// A duration of zero behaves like `yield`.
+fn sys_nanosleep(registers: &Registers, ms: usize) -> SyscallResult {
+    if ms == 0 {
+        return sys_yield(registers);
+    }
+    let wake_ns = clock::now_ns().saturating_add(clock::ms_to_ns(ms));
+    scheduler::sleep_until(resume_registers(registers), wake_ns);
+    Ok(0)
+}
+
+/// Ends the calling task with an exit code. Never returns.
+fn sys_exit(code: usize) -> SyscallResult {
+    scheduler::exit(code)
+}
+
+/// Returns the registers to resume a task blocked in a system call with
+///
+/// The call returns 0 once the task is resumed.
+fn resume_registers(registers: &Registers) -> Registers {
+    let mut saved = *registers;
+    saved.rax = 0;
+    saved.cs = USR_CODE_OFFSET as u16;
+    saved.ss = USR_DATA_OFFSET as u16;
+    saved
+}
+
+isr_plain! {
+    // system calls through `int 0x80`, taking the same registers as `syscall`
+    0x80 => fn isr_syscall(state) {
+        let mut registers = Registers::from(&*state);
+        dispatch(&mut registers);
+        state.load(&registers);
+    }
 }
 
 /// The function called in kernelspace by `syscall`
 #[naked]
 unsafe fn syscall_enter() {
-    fn action(_regs: &mut Registers) {
-        println!("syscall'd");
-    }
     asm!("
-    pushq %rsp
+    movq %rsp, ${1:c}  // switch to the kernel stack
+    movq ${2:c}, %rsp
+    pushq ${1:c}       // user rsp
+    sti                // the scratch user rsp is free and the stack switched
     pushq %r11
     pushq %rcx
     sub $$16, %rsp  // skip the 4 bytes of padding
@@ -78,8 +221,13 @@ unsafe fn syscall_enter() {
     pushq %rcx
     pushq %rbx
     pushq %rax
+    movq ${3:c}, %rax  // save the FPU state of the task
+    fxsave64 (%rax)
     movq %rsp, %rdi // pass register state to function
     callq ${0:c}
+    cli             // until sysret, as the user stack is restored below
+    movq ${3:c}, %rax
+    fxrstor64 (%rax)
     popq %rax
     popq %rbx
     popq %rcx
@@ -105,7 +253,7 @@ unsafe fn syscall_enter() {
     popq %r11
     popq %rsp
     sysretq
-    " :: "s"(action as u64))
+    " :: "s"(dispatch as u64), "s"(&USER_RSP), "s"(&KERNEL_RSP), "s"(&fpu::USER_FPU))
 }
 
 pub fn sysret(registers: &Registers) -> ! {
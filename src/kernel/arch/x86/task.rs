@@ -0,0 +1,64 @@
+//! Userspace Tasks
+//!
+//! A task is a thread of execution in userspace together with the address
+//! space it runs in. While a task is not running, the registers it left the
+//! CPU with are kept in the task so that it may later be resumed. Tasks are
+//! created and switched between by the `scheduler` module.
+
+use alloc::boxed::Box;
+
+use super::address_space::AddressSpace;
+use super::fpu::FpuState;
+use super::stacks::StaticStack;
+use super::Registers;
+
+/// Unique identifier of a task
+pub type TaskId = usize;
+
+/// Scheduling state of a task
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum TaskState {
+    /// Currently executing
+    Running,
+    /// Waiting for its turn to run
+    Runnable,
+    /// Sleeping until `clock::now_ns()` reaches `wake_ns`
+    Blocked { wake_ns: u64 },
+    /// Exited, but not yet reaped. See `Scheduler::reap()`
+    Zombie { code: usize },
+}
+
+pub struct Task {
+    pub id: TaskId,
+    /// The registers to resume with. Stale while the task is running.
+    pub registers: Registers,
+    /// The address space of the task. While the task is running it is moved
+    /// to `address_space::current()`.
+    pub space: Option<AddressSpace>,
+    pub state: TaskState,
+    /// The stack used when the task is interrupted in userspace
+    pub kernel_stack: Box<StaticStack>,
+    /// The floating point state of the task. See `fpu`.
+    pub fpu: Box<FpuState>,
+}
+
+impl Task {
+    /// Creates a runnable task which begins at `entry` with the given stack
+    pub fn new(id: TaskId, space: AddressSpace, entry: usize, stack: usize) -> Task {
+        let mut kernel_stack = box StaticStack::zero();
+        kernel_stack.init();
+        Task {
+            id: id,
+            registers: Registers::default_user(entry, stack),
+            space: Some(space),
+            state: TaskState::Runnable,
+            kernel_stack: kernel_stack,
+            fpu: box FpuState::new(),
+        }
+    }
+
+    /// May the scheduler pick this task?
+    pub fn is_runnable(&self) -> bool {
+        self.state == TaskState::Runnable || self.state == TaskState::Running
+    }
+}
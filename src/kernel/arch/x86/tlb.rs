@@ -0,0 +1,119 @@
+//! TLB Shootdown
+//!
+//! Each core caches translations in its own TLB, and `invlpg` only flushes
+//! the TLB of the core executing it. Once a mapping is removed or its flags
+//! reduced, every other core which may have cached it must flush it too, or
+//! it could keep using the stale translation.
+//!
+//! `shootdown()` flushes the page locally, then sends an IPI to every other
+//! active core and waits until each has flushed the page and acknowledged.
+//! Only one shootdown is in flight at a time. Cores are recorded in a bitmask
+//! indexed by local APIC id, so at most `MAX_CPUS` cores take part. Without a
+//! local APIC, or with a single active core, a shootdown is a plain `invlpg`.
+//!
+//! The initiator spins until every target acknowledges. A core may need a
+//! shootdown with interrupts disabled, as in the page fault handler, so while
+//! waiting for another core's shootdown to finish it flushes any page that
+//! shootdown targets at it itself, rather than waiting for the IPI.
+//!
+//! Shootdowns are initiated with page tables locked, such as the kernel's
+//! (see `paging::kernel_tables()`), so a core spinning on such a lock must
+//! likewise call `acknowledge()`, or the initiator waits on it forever.
+
+use core::sync::atomic::{AtomicUsize, Ordering};
+use spin::Mutex;
+
+use super::apic;
+use super::interrupts;
+use super::intrinsics::invlpg;
+
+/// The vector of the shootdown IPI
+pub const SHOOTDOWN_VECTOR: usize = 0xf0;
+
+/// The number of cores the active set can describe
+pub const MAX_CPUS: usize = 64;
+
+/// Bitmask of the local APIC ids of the cores taking part in shootdowns
+static ACTIVE_CPUS: AtomicUsize = AtomicUsize::new(0);
+
+/// Serializes shootdowns, which share the following
+static SHOOTDOWN: Mutex<()> = Mutex::new(());
+/// The page being shot down
+static SHOOTDOWN_ADDR: AtomicUsize = AtomicUsize::new(0);
+/// Bitmask of the targets which have not yet acknowledged the shootdown
+static PENDING_ACKS: AtomicUsize = AtomicUsize::new(0);
+
+/// Registers the shootdown handler and marks the current core active
+///
+/// Requires the local APIC.
+pub fn initialize() {
+    let mut idt = interrupts::Idt::current().unwrap();
+    idt.register_isr(SHOOTDOWN_VECTOR, isr_shootdown);
+    idt.load();
+    set_active(apic::id(), true);
+}
+
+/// Adds a core to, or removes it from, the set receiving shootdowns
+pub fn set_active(apic_id: u32, active: bool) {
+    assert!((apic_id as usize) < MAX_CPUS, "APIC id {} too large for shootdowns", apic_id);
+    let bit = 1 << apic_id;
+    if active {
+        ACTIVE_CPUS.fetch_or(bit, Ordering::SeqCst);
+    } else {
+        ACTIVE_CPUS.fetch_and(!bit, Ordering::SeqCst);
+    }
+}
+
+/// Returns the bitmask of active cores
+pub fn active_cpus() -> usize {
+    ACTIVE_CPUS.load(Ordering::SeqCst)
+}
+
+/// Flushes the page containing `vaddr` from the TLB of every active core
+pub fn shootdown(vaddr: usize) {
+    invlpg(vaddr);
+
+    let active = active_cpus();
+    if active.count_ones() <= 1 {
+        return; // no other core can have cached the page
+    }
+    let targets = active & !(1 << apic::id());
+
+    let _lock = loop {
+        if let Some(lock) = SHOOTDOWN.try_lock() {
+            break lock;
+        }
+        acknowledge(); // the holder may be waiting for this core
+    };
+    SHOOTDOWN_ADDR.store(vaddr, Ordering::SeqCst);
+    PENDING_ACKS.store(targets, Ordering::SeqCst);
+    for apic_id in 0..MAX_CPUS {
+        if targets & (1 << apic_id) != 0 {
+            apic::send_ipi(apic_id as u32, SHOOTDOWN_VECTOR);
+        }
+    }
+    while PENDING_ACKS.load(Ordering::SeqCst) != 0 { }
+}
+
+/// Flushes the page of the shootdown in flight and acknowledges it, if it
+/// targets the current core and has not been acknowledged yet
+pub fn acknowledge() {
+    let pending = PENDING_ACKS.load(Ordering::SeqCst);
+    if pending == 0 {
+        return; // also without a local APIC, when there are no shootdowns
+    }
+    let bit = 1 << apic::id();
+    if pending & bit != 0 {
+        invlpg(SHOOTDOWN_ADDR.load(Ordering::SeqCst));
+        PENDING_ACKS.fetch_and(!bit, Ordering::SeqCst);
+    }
+}
+
+isr_plain! {
+    // the shootdown may already have been acknowledged while waiting to
+    // initiate another, in which case this does nothing
+    0xf0 => fn isr_shootdown(_state) {
+        acknowledge();
+        apic::eoi();
+    }
+}
@@ -10,8 +10,9 @@
 //! The TSS used to hold registers and other fields to facilitate hardware task
 //! switching, but that's deprecated in AMD64.
 
-use super::gdt::{GDT, TSS_OFFSET};
-use super::stacks::{DEFAULT, NMI};
+use super::gdt::flags;
+use super::gdt::{Gdt, GDT, TSS_OFFSET};
+use super::stacks::{DEFAULT, MACHINE_CHECK, NMI};
 
 /// A wrapper around a Task State Segment
 #[allow(dead_code)]
@@ -36,44 +37,74 @@ pub struct Tss {
     io_map:     u16,
 }
 
-pub static mut TSS: Tss = Tss {
-    _reserved0: 0,
-    rsp0:       0,
-    rsp1:       0,
-    rsp2:       0,
-    _reserved1: 0,
-    _reserved2: 0,
-    ist1:       0,
-    ist2:       0,
-    ist3:       0,
-    ist4:       0,
-    ist5:       0,
-    ist6:       0,
-    ist7:       0,
-    _reserved3: 0,
-    _reserved4: 0,
-    _reserved5: 0,
-    io_map:     0,
-};
+impl Tss {
+    pub const fn zero() -> Tss {
+        Tss {
+            _reserved0: 0,
+            rsp0:       0,
+            rsp1:       0,
+            rsp2:       0,
+            _reserved1: 0,
+            _reserved2: 0,
+            ist1:       0,
+            ist2:       0,
+            ist3:       0,
+            ist4:       0,
+            ist5:       0,
+            ist6:       0,
+            ist7:       0,
+            _reserved3: 0,
+            _reserved4: 0,
+            _reserved5: 0,
+            io_map:     0,
+        }
+    }
+
+    /// Sets the stacks loaded on entry from userspace, for NMIs and for
+    /// machine checks. See `interrupts::NMI_IST` and `interrupts::MC_IST`.
+    pub fn set_stacks(&mut self, kernel: usize, nmi: usize, machine_check: usize) {
+        self.rsp0 = kernel;
+        self.ist1 = nmi;
+        self.ist2 = machine_check;
+    }
+}
+
+pub static mut TSS: Tss = Tss::zero();
+
+/// Sets the stack loaded when an interrupt arrives in userspace
+pub fn set_kernel_stack(top: usize) {
+    unsafe { TSS.rsp0 = top; }
+}
 
 /// Initializes the TSS and TR
 ///
 /// Necessary to re-enter ring0
 pub fn initialize() {
-    // GDT[6..8] contains the TSS segment.
-    // It's already been initialized with the proper size and flags, but
-    // we initialize the multi-part address fields here since we can't
-    // manipulate the tss ptr before linking.
     unsafe {
-        TSS.rsp0 = DEFAULT.top();
-        TSS.ist1 = NMI.top();
+        TSS.set_stacks(DEFAULT.top(), NMI.top(), MACHINE_CHECK.top());
+        set_descriptor(&mut GDT, &TSS);
+        load();
+    }
+}
 
-        let tss_ptr = &TSS as *const _ as usize;
-        GDT[6] |= (tss_ptr & 0x00ffffff) << 16; // 39:16
-        GDT[6] |= (tss_ptr & 0xff000000) << 32; // 63:56
-        GDT[7] = tss_ptr >> 32; // 95:64
+/// Points the TSS descriptor of a GDT at a TSS
+///
+/// The descriptor is rewritten rather than updated, since `load()` marks it
+/// busy and copies of `GDT` carry the descriptor of another TSS.
+pub fn set_descriptor(gdt: &mut Gdt, tss: &Tss) {
+    // GDT[6..8] contains the TSS segment. The address can't be part of the
+    // static GDT, since we can't manipulate the tss ptr before linking.
+    let tss_ptr = tss as *const _ as usize;
+    gdt[6] = flags::TSS | flags::PRESENT | 104;
+    gdt[6] |= (tss_ptr & 0x00ffffff) << 16; // 39:16
+    gdt[6] |= (tss_ptr & 0xff000000) << 32; // 63:56
+    gdt[7] = tss_ptr >> 32; // 95:64
+}
 
-        // load TR with byte-offset into GDT for TSS
-        asm!("ltr ax" :: "{rax}"(TSS_OFFSET) :: "intel");
-    }
+/// Loads the TSS described by the loaded GDT into the TR
+///
+/// The TSS must outlive its use by the current core.
+pub unsafe fn load() {
+    // load TR with byte-offset into GDT for TSS
+    asm!("ltr ax" :: "{rax}"(TSS_OFFSET) :: "intel");
 }
@@ -0,0 +1,85 @@
+//! Moving data across the user/kernel boundary
+//!
+//! Pointers supplied by userspace cannot be trusted: they may be unmapped,
+//! read-only, or point into the kernel. Every page touched by a copy is
+//! checked in the current tables before any byte is moved. Pages userspace
+//! could legitimately fault in (lazily allocated or copy-on-write) are
+//! resolved first, as if the process had touched them itself.
+
+use core;
+
+use super::address_space::{handle_page_fault, USER_END};
+use super::frame_allocator::PAGE_SIZE;
+use super::interrupts::{PF_PRESENT, PF_USER, PF_WRITE};
+use super::paging::{PT4, USER, WRITE};
+
+/// Userspace supplied an address it may not access
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct Fault {
+    /// The first inaccessible address
+    pub addr: usize,
+}
+
+/// Copies `dst.len()` bytes from userspace into a kernel buffer
+pub fn copy_from_user(dst: &mut [u8], user_ptr: usize) -> Result<(), Fault> {
+    check_range(user_ptr, dst.len(), false)?;
+    unsafe {
+        core::ptr::copy_nonoverlapping(user_ptr as *const u8, dst.as_mut_ptr(), dst.len());
+    }
+    Ok(())
+}
+
+/// Copies a kernel buffer out to userspace
+pub fn copy_to_user(user_ptr: usize, src: &[u8]) -> Result<(), Fault> {
+    check_range(user_ptr, src.len(), true)?;
+    unsafe {
+        core::ptr::copy_nonoverlapping(src.as_ptr(), user_ptr as *mut u8, src.len());
+    }
+    Ok(())
+}
+
+/// Checks that every page of a user buffer may be accessed by userspace
+fn check_range(start: usize, len: usize, write: bool) -> Result<(), Fault> {
+    if len == 0 {
+        return Ok(());
+    }
+    // rejects all kernel addresses, including those below KERNEL_BASE
+    let last = match start.checked_add(len - 1) {
+        Some(last) if last < USER_END => last,
+        _ => return Err(Fault { addr: core::cmp::max(start, USER_END) }),
+    };
+
+    let mut page = start & !(PAGE_SIZE - 1);
+    while page <= last {
+        check_page(page, write).map_err(|_| Fault { addr: core::cmp::max(page, start) })?;
+        page += PAGE_SIZE;
+    }
+    Ok(())
+}
+
+/// Checks a single user page, faulting it in if userspace could do so
+fn check_page(page: usize, write: bool) -> Result<(), ()> {
+    let accessible = || {
+        let flags = unsafe { PT4::current() }.translate_flags(page);
+        match flags {
+            Some(flags) => flags.contains(USER) && (!write || flags.contains(WRITE)),
+            None => false,
+        }
+    };
+    if accessible() {
+        return Ok(());
+    }
+
+    let mut error = PF_USER;
+    if write {
+        error.insert(PF_WRITE);
+    }
+    if unsafe { PT4::current() }.translate(page).is_some() {
+        error.insert(PF_PRESENT);
+    }
+    if handle_page_fault(page, error) && accessible() {
+        Ok(())
+    } else {
+        Err(())
+    }
+}
@@ -0,0 +1,71 @@
+//! Hung Kernel Detection
+//!
+//! Once armed, the watchdog counts down on every timer tick. Code making
+//! progress calls `pet()` to restart the countdown; should it ever run out,
+//! the kernel is presumed hung and is halted after reporting where the timer
+//! interrupted it. This turns a silent hang under QEMU into a visible failure.
+//!
+//! `kstart` arms the watchdog once the timer runs. It is petted by the idle
+//! loops and whenever the timer interrupts userspace, so only the kernel
+//! spinning with the timer running for the whole timeout trips it.
+
+use core::fmt::Write;
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+use crate::vestige::PanicWriter;
+
+use super::interrupts::InterruptState;
+use super::intrinsics::halt;
+
+/// Timeout used by `arm()` if none is configured, in ticks
+pub const DEFAULT_TIMEOUT: usize = 10 * super::pit::TICK_HZ;
+
+/// Ticks allowed between calls to `pet()`. Zero while disarmed.
+static TIMEOUT: AtomicUsize = AtomicUsize::new(0);
+/// Ticks left until the timeout
+static REMAINING: AtomicUsize = AtomicUsize::new(0);
+
+/// Starts the watchdog with a timeout in ticks
+pub fn arm(timeout: usize) {
+    assert!(timeout > 0, "Watchdog timeout must be positive");
+    REMAINING.store(timeout, Ordering::Relaxed);
+    TIMEOUT.store(timeout, Ordering::Relaxed);
+}
+
+/// Stops the watchdog
+pub fn disarm() {
+    TIMEOUT.store(0, Ordering::Relaxed);
+}
+
+/// Restarts the countdown
+pub fn pet() {
+    REMAINING.store(TIMEOUT.load(Ordering::Relaxed), Ordering::Relaxed);
+}
+
+/// Counts down a tick, returning true once the timeout has been reached
+///
+/// The countdown restarts after a timeout.
+pub fn count_down() -> bool {
+    if TIMEOUT.load(Ordering::Relaxed) == 0 {
+        return false;
+    }
+    let remaining = REMAINING.load(Ordering::Relaxed);
+    if remaining <= 1 {
+        pet();
+        true
+    } else {
+        REMAINING.store(remaining - 1, Ordering::Relaxed);
+        false
+    }
+}
+
+/// Counts a timer tick. Called by the IRQ0 handler.
+pub fn tick(state: &InterruptState) {
+    if count_down() {
+        let (rip, cs) = (state.rip, state.cs);
+        // the hang may well be holding the console lock
+        let mut out = unsafe { PanicWriter::new() };
+        let _ = write!(out, "WATCHDOG TIMEOUT at 0x{:x} (cs 0x{:x})\n", rip, cs);
+        halt();
+    }
+}
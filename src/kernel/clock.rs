@@ -0,0 +1,48 @@
+//! Monotonic Clocks
+//!
+//! Timer backends present a uniform time source through `ClockSource`. One
+//! source at a time is installed, and `now_ns()` reads the time from it, so
+//! code measuring time need not know which timer drives it.
+
+use spin::RwLock;
+
+/// Nanoseconds per second
+pub const NS_PER_SEC: u64 = 1_000_000_000;
+
+/// A monotonic counter of time
+pub trait ClockSource: Sync {
+    /// Returns the time since the source started, in nanoseconds
+    fn now_ns(&self) -> u64;
+
+    /// Returns the frequency in Hz at which the underlying counter advances
+    fn frequency(&self) -> u64;
+}
+
+/// The installed clock source
+///
+/// Only written while booting or switching sources, so readers in interrupt
+/// handlers do not contend with each other.
+static SOURCE: RwLock<Option<&'static dyn ClockSource>> = RwLock::new(None);
+
+/// Makes a source the one read by `now_ns()`
+///
+/// A source replacing another must count from the same origin, or time seen
+/// by sleeping tasks jumps.
+pub fn install(source: &'static dyn ClockSource) {
+    *SOURCE.write() = Some(source);
+}
+
+/// Returns the time since boot in nanoseconds, or 0 if no source is installed
+pub fn now_ns() -> u64 {
+    SOURCE.read().map_or(0, |source| source.now_ns())
+}
+
+/// Returns the frequency of the installed source, or 0 if none is
+pub fn frequency() -> u64 {
+    SOURCE.read().map_or(0, |source| source.frequency())
+}
+
+/// Converts milliseconds to nanoseconds, saturating
+pub fn ms_to_ns(ms: usize) -> u64 {
+    (ms as u64).saturating_mul(1_000_000)
+}
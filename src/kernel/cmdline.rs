@@ -0,0 +1,83 @@
+//! Kernel Command Line
+//!
+//! The boot loader passes a line of whitespace separated options, each either
+//! a bare flag such as `noapic` or a `key=value` pair such as `loglevel=debug`.
+//! A value may be wrapped in double quotes to contain whitespace, as in
+//! `label="boot disk"`. If a key is given more than once, the last value wins,
+//! so options appended by the boot loader override earlier ones.
+
+/// A parsed view of a command line
+#[derive(Copy, Clone, Debug)]
+pub struct CmdLine<'a> {
+    line: &'a str,
+}
+
+/// Iterator over the `(key, value)` options of a command line
+///
+/// Bare flags have no value.
+pub struct Options<'a> {
+    rest: &'a str,
+}
+
+impl<'a> CmdLine<'a> {
+    pub fn new(line: &'a str) -> CmdLine<'a> {
+        CmdLine { line: line }
+    }
+
+    /// Returns the options in the order given
+    pub fn options(&self) -> Options<'a> {
+        Options { rest: self.line }
+    }
+
+    /// Returns the last value of a `key=value` option
+    pub fn get(&self, key: &str) -> Option<&'a str> {
+        self.options()
+            .filter(|&(k, _)| k == key)
+            .filter_map(|(_, v)| v)
+            .last()
+    }
+
+    /// Is a bare flag present?
+    pub fn has_flag(&self, name: &str) -> bool {
+        self.options().any(|(k, v)| k == name && v.is_none())
+    }
+}
+
+impl<'a> Iterator for Options<'a> {
+    type Item = (&'a str, Option<&'a str>);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let rest = self.rest.trim_start();
+        if rest.is_empty() {
+            self.rest = rest;
+            return None;
+        }
+
+        // a token ends at the first whitespace outside of quotes
+        let mut quoted = false;
+        let end = rest.char_indices()
+            .find(|&(_, c)| {
+                if c == '"' {
+                    quoted = !quoted;
+                }
+                !quoted && c.is_whitespace()
+            })
+            .map_or(rest.len(), |(i, _)| i);
+        let (token, rest) = rest.split_at(end);
+        self.rest = rest;
+
+        Some(match token.find('=') {
+            Some(i) => (&token[..i], Some(unquote(&token[i + 1..]))),
+            None => (token, None),
+        })
+    }
+}
+
+/// Strips a pair of surrounding double quotes
+fn unquote(value: &str) -> &str {
+    if value.len() >= 2 && value.starts_with('"') && value.ends_with('"') {
+        &value[1..value.len() - 1]
+    } else {
+        value
+    }
+}
@@ -0,0 +1,95 @@
+//! Console Output
+//!
+//! `print!` and `println!` write to the consoles selected here, the VGA text
+//! buffer by default. A headless machine may instead be told to use the
+//! serial port with `console=serial` (or `console=both`) on the command line.
+//!
+//! A graphical boot has no text mode, so when the boot loader provides a
+//! linear framebuffer the console draws into it instead of the VGA buffer.
+//! See `use_framebuffer()`.
+
+use core::fmt;
+use core::fmt::Write;
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+use crate::drivers::serial;
+use crate::fb;
+use crate::vga::get_vgabuffer;
+
+/// Where console output is written
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum Console {
+    Vga    = 1,
+    Serial = 2,
+    Both   = 3,
+    Framebuffer = 4,
+    FramebufferAndSerial = 6,
+}
+
+static CONSOLE: AtomicUsize = AtomicUsize::new(Console::Vga as usize);
+
+impl Console {
+    /// Parses the value of the `console=` command line option
+    pub fn from_name(name: &str) -> Option<Console> {
+        match name {
+            "vga"    => Some(Console::Vga),
+            "serial" | "ttyS0" => Some(Console::Serial),
+            "both"   => Some(Console::Both),
+            "fb"     => Some(Console::Framebuffer),
+            _ => None,
+        }
+    }
+
+    fn includes(self, other: Console) -> bool {
+        self as usize & other as usize != 0
+    }
+}
+
+/// Selects where output is written from now on
+pub fn set(console: Console) {
+    CONSOLE.store(console as usize, Ordering::Relaxed);
+}
+
+/// Returns where output is written
+pub fn get() -> Console {
+    match CONSOLE.load(Ordering::Relaxed) {
+        1 => Console::Vga,
+        2 => Console::Serial,
+        3 => Console::Both,
+        4 => Console::Framebuffer,
+        _ => Console::FramebufferAndSerial,
+    }
+}
+
+/// Draws the screen output of the selected consoles into the framebuffer
+///
+/// Called once `fb::initialize()` has installed a writer, as the VGA buffer
+/// is not displayed in graphics modes. Serial output is unaffected.
+pub fn use_framebuffer() {
+    let selected = match get() {
+        Console::Vga | Console::Framebuffer => Console::Framebuffer,
+        Console::Serial => Console::Serial,
+        Console::Both | Console::FramebufferAndSerial => Console::FramebufferAndSerial,
+    };
+    set(selected);
+}
+
+/// Writes to the selected consoles. See `print!`.
+///
+/// Serial and framebuffer output is dropped until the device is initialized.
+pub fn print(args: fmt::Arguments) {
+    let console = get();
+    if console.includes(Console::Vga) {
+        let _ = get_vgabuffer().write_fmt(args);
+    }
+    if console.includes(Console::Framebuffer) {
+        if let Some(mut writer) = fb::framebuffer() {
+            let _ = writer.write_fmt(args);
+        }
+    }
+    if console.includes(Console::Serial) {
+        if let Some(mut port) = serial::com1() {
+            let _ = port.write_fmt(args);
+        }
+    }
+}
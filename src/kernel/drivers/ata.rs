@@ -0,0 +1,232 @@
+//! ATA PIO Disks
+//!
+//! Disks on the legacy IDE buses are driven by programmed I/O: a command is
+//! written to the task file registers and each sector is then transferred as
+//! 256 words through the data port, polling the status register in between.
+//! Only 28 bit LBA addressing is supported, limiting disks to 128 GiB.
+//!
+//! Writes may sit in the drive's cache until a CACHE FLUSH command, which
+//! `write_sectors()` issues before returning.
+//!
+//! Every wait on the status register gives up after `TIMEOUT_NS`, so a hung
+//! or missing drive fails the command rather than the kernel.
+
+use crate::arch::x86::intrinsics::{inb, inw, outb, outw};
+use crate::clock::{self, NS_PER_SEC};
+
+/// I/O and control ports of the primary bus
+pub const PRIMARY_IO: u16 = 0x1f0;
+pub const PRIMARY_CONTROL: u16 = 0x3f6;
+
+/// Bytes per sector
+pub const SECTOR_SIZE: usize = 512;
+
+/// Task file register offsets from the I/O base
+const DATA:         u16 = 0;
+const ERROR:        u16 = 1;
+const SECTOR_COUNT: u16 = 2;
+const LBA_LOW:      u16 = 3;
+const LBA_MID:      u16 = 4;
+const LBA_HIGH:     u16 = 5;
+const DRIVE:        u16 = 6;
+const STATUS:       u16 = 7;
+const COMMAND:      u16 = 7;
+
+/// How long a drive may stay busy, long enough for a disk to spin up
+const TIMEOUT_NS: u64 = 30 * NS_PER_SEC;
+
+/// Status read from a bus with no drives, whose lines float high
+const STATUS_FLOATING: u8 = 0xff;
+
+/// Status register bits
+const STATUS_ERR: u8 = 1 << 0;
+const STATUS_DRQ: u8 = 1 << 3;
+const STATUS_DF:  u8 = 1 << 5;
+const STATUS_BSY: u8 = 1 << 7;
+
+/// Commands
+const CMD_READ_SECTORS:  u8 = 0x20;
+const CMD_WRITE_SECTORS: u8 = 0x30;
+const CMD_CACHE_FLUSH:   u8 = 0xe7;
+const CMD_IDENTIFY:      u8 = 0xec;
+
+/// Drive register: LBA addressing, plus the obsolete bits which must be set
+const DRIVE_LBA: u8 = 0xe0;
+/// Drive register: select the slave rather than the master
+const DRIVE_SLAVE: u8 = 1 << 4;
+
+/// Words of IDENTIFY data holding the number of LBA28 sectors
+const IDENTIFY_LBA28_SECTORS: usize = 60;
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum AtaError {
+    /// No drive answered, or it is not an ATA disk
+    NoDevice,
+    /// The drive reported a fault
+    DeviceFault,
+    /// The command failed, with the contents of the error register
+    Error(u8),
+    /// The sectors lie beyond the end of the disk
+    OutOfRange,
+    /// The buffer does not hold exactly the sectors requested
+    BadBuffer,
+    /// The drive stayed busy, or never became ready to transfer
+    Timeout,
+}
+
+/// An identified ATA disk
+#[derive(Copy, Clone, Debug)]
+pub struct Drive {
+    io: u16,
+    control: u16,
+    slave: bool,
+    /// Number of addressable sectors
+    sectors: u32,
+}
+
+impl Drive {
+    /// Identifies the master or slave disk of the bus at `io`
+    ///
+    /// Drives exist only once identified, so reads and writes are never
+    /// issued to an absent or non-ATA device.
+    pub fn identify(io: u16, control: u16, slave: bool) -> Result<Drive, AtaError> {
+        let mut drive = Drive { io: io, control: control, slave: slave, sectors: 0 };
+        drive.select(0);
+        outb(io + SECTOR_COUNT, 0);
+        outb(io + LBA_LOW, 0);
+        outb(io + LBA_MID, 0);
+        outb(io + LBA_HIGH, 0);
+        outb(io + COMMAND, CMD_IDENTIFY);
+        let status = inb(io + STATUS);
+        if status == 0 || status == STATUS_FLOATING {
+            return Err(AtaError::NoDevice);
+        }
+        drive.wait_not_busy()?;
+        // ATAPI and SATA devices set the signature in the LBA registers
+        if inb(io + LBA_MID) != 0 || inb(io + LBA_HIGH) != 0 {
+            return Err(AtaError::NoDevice);
+        }
+        drive.wait_drq()?;
+
+        let mut identify = [0u16; SECTOR_SIZE / 2];
+        for word in identify.iter_mut() {
+            *word = inw(io + DATA);
+        }
+        drive.sectors = identify[IDENTIFY_LBA28_SECTORS] as u32
+            | (identify[IDENTIFY_LBA28_SECTORS + 1] as u32) << 16;
+        Ok(drive)
+    }
+
+    /// Returns the number of addressable sectors
+    pub fn sectors(&self) -> u32 {
+        self.sectors
+    }
+
+    /// Reads `count` sectors starting at `lba` into `buf`
+    pub fn read_sectors(&self, lba: u32, count: u8, buf: &mut [u8]) -> Result<(), AtaError> {
+        self.start(lba, count, buf.len(), CMD_READ_SECTORS)?;
+        for sector in buf.chunks_mut(SECTOR_SIZE) {
+            self.wait_drq()?;
+            for word in sector.chunks_mut(2) {
+                let value = inw(self.io + DATA);
+                word[0] = value as u8;
+                word[1] = (value >> 8) as u8;
+            }
+        }
+        Ok(())
+    }
+
+    /// Writes `count` sectors starting at `lba` from `buf`, then flushes them
+    pub fn write_sectors(&self, lba: u32, count: u8, buf: &[u8]) -> Result<(), AtaError> {
+        self.start(lba, count, buf.len(), CMD_WRITE_SECTORS)?;
+        for sector in buf.chunks(SECTOR_SIZE) {
+            self.wait_drq()?;
+            for word in sector.chunks(2) {
+                outw(self.io + DATA, word[0] as u16 | (word[1] as u16) << 8);
+            }
+        }
+        self.wait_ready()?;
+        self.flush()
+    }
+
+    /// Waits for the drive to commit any cached writes
+    pub fn flush(&self) -> Result<(), AtaError> {
+        self.select(0);
+        outb(self.io + COMMAND, CMD_CACHE_FLUSH);
+        self.wait_ready()
+    }
+
+    /// Validates a transfer and issues its command
+    ///
+    /// A count of 0 would mean 256 sectors to the drive, so it is rejected.
+    fn start(&self, lba: u32, count: u8, len: usize, command: u8) -> Result<(), AtaError> {
+        if count == 0 || len != count as usize * SECTOR_SIZE {
+            return Err(AtaError::BadBuffer);
+        }
+        if lba as u64 + count as u64 > self.sectors as u64 {
+            return Err(AtaError::OutOfRange);
+        }
+        self.select((lba >> 24) as u8 & 0x0f);
+        outb(self.io + SECTOR_COUNT, count);
+        outb(self.io + LBA_LOW, lba as u8);
+        outb(self.io + LBA_MID, (lba >> 8) as u8);
+        outb(self.io + LBA_HIGH, (lba >> 16) as u8);
+        outb(self.io + COMMAND, command);
+        Ok(())
+    }
+
+    /// Selects this drive, with the top bits of an LBA
+    fn select(&self, lba_high: u8) {
+        let slave = if self.slave { DRIVE_SLAVE } else { 0 };
+        outb(self.io + DRIVE, DRIVE_LBA | slave | lba_high);
+        self.delay();
+    }
+
+    /// Waits 400ns, by reading the alternate status register four times
+    fn delay(&self) {
+        for _ in 0..4 {
+            inb(self.control);
+        }
+    }
+
+    /// Waits until the drive is no longer busy, returning its status
+    fn wait_not_busy(&self) -> Result<u8, AtaError> {
+        let deadline = clock::now_ns() + TIMEOUT_NS;
+        loop {
+            let status = inb(self.io + STATUS);
+            if status & STATUS_BSY == 0 {
+                return Ok(status);
+            }
+            if clock::now_ns() >= deadline {
+                return Err(AtaError::Timeout);
+            }
+        }
+    }
+
+    /// Waits until the drive is no longer busy, checking for errors
+    fn wait_ready(&self) -> Result<u8, AtaError> {
+        self.delay();
+        let status = self.wait_not_busy()?;
+        if status & STATUS_DF != 0 {
+            return Err(AtaError::DeviceFault);
+        }
+        if status & STATUS_ERR != 0 {
+            return Err(AtaError::Error(inb(self.io + ERROR)));
+        }
+        Ok(status)
+    }
+
+    /// Waits until the drive is ready to transfer a sector
+    fn wait_drq(&self) -> Result<(), AtaError> {
+        let deadline = clock::now_ns() + TIMEOUT_NS;
+        loop {
+            let status = self.wait_ready()?;
+            if status & STATUS_DRQ != 0 {
+                return Ok(());
+            }
+            if clock::now_ns() >= deadline {
+                return Err(AtaError::Timeout);
+            }
+        }
+    }
+}
@@ -1 +1,4 @@
+pub mod ata;
 pub mod pci;
+pub mod rtc;
+pub mod serial;
@@ -32,4 +32,80 @@ impl HostBusBridge for x86PIO {
     }
 }
 
+/// Register holding the command (low half) and status (high half) words
+pub const COMMAND_REGISTER: u8 = 0x04;
 
+/// Bits of the command word
+pub const COMMAND_IO:                u16 = 1 << 0;
+pub const COMMAND_MMIO:              u16 = 1 << 1;
+pub const COMMAND_BUS_MASTER:        u16 = 1 << 2;
+pub const COMMAND_INTERRUPT_DISABLE: u16 = 1 << 10;
+
+/// A function of a device on a PCI bus
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct PciDevice {
+    pub bus: u8,
+    pub device: u8,
+    pub func: u8,
+}
+
+impl PciDevice {
+    pub fn new(bus: u8, device: u8, func: u8) -> PciDevice {
+        PciDevice { bus: bus, device: device, func: func }
+    }
+
+    /// Reads a register of the configuration space
+    pub fn read<B: HostBusBridge>(&self, bridge: &B, register: u8) -> u32 {
+        bridge.pci_cs_read(self.bus, self.device, self.func, register)
+    }
+
+    /// Writes a register of the configuration space
+    pub fn write<B: HostBusBridge>(&self, bridge: &B, register: u8, val: u32) {
+        bridge.pci_cs_write(self.bus, self.device, self.func, register, val)
+    }
+
+    /// Reads the command word
+    pub fn command<B: HostBusBridge>(&self, bridge: &B) -> u16 {
+        self.read(bridge, COMMAND_REGISTER) as u16
+    }
+
+    /// Writes the command word
+    ///
+    /// The status bits sharing the register are cleared by writing ones, so
+    /// zeros are written to them.
+    pub fn set_command<B: HostBusBridge>(&self, bridge: &B, command: u16) {
+        self.write(bridge, COMMAND_REGISTER, command as u32)
+    }
+
+    /// Sets bits of the command word, preserving the others
+    pub fn set_command_bits<B: HostBusBridge>(&self, bridge: &B, bits: u16) {
+        let command = self.command(bridge);
+        self.set_command(bridge, command | bits)
+    }
+
+    /// Clears bits of the command word, preserving the others
+    pub fn clear_command_bits<B: HostBusBridge>(&self, bridge: &B, bits: u16) {
+        let command = self.command(bridge);
+        self.set_command(bridge, command & !bits)
+    }
+
+    /// Allows the device to access memory itself, as required for DMA
+    pub fn enable_bus_master<B: HostBusBridge>(&self, bridge: &B) {
+        self.set_command_bits(bridge, COMMAND_BUS_MASTER)
+    }
+
+    /// Makes the device respond to its memory mapped BARs
+    pub fn enable_mmio<B: HostBusBridge>(&self, bridge: &B) {
+        self.set_command_bits(bridge, COMMAND_MMIO)
+    }
+
+    /// Makes the device respond to its I/O port BARs
+    pub fn enable_io<B: HostBusBridge>(&self, bridge: &B) {
+        self.set_command_bits(bridge, COMMAND_IO)
+    }
+
+    /// Stops the device from asserting legacy INTx interrupts
+    pub fn disable_interrupts<B: HostBusBridge>(&self, bridge: &B) {
+        self.set_command_bits(bridge, COMMAND_INTERRUPT_DISABLE)
+    }
+}
@@ -0,0 +1,133 @@
+//! CMOS Real Time Clock
+//!
+//! The RTC keeps wall-clock time while the machine is off. Its registers are
+//! read through the CMOS index (0x70) and data (0x71) ports. Depending on
+//! status register B, values are either BCD or binary and hours are either
+//! 12 or 24 hour. The clock updates once a second, during which the registers
+//! may be inconsistent, so they are read until two reads agree.
+
+use crate::arch::x86::intrinsics::{inb, outb};
+
+const CMOS_INDEX: u16 = 0x70;
+const CMOS_DATA:  u16 = 0x71;
+
+const REG_SECONDS: u8 = 0x00;
+const REG_MINUTES: u8 = 0x02;
+const REG_HOURS:   u8 = 0x04;
+const REG_DAY:     u8 = 0x07;
+const REG_MONTH:   u8 = 0x08;
+const REG_YEAR:    u8 = 0x09;
+const REG_STATUS_A: u8 = 0x0a;
+const REG_STATUS_B: u8 = 0x0b;
+
+/// Status A: an update is in progress
+const UPDATE_IN_PROGRESS: u8 = 1 << 7;
+/// Status B: hours are in 24 hour format
+pub const HOUR_24: u8 = 1 << 1;
+/// Status B: values are binary rather than BCD
+pub const BINARY: u8 = 1 << 2;
+/// Hours register: PM in 12 hour format
+const HOUR_PM: u8 = 1 << 7;
+
+/// The RTC has no reliable century register, so assume this one
+const CENTURY: u16 = 2000;
+
+/// Wall-clock time as reported by the RTC
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct DateTime {
+    pub year:   u16,
+    pub month:  u8,
+    pub day:    u8,
+    pub hour:   u8,
+    pub minute: u8,
+    pub second: u8,
+}
+
+/// Raw register values
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct Registers {
+    pub second: u8,
+    pub minute: u8,
+    pub hour:   u8,
+    pub day:    u8,
+    pub month:  u8,
+    pub year:   u8,
+}
+
+fn read_register(register: u8) -> u8 {
+    outb(CMOS_INDEX, register);
+    inb(CMOS_DATA)
+}
+
+fn update_in_progress() -> bool {
+    read_register(REG_STATUS_A) & UPDATE_IN_PROGRESS != 0
+}
+
+fn read_registers() -> Registers {
+    while update_in_progress() { }
+    Registers {
+        second: read_register(REG_SECONDS),
+        minute: read_register(REG_MINUTES),
+        hour:   read_register(REG_HOURS),
+        day:    read_register(REG_DAY),
+        month:  read_register(REG_MONTH),
+        year:   read_register(REG_YEAR),
+    }
+}
+
+/// Converts a binary coded decimal byte to binary
+pub fn bcd_to_binary(bcd: u8) -> u8 {
+    (bcd >> 4) * 10 + (bcd & 0x0f)
+}
+
+/// Expands a two digit year to a full year
+pub fn full_year(year: u8) -> u16 {
+    CENTURY + year as u16
+}
+
+/// Reads the current date and time
+pub fn read_datetime() -> DateTime {
+    // repeat until the values are stable across an update
+    let mut regs = read_registers();
+    loop {
+        let again = read_registers();
+        if again == regs {
+            break;
+        }
+        regs = again;
+    }
+    decode(regs, read_register(REG_STATUS_B))
+}
+
+/// Returns the current date and time. Alias of `read_datetime()`
+pub fn now() -> DateTime {
+    read_datetime()
+}
+
+/// Assembles a `DateTime` from raw registers in the format given by status
+/// register B
+pub fn decode(mut regs: Registers, status: u8) -> DateTime {
+    let pm = regs.hour & HOUR_PM != 0;
+    let mut hour = regs.hour & !HOUR_PM;
+    if status & BINARY == 0 {
+        regs.second = bcd_to_binary(regs.second);
+        regs.minute = bcd_to_binary(regs.minute);
+        hour = bcd_to_binary(hour);
+        regs.day = bcd_to_binary(regs.day);
+        regs.month = bcd_to_binary(regs.month);
+        regs.year = bcd_to_binary(regs.year);
+    }
+    if status & HOUR_24 == 0 {
+        // 12am is hour 0, 12pm is hour 12
+        hour = hour % 12 + if pm { 12 } else { 0 };
+    }
+
+    DateTime {
+        year:   full_year(regs.year),
+        month:  regs.month,
+        day:    regs.day,
+        hour:   hour,
+        minute: regs.minute,
+        second: regs.second,
+    }
+}
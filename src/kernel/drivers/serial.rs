@@ -0,0 +1,123 @@
+//! 16550 UART Serial Ports
+//!
+//! Serial output is the only console visible when running headless, e.g.
+//! under QEMU with `-display none -serial stdio`. The port is driven by
+//! polling: each byte waits until the transmit holding register is empty.
+//! Interrupt handlers print, so the port is locked with interrupts disabled.
+
+use core::fmt;
+use core::ops::{Deref, DerefMut};
+
+use crate::arch::x86::interrupts::InterruptGuard;
+use crate::arch::x86::intrinsics::{inb, outb};
+use crate::sync::{Once, SpinLock, SpinLockGuard};
+
+/// I/O base of the first serial port
+pub const COM1: u16 = 0x3f8;
+
+/// Register offsets from the base port
+const DATA:          u16 = 0; // divisor low byte while DLAB is set
+const INT_ENABLE:    u16 = 1; // divisor high byte while DLAB is set
+const FIFO_CONTROL:  u16 = 2;
+const LINE_CONTROL:  u16 = 3;
+const MODEM_CONTROL: u16 = 4;
+const LINE_STATUS:   u16 = 5;
+
+/// Line control: access the baud rate divisor
+const DLAB: u8 = 1 << 7;
+/// Line control: 8 data bits, no parity, one stop bit
+const LINE_8N1: u8 = 0x03;
+/// Line status: the transmit holding register is empty
+const TX_EMPTY: u8 = 1 << 5;
+
+/// Base clock of the UART divided by 16
+const MAX_BAUD: u32 = 115_200;
+pub const DEFAULT_BAUD: u32 = 38_400;
+
+static COM1_PORT: Once<SpinLock<SerialPort>> = Once::new();
+
+/// A serial port at some I/O base
+pub struct SerialPort {
+    base: u16,
+}
+
+/// COM1, locked with interrupts disabled
+///
+/// The lock is released before interrupts are restored, as fields are
+/// dropped in order.
+pub struct LockedPort<'a> {
+    port: SpinLockGuard<'a, SerialPort>,
+    _interrupts: InterruptGuard,
+}
+
+impl SerialPort {
+    /// Configures the port for 8N1 at `baud` with interrupts disabled
+    pub fn new(base: u16, baud: u32) -> SerialPort {
+        let divisor = (MAX_BAUD / baud) as u16;
+        outb(base + INT_ENABLE, 0);
+        outb(base + LINE_CONTROL, DLAB);
+        outb(base + DATA, divisor as u8);
+        outb(base + INT_ENABLE, (divisor >> 8) as u8);
+        outb(base + LINE_CONTROL, LINE_8N1);
+        outb(base + FIFO_CONTROL, 0xc7);  // enable and clear, 14 byte threshold
+        outb(base + MODEM_CONTROL, 0x03); // DTR and RTS
+        SerialPort { base: base }
+    }
+
+    /// Sends a byte, waiting for the transmitter to be ready
+    pub fn write_byte(&mut self, byte: u8) {
+        while inb(self.base + LINE_STATUS) & TX_EMPTY == 0 { }
+        outb(self.base + DATA, byte);
+    }
+}
+
+impl fmt::Write for SerialPort {
+    /// Sends a string, translating `\n` to `\r\n` for terminals
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        for byte in s.bytes() {
+            if byte == b'\n' {
+                self.write_byte(b'\r');
+            }
+            self.write_byte(byte);
+        }
+        Ok(())
+    }
+}
+
+/// Sets up COM1
+pub fn initialize() {
+    COM1_PORT.initialize(SpinLock::new(SerialPort::new(COM1, DEFAULT_BAUD)));
+}
+
+/// Returns COM1, or `None` before `initialize()`
+pub fn com1<'a>() -> Option<LockedPort<'a>> {
+    COM1_PORT.get().map(|port| {
+        let interrupts = InterruptGuard::new();
+        LockedPort {
+            port: port.lock(),
+            _interrupts: interrupts,
+        }
+    })
+}
+
+/// Returns COM1 without locking it, or `None` before `initialize()`
+///
+/// Only for the panic handler, which may have interrupted the holder of the
+/// lock. Output may interleave with whatever that holder was writing.
+pub unsafe fn com1_unlocked() -> Option<SerialPort> {
+    COM1_PORT.get().map(|_| SerialPort { base: COM1 })
+}
+
+impl<'a> Deref for LockedPort<'a> {
+    type Target = SerialPort;
+
+    fn deref(&self) -> &SerialPort {
+        &self.port
+    }
+}
+
+impl<'a> DerefMut for LockedPort<'a> {
+    fn deref_mut(&mut self) -> &mut SerialPort {
+        &mut self.port
+    }
+}
@@ -0,0 +1,290 @@
+//! Framebuffer Console
+//!
+//! On systems without text-mode VGA the bootloader may instead provide a
+//! linear framebuffer. `FramebufferWriter` draws text into a 32 bits per
+//! pixel framebuffer using the embedded font, tracking its cursor in
+//! character cells like the VGA writer. Rows of a framebuffer may be padded,
+//! so pixels are always addressed through the pitch.
+//!
+//! Drawing straight into video memory tears while scrolling, so once the heap
+//! is available the writer may draw into a back buffer instead (see
+//! `enable_double_buffering()`). Rows of text changed since the last `flush()`
+//! are then copied to the framebuffer, which happens after every write.
+//!
+//! `initialize()` installs the writer used by the `Console::Framebuffer`
+//! console. Like the VGA writer, it is locked with interrupts disabled.
+
+use alloc::vec::Vec;
+use core::fmt;
+use core::ops::{Deref, DerefMut};
+use core::ptr;
+
+use crate::arch::x86::interrupts::InterruptGuard;
+use crate::arch::x86::paging::{self, CacheType};
+use crate::sync::{Once, SpinLock, SpinLockGuard};
+use crate::vga::ColorCode;
+
+use self::font::{glyph, GLYPH_HEIGHT, GLYPH_WIDTH};
+
+pub mod font;
+
+/// Bytes per pixel of supported framebuffers
+pub const BYTES_PER_PIXEL: usize = 4;
+
+static FRAMEBUFFER: Once<SpinLock<FramebufferWriter>> = Once::new();
+
+/// Text console on a linear 32 bits per pixel framebuffer
+pub struct FramebufferWriter {
+    base: *mut u8,
+    /// Bytes per row of pixels
+    pitch: usize,
+    /// Size of the console in characters
+    columns: usize,
+    rows: usize,
+    col: usize,
+    row: usize,
+    foreground: u32,
+    background: u32,
+    /// Copy of the framebuffer which is drawn into, if double buffering
+    back: Option<Vec<u8>>,
+    /// Rows of text in the back buffer not yet flushed
+    dirty: Vec<bool>,
+}
+
+// the framebuffer is only reached through the lock in `FRAMEBUFFER`
+unsafe impl Send for FramebufferWriter {}
+
+/// The installed writer, locked with interrupts disabled
+///
+/// The lock is released before interrupts are restored, as fields are
+/// dropped in order.
+pub struct LockedFramebuffer<'a> {
+    writer: SpinLockGuard<'a, FramebufferWriter>,
+    _interrupts: InterruptGuard,
+}
+
+impl FramebufferWriter {
+    /// Creates a writer over the framebuffer at `base`
+    ///
+    /// `width` and `height` are in pixels, `pitch` in bytes. The caller must
+    /// ensure the framebuffer is mapped and stays so. Returns None unless the
+    /// framebuffer is 32 bits per pixel and holds at least one character.
+    pub unsafe fn new(base: *mut u8, width: usize, height: usize, pitch: usize, bpp: u8)
+                      -> Option<FramebufferWriter> {
+        let (columns, rows) = (width / GLYPH_WIDTH, height / GLYPH_HEIGHT);
+        if bpp as usize != BYTES_PER_PIXEL * 8 || columns == 0 || rows == 0 {
+            return None;
+        }
+        assert!(pitch >= width * BYTES_PER_PIXEL, "Framebuffer pitch smaller than a row");
+        Some(FramebufferWriter {
+            base: base,
+            pitch: pitch,
+            columns: columns,
+            rows: rows,
+            col: 0,
+            row: 0,
+            foreground: 0x00ff_ffff,
+            background: 0x0000_0000,
+            back: None,
+            dirty: Vec::new(),
+        })
+    }
+
+    /// Draws into a back buffer from now on. Requires the heap.
+    pub fn enable_double_buffering(&mut self) {
+        if self.back.is_some() {
+            return;
+        }
+        let size = self.rows * GLYPH_HEIGHT * self.pitch;
+        let mut back = alloc::vec![0; size];
+        unsafe {
+            ptr::copy_nonoverlapping(self.base, back.as_mut_ptr(), size);
+        }
+        self.back = Some(back);
+        self.dirty = alloc::vec![false; self.rows];
+    }
+
+    /// Copies the rows changed since the last flush to the framebuffer
+    ///
+    /// Does nothing unless double buffering.
+    pub fn flush(&mut self) {
+        let back = match self.back {
+            Some(ref back) => back,
+            None => return,
+        };
+        let line_bytes = GLYPH_HEIGHT * self.pitch;
+        for (row, dirty) in self.dirty.iter_mut().enumerate() {
+            if *dirty {
+                let offset = row * line_bytes;
+                unsafe {
+                    ptr::copy_nonoverlapping(back[offset..].as_ptr(),
+                                             self.base.offset(offset as isize), line_bytes);
+                }
+                *dirty = false;
+            }
+        }
+    }
+
+    /// Returns the buffer drawn into
+    fn target(&mut self) -> *mut u8 {
+        match self.back {
+            Some(ref mut back) => back.as_mut_ptr(),
+            None => self.base,
+        }
+    }
+
+    /// Records that a row of text must be flushed
+    fn mark_dirty(&mut self, row: usize) {
+        if let Some(dirty) = self.dirty.get_mut(row) {
+            *dirty = true;
+        }
+    }
+
+    /// Sets the colors of text written from now on, as 0x00RRGGBB
+    pub fn set_colors(&mut self, foreground: u32, background: u32) {
+        self.foreground = foreground;
+        self.background = background;
+    }
+
+    /// Sets the colors of text written from now on from a VGA color code
+    pub fn set_color_code(&mut self, color_code: ColorCode) {
+        self.set_colors(color_code.foreground_rgb(), color_code.background_rgb());
+    }
+
+    /// Returns the cursor position in characters as (row, column)
+    pub fn cursor(&self) -> (usize, usize) {
+        (self.row, self.col)
+    }
+
+    /// Clears the screen and moves the cursor to the top left
+    pub fn clear(&mut self) {
+        for row in 0..self.rows {
+            self.clear_row(row);
+        }
+        self.row = 0;
+        self.col = 0;
+    }
+
+    fn write_byte(&mut self, byte: u8) {
+        match byte {
+            b'\n' => self.new_line(),
+            byte => {
+                if self.col >= self.columns {
+                    self.new_line();
+                }
+                let (row, col) = (self.row, self.col);
+                self.draw_glyph(row, col, byte);
+                self.col += 1;
+            }
+        }
+    }
+
+    /// Moves the cursor to the next row, scrolling if on the last
+    fn new_line(&mut self) {
+        if self.row + 1 >= self.rows {
+            // move every row of text up by one in a single copy
+            let line_bytes = GLYPH_HEIGHT * self.pitch;
+            let target = self.target();
+            unsafe {
+                ptr::copy(target.offset(line_bytes as isize), target,
+                          (self.rows - 1) * line_bytes);
+            }
+            for row in 0..self.rows {
+                self.mark_dirty(row);
+            }
+        } else {
+            self.row += 1;
+        }
+        let row = self.row; // borrowck
+        self.clear_row(row);
+        self.col = 0;
+    }
+
+    /// Fills a row of characters with the background color
+    fn clear_row(&mut self, row: usize) {
+        let background = self.background;
+        self.mark_dirty(row);
+        for y in row * GLYPH_HEIGHT..(row + 1) * GLYPH_HEIGHT {
+            for x in 0..self.columns * GLYPH_WIDTH {
+                self.put_pixel(x, y, background);
+            }
+        }
+    }
+
+    fn draw_glyph(&mut self, row: usize, col: usize, byte: u8) {
+        let (foreground, background) = (self.foreground, self.background);
+        self.mark_dirty(row);
+        for (dy, &bits) in glyph(byte).iter().enumerate() {
+            for dx in 0..GLYPH_WIDTH {
+                let set = bits & (0x80 >> dx) != 0;
+                self.put_pixel(col * GLYPH_WIDTH + dx, row * GLYPH_HEIGHT + dy,
+                               if set { foreground } else { background });
+            }
+        }
+    }
+
+    fn put_pixel(&mut self, x: usize, y: usize, color: u32) {
+        let offset = y * self.pitch + x * BYTES_PER_PIXEL;
+        let target = self.target();
+        unsafe {
+            ptr::write_volatile(target.offset(offset as isize) as *mut u32, color);
+        }
+    }
+}
+
+impl fmt::Write for FramebufferWriter {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        for byte in s.bytes() {
+            self.write_byte(byte)
+        }
+        self.flush();
+        Ok(())
+    }
+}
+
+impl<'a> Deref for LockedFramebuffer<'a> {
+    type Target = FramebufferWriter;
+
+    fn deref(&self) -> &FramebufferWriter {
+        &self.writer
+    }
+}
+
+impl<'a> DerefMut for LockedFramebuffer<'a> {
+    fn deref_mut(&mut self) -> &mut FramebufferWriter {
+        &mut self.writer
+    }
+}
+
+/// Maps the framebuffer at `paddr` and installs a writer drawing into it
+///
+/// The framebuffer is mapped write combining, cleared and double buffered.
+/// Returns false, mapping nothing, unless it is 32 bits per pixel. Requires
+/// the kernel tables and the heap.
+pub fn initialize(paddr: usize, width: usize, height: usize, pitch: usize, bpp: u8) -> bool {
+    if bpp as usize != BYTES_PER_PIXEL * 8 {
+        return false;
+    }
+    let vaddr = paging::map_device(paddr, height * pitch, CacheType::WriteCombining);
+    let writer = unsafe { FramebufferWriter::new(vaddr as *mut u8, width, height, pitch, bpp) };
+    match writer {
+        Some(mut writer) => {
+            writer.clear();
+            writer.enable_double_buffering();
+            FRAMEBUFFER.initialize(SpinLock::new(writer));
+            true
+        }
+        None => false,
+    }
+}
+
+/// Returns the installed writer, or `None` before `initialize()`
+pub fn framebuffer<'a>() -> Option<LockedFramebuffer<'a>> {
+    FRAMEBUFFER.get().map(|writer| {
+        let interrupts = InterruptGuard::new();
+        LockedFramebuffer {
+            writer: writer.lock(),
+            _interrupts: interrupts,
+        }
+    })
+}
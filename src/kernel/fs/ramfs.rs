@@ -0,0 +1,161 @@
+//! Read-only filesystem over the initial ramdisk
+//!
+//! The initrd is a tar archive loaded by the bootloader as a boot module.
+//! Files are never copied: `open()` returns a slice of the archive itself.
+//! Each file is a 512 byte header followed by its contents padded to 512
+//! bytes, and the archive ends with an empty header. All offsets come from
+//! the archive, so every access is checked against its size.
+
+use core;
+
+use crate::sync::Once;
+
+/// Size of tar headers and the granularity of file contents
+const BLOCK_SIZE: usize = 512;
+
+const NAME_OFFSET: usize = 0;
+const NAME_SIZE:   usize = 100;
+const SIZE_OFFSET: usize = 124;
+const SIZE_SIZE:   usize = 12;
+const TYPE_OFFSET: usize = 156;
+const PREFIX_OFFSET: usize = 345;
+const PREFIX_SIZE:   usize = 155;
+
+/// Type flags of regular files
+const TYPE_FILE: u8 = b'0';
+const TYPE_FILE_OLD: u8 = 0;
+
+static RAMFS: Once<Archive> = Once::new();
+
+/// A tar archive
+#[derive(Copy, Clone)]
+pub struct Archive {
+    image: &'static [u8],
+}
+
+/// A regular file in an archive
+#[derive(Copy, Clone)]
+pub struct File {
+    /// Directory part of the path, possibly empty
+    pub prefix: &'static str,
+    pub name: &'static str,
+    pub data: &'static [u8],
+}
+
+/// Iterator over the files in an archive
+pub struct Files {
+    image: &'static [u8],
+    offset: usize,
+}
+
+impl Archive {
+    pub fn new(image: &'static [u8]) -> Archive {
+        Archive { image: image }
+    }
+
+    /// Returns an iterator over the regular files
+    pub fn files(&self) -> Files {
+        Files { image: self.image, offset: 0 }
+    }
+
+    /// Looks up a file by its path
+    ///
+    /// Leading `/` and `./` are ignored, so `/bin/init` matches an archive
+    /// entry named `./bin/init`.
+    pub fn open(&self, path: &str) -> Option<&'static [u8]> {
+        let path = trim_path(path);
+        self.files().find(|file| file.matches(path)).map(|file| file.data)
+    }
+}
+
+impl File {
+    /// Is this the file at the given (trimmed) path?
+    fn matches(&self, path: &str) -> bool {
+        if self.prefix.is_empty() {
+            return trim_path(self.name) == path;
+        }
+        let prefix = trim_path(self.prefix.trim_right_matches('/'));
+        path.len() == prefix.len() + 1 + self.name.len()
+            && path.starts_with(prefix)
+            && path[prefix.len()..].starts_with('/')
+            && path.ends_with(self.name)
+    }
+}
+
+impl Iterator for Files {
+    type Item = File;
+
+    fn next(&mut self) -> Option<File> {
+        loop {
+            let header = self.image.get(self.offset..self.offset + BLOCK_SIZE)?;
+            if header.iter().all(|&byte| byte == 0) {
+                return None; // end of archive
+            }
+
+            let size = parse_octal(&header[SIZE_OFFSET..SIZE_OFFSET + SIZE_SIZE])?;
+            let start = self.offset + BLOCK_SIZE;
+            let end = start.checked_add(size)?;
+            let data = self.image.get(start..end)?;
+            self.offset = start + (size + BLOCK_SIZE - 1) / BLOCK_SIZE * BLOCK_SIZE;
+
+            let kind = header[TYPE_OFFSET];
+            if kind != TYPE_FILE && kind != TYPE_FILE_OLD {
+                continue; // directories, links, ...
+            }
+            let image = self.image;
+            let header_start = start - BLOCK_SIZE;
+            let field = |offset: usize, size: usize| {
+                parse_str(&image[header_start + offset..header_start + offset + size])
+            };
+            return Some(File {
+                prefix: field(PREFIX_OFFSET, PREFIX_SIZE)?,
+                name: field(NAME_OFFSET, NAME_SIZE)?,
+                data: data,
+            });
+        }
+    }
+}
+
+/// Strips leading `/` and `./` from a path
+fn trim_path(mut path: &str) -> &str {
+    loop {
+        if path.starts_with('/') {
+            path = &path[1..];
+        } else if path.starts_with("./") {
+            path = &path[2..];
+        } else {
+            return path;
+        }
+    }
+}
+
+/// Parses a NUL or space terminated octal number
+fn parse_octal(field: &[u8]) -> Option<usize> {
+    let mut value: usize = 0;
+    for &byte in field {
+        match byte {
+            b'0'...b'7' => {
+                value = value.checked_mul(8)?.checked_add((byte - b'0') as usize)?;
+            }
+            0 | b' ' => break,
+            _ => return None,
+        }
+    }
+    Some(value)
+}
+
+/// Parses a NUL padded string field
+fn parse_str(field: &'static [u8]) -> Option<&'static str> {
+    let len = field.iter().position(|&byte| byte == 0).unwrap_or(field.len());
+    core::str::from_utf8(&field[..len]).ok()
+}
+
+/// Mounts an archive as the root filesystem
+pub fn initialize(image: &'static [u8]) {
+    RAMFS.initialize(Archive::new(image));
+}
+
+/// Returns the contents of a file in the root filesystem
+pub fn open(path: &str) -> Option<&'static [u8]> {
+    RAMFS.get().and_then(|archive| archive.open(path))
+}
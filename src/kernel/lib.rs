@@ -19,8 +19,17 @@ extern crate spin;
 // Import macros first
 #[macro_use]
 pub mod vga;
+#[macro_use]
+pub mod log;
 
 pub mod arch;
+pub mod clock;
+pub mod cmdline;
+pub mod console;
 pub mod main;
 pub mod vestige;
 pub mod drivers;
+pub mod fs;
+pub mod fb;
+pub mod sync;
+pub mod util;
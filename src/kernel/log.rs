@@ -0,0 +1,129 @@
+//! Leveled Logging
+//!
+//! The `log_error!`, `log_warn!`, `log_info!`, `log_debug!` and `log_trace!`
+//! macros print a message prefixed by its level, errors in red and warnings in
+//! yellow. Messages less severe than `MAX_LOG_LEVEL` are compiled out, and
+//! those less severe than the runtime threshold (see `set_level()`) are
+//! skipped before being formatted.
+
+use core::fmt;
+use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+
+use crate::clock::{self, NS_PER_SEC};
+use crate::console;
+use crate::vga::{get_vgabuffer, Color, ColorCode};
+
+/// The severity of a message, most severe first
+#[derive(Copy, Clone, Debug, Eq, PartialEq, Ord, PartialOrd)]
+pub enum Level {
+    Error = 1,
+    Warn  = 2,
+    Info  = 3,
+    Debug = 4,
+    Trace = 5,
+}
+
+/// The least severe level which is printed
+#[cfg(debug_assertions)]
+pub const MAX_LOG_LEVEL: Level = Level::Trace;
+/// The least severe level which is printed
+#[cfg(not(debug_assertions))]
+pub const MAX_LOG_LEVEL: Level = Level::Info;
+
+/// The least severe level which is printed, adjusted at runtime
+///
+/// Trace messages are noisy, so they must be enabled explicitly.
+static LEVEL: AtomicUsize = AtomicUsize::new(Level::Debug as usize);
+/// Are messages prefixed by the time since boot?
+static TIMESTAMPS: AtomicBool = AtomicBool::new(false);
+
+impl Level {
+    /// Returns the prefix of messages at this level
+    pub fn name(self) -> &'static str {
+        match self {
+            Level::Error => "ERROR",
+            Level::Warn  => "WARN",
+            Level::Info  => "INFO",
+            Level::Debug => "DEBUG",
+            Level::Trace => "TRACE",
+        }
+    }
+
+    /// Parses a level from its name as given on the command line, e.g. `debug`
+    pub fn from_name(name: &str) -> Option<Level> {
+        match name {
+            "error" => Some(Level::Error),
+            "warn"  => Some(Level::Warn),
+            "info"  => Some(Level::Info),
+            "debug" => Some(Level::Debug),
+            "trace" => Some(Level::Trace),
+            _ => None,
+        }
+    }
+
+    /// Returns the color of messages at this level, if not the default
+    fn color(self) -> Option<ColorCode> {
+        match self {
+            Level::Error => Some(ColorCode::new(Color::Red, Color::Black)),
+            Level::Warn  => Some(ColorCode::new(Color::Yellow, Color::Black)),
+            _ => None,
+        }
+    }
+
+    /// Are messages at this level printed?
+    pub fn enabled(self) -> bool {
+        self as u8 <= MAX_LOG_LEVEL as u8
+            && self as usize <= LEVEL.load(Ordering::Relaxed)
+    }
+}
+
+/// Sets the least severe level which is printed
+///
+/// Levels beyond `MAX_LOG_LEVEL` remain compiled out regardless.
+pub fn set_level(level: Level) {
+    LEVEL.store(level as usize, Ordering::Relaxed);
+}
+
+/// Enables or disables prefixing messages by the time since boot
+pub fn set_timestamps(enabled: bool) {
+    TIMESTAMPS.store(enabled, Ordering::Relaxed);
+}
+
+/// Prints a message at the given level. See the `log_*!` macros.
+pub fn log(level: Level, args: fmt::Arguments) {
+    let _guard = level.color().map(|color| get_vgabuffer().push_color(color));
+    if TIMESTAMPS.load(Ordering::Relaxed) {
+        let now = clock::now_ns();
+        console::print(format_args!("[{:5}.{:03}] ", now / NS_PER_SEC, now % NS_PER_SEC / 1_000_000));
+    }
+    console::print(format_args!("[{}] {}\n", level.name(), args));
+}
+
+macro_rules! log_at {
+    ($level:expr, $($arg:tt)*) => ({
+        let level = $level;
+        if level.enabled() {
+            $crate::log::log(level, format_args!($($arg)*));
+        }
+    });
+}
+
+macro_rules! log_error {
+    ($($arg:tt)*) => (log_at!($crate::log::Level::Error, $($arg)*));
+}
+
+macro_rules! log_warn {
+    ($($arg:tt)*) => (log_at!($crate::log::Level::Warn, $($arg)*));
+}
+
+macro_rules! log_info {
+    ($($arg:tt)*) => (log_at!($crate::log::Level::Info, $($arg)*));
+}
+
+macro_rules! log_debug {
+    ($($arg:tt)*) => (log_at!($crate::log::Level::Debug, $($arg)*));
+}
+
+macro_rules! log_trace {
+    ($($arg:tt)*) => (log_at!($crate::log::Level::Trace, $($arg)*));
+}
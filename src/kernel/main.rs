@@ -1,7 +1,13 @@
+use crate::arch::generic::intrinsics::wait_for_interrupt;
+use crate::arch::generic::watchdog;
+
 /// Main architecture-independent kernel functionality
 ///
 /// Called from `arch::kstart()`
 pub fn kmain() {
     println!("kmain()");
-    loop {}
+    loop {
+        watchdog::pet();
+        wait_for_interrupt(); // the timer wakes this to pet again
+    }
 }
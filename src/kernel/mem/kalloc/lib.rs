@@ -1,7 +1,12 @@
 //! Kernel Heap Allocator
 //!
-//! Currently implemented using a simplistic bump allocator. Freed memory is
-//! just leaked.
+//! Currently implemented using a simplistic bump allocator, `Arena`, over the
+//! kernel heap. Freed memory is just leaked, except for the most recent
+//! allocation. Subsystems may create their own `Arena` over a separate region
+//! to avoid fragmenting the heap.
+//!
+//! Any power of two alignment up to `MAX_ALIGN` is supported. Larger
+//! alignments are refused rather than wasting most of the heap on padding.
 #![feature(const_fn)]
 #![feature(allocator_internals)]
 #![feature(alloc)]
@@ -16,37 +21,78 @@ use spin::Mutex;
 use alloc::alloc::{Alloc, GlobalAlloc, Layout, AllocErr};
 use core::ptr::NonNull;
 
-pub const HEAP_SIZE:  usize = 1024 * 1024; // 1MiB
+/// A multiple of 2MiB, so the heap is mapped with huge pages
+pub const HEAP_SIZE:  usize = 2 * 1024 * 1024; // 2MiB
 pub const HEAP_START: usize = 0xffff_e000_0000_0000;
-
-fn align_up(start: usize, align: usize) -> usize {
+/// Largest alignment the heap will satisfy
+pub const MAX_ALIGN:  usize = 64 * 1024; // 64KiB
+
+/// Rounds `start` up to a multiple of `align`, which must be a power of two
+///
+/// Returns `None` if the result does not fit in a `usize`.
+fn align_up(start: usize, align: usize) -> Option<usize> {
+    debug_assert!(align.is_power_of_two(), "alignment must be a power of two");
     let mask = align - 1;
-    (start + mask) & !mask
+    start.checked_add(mask).map(|end| end & !mask)
 }
 
-struct BumpAllocator {
+/// Bump allocator over a caller-provided region of memory
+pub struct Arena {
+    start: usize,
     next: usize,
     end: usize,
+    /// Start of the latest allocation and the value of `next` before it,
+    /// so that freeing it also reclaims its alignment padding
+    last: Option<(usize, usize)>,
 }
 
-impl BumpAllocator {
-    const fn new(start: usize, size: usize) -> BumpAllocator {
-        BumpAllocator {
+impl Arena {
+    /// Creates an arena allocating from `size` bytes at `start`
+    ///
+    /// The region must be mapped, writable and unused for as long as
+    /// allocations are made from the arena.
+    pub const fn new(start: usize, size: usize) -> Arena {
+        Arena {
+            start: start,
             next: start,
             end: start + size,
+            last: None,
         }
     }
+
+    /// Returns the number of bytes allocated, including alignment padding
+    pub fn used(&self) -> usize {
+        self.next - self.start
+    }
+
+    /// Returns the number of bytes left for allocation
+    pub fn remaining(&self) -> usize {
+        self.end - self.next
+    }
+
+    /// Frees every allocation at once, making the whole region available
+    ///
+    /// Nothing allocated from the arena may be used afterwards, which is why
+    /// this is unsafe: the arena cannot tell whether anything still is.
+    pub unsafe fn reset(&mut self) {
+        self.next = self.start;
+        self.last = None;
+    }
 }
 
-unsafe impl Alloc for BumpAllocator {
+unsafe impl Alloc for Arena {
     unsafe fn alloc(&mut self, layout: Layout) -> Result<NonNull<u8>, AllocErr> {
         let size = layout.size();
         let align = layout.align();
+        if align > MAX_ALIGN {
+            return Err(AllocErr);
+        }
 
-        let alloc_start = align_up(self.next, align);
-        let alloc_end = alloc_start + size;
+        let alloc_start = align_up(self.next, align).ok_or(AllocErr)?;
+        let alloc_end = alloc_start.checked_add(size).ok_or(AllocErr)?;
 
         if alloc_end <= self.end {
+            self.last = Some((alloc_start, self.next));
             self.next = alloc_end;
 
             Ok(NonNull::new_unchecked(alloc_start as *mut u8))
@@ -55,19 +101,25 @@ unsafe impl Alloc for BumpAllocator {
         }
     }
 
-    unsafe fn dealloc(&mut self, _ptr: NonNull<u8>, _layout: Layout) {
-        // leak memory for time being
+    unsafe fn dealloc(&mut self, ptr: NonNull<u8>, _layout: Layout) {
+        // only the latest allocation can be returned, otherwise leak it
+        if let Some((start, prev_next)) = self.last {
+            if ptr.as_ptr() as usize == start {
+                self.next = prev_next;
+                self.last = None;
+            }
+        }
     }
 }
 
 struct GlobalAllocator {
-    allocator: Mutex<BumpAllocator>,
+    allocator: Mutex<Arena>,
 }
 
 impl GlobalAllocator {
     const fn new() -> GlobalAllocator {
         GlobalAllocator {
-            allocator: Mutex::new(BumpAllocator::new(HEAP_START, HEAP_SIZE)),
+            allocator: Mutex::new(Arena::new(HEAP_START, HEAP_SIZE)),
         }
     }
 }
@@ -0,0 +1,158 @@
+//! Synchronization Primitives
+//!
+//! Wrappers around `spin` suited to kernel statics.
+
+use core::ops::{Deref, DerefMut};
+#[cfg(debug_assertions)]
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+use spin;
+
+/// A value initialized exactly once, for statics set up during boot
+///
+/// Unlike `static mut X: Option<T>`, initialization is safe to race and
+/// access before initialization is detected rather than undefined.
+pub struct Once<T> {
+    inner: spin::Once<T>,
+}
+
+impl<T> Once<T> {
+    pub const fn new() -> Once<T> {
+        Once { inner: spin::Once::new() }
+    }
+
+    /// Returns the value, initializing it with `builder` if necessary
+    ///
+    /// Concurrent callers wait for the first to finish, so `builder` runs at
+    /// most once.
+    pub fn call_once<F: FnOnce() -> T>(&self, builder: F) -> &T {
+        self.inner.call_once(builder)
+    }
+
+    /// Sets the value, panicking if it has already been set
+    pub fn initialize(&self, value: T) -> &T {
+        let mut fresh = false;
+        let result = self.inner.call_once(|| {
+            fresh = true;
+            value
+        });
+        assert!(fresh, "Once initialized twice");
+        result
+    }
+
+    /// Returns the value, if initialized
+    pub fn get(&self) -> Option<&T> {
+        self.inner.r#try()
+    }
+
+    /// Returns the value, panicking with `what` if it is not yet initialized
+    pub fn expect(&self, what: &str) -> &T {
+        match self.get() {
+            Some(value) => value,
+            None => panic!("{} used before initialization", what),
+        }
+    }
+}
+
+/// Value of `SpinLock::owner` while unlocked
+#[cfg(debug_assertions)]
+const NO_OWNER: usize = usize::MAX;
+
+/// A spin lock which, in debug builds, detects a core locking it twice
+///
+/// With `spin::Mutex`, an interrupt handler taking a lock held by the code it
+/// interrupted spins forever. Debug builds instead record the core holding
+/// the lock and panic if that core tries to take it again. Release builds
+/// are a plain `spin::Mutex`.
+pub struct SpinLock<T> {
+    inner: spin::Mutex<T>,
+    #[cfg(debug_assertions)]
+    owner: AtomicUsize,
+}
+
+/// Releases a `SpinLock` when dropped
+pub struct SpinLockGuard<'a, T: 'a> {
+    guard: spin::MutexGuard<'a, T>,
+    lock: &'a SpinLock<T>,
+}
+
+impl<T> SpinLock<T> {
+    #[cfg(debug_assertions)]
+    pub const fn new(value: T) -> SpinLock<T> {
+        SpinLock { inner: spin::Mutex::new(value), owner: AtomicUsize::new(NO_OWNER) }
+    }
+
+    #[cfg(not(debug_assertions))]
+    pub const fn new(value: T) -> SpinLock<T> {
+        SpinLock { inner: spin::Mutex::new(value) }
+    }
+
+    /// Takes the lock, spinning until it is free
+    ///
+    /// Panics in debug builds if this core already holds it.
+    pub fn lock(&self) -> SpinLockGuard<T> {
+        self.check_owner();
+        let guard = self.inner.lock();
+        self.set_owner(true);
+        SpinLockGuard { guard: guard, lock: self }
+    }
+
+    /// Takes the lock if it is free
+    pub fn try_lock(&self) -> Option<SpinLockGuard<T>> {
+        let guard = self.inner.try_lock()?;
+        self.set_owner(true);
+        Some(SpinLockGuard { guard: guard, lock: self })
+    }
+
+    /// Panics if this core holds the lock
+    #[cfg(debug_assertions)]
+    fn check_owner(&self) {
+        let cpu = current_cpu();
+        if self.owner.load(Ordering::Relaxed) == cpu {
+            panic!("recursive/cross-ISR lock on cpu {}", cpu);
+        }
+    }
+
+    /// Records this core as holding the lock, or no core
+    #[cfg(debug_assertions)]
+    fn set_owner(&self, held: bool) {
+        let owner = if held { current_cpu() } else { NO_OWNER };
+        self.owner.store(owner, Ordering::Relaxed);
+    }
+
+    #[cfg(not(debug_assertions))]
+    fn check_owner(&self) {}
+
+    #[cfg(not(debug_assertions))]
+    fn set_owner(&self, _held: bool) {}
+}
+
+impl<'a, T> Deref for SpinLockGuard<'a, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        &*self.guard
+    }
+}
+
+impl<'a, T> DerefMut for SpinLockGuard<'a, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        &mut *self.guard
+    }
+}
+
+impl<'a, T> Drop for SpinLockGuard<'a, T> {
+    /// Clears the owner, before the inner guard releases the lock
+    fn drop(&mut self) {
+        self.lock.set_owner(false);
+    }
+}
+
+/// Returns the id of the executing core
+///
+/// This is the local APIC id, or 0 while only the bootstrap processor runs
+/// without one.
+pub fn current_cpu() -> usize {
+    use crate::arch::x86::apic;
+    if apic::is_enabled() { apic::id() as usize } else { 0 }
+}
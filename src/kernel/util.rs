@@ -0,0 +1,45 @@
+//! Debugging Utilities
+
+use core;
+use core::fmt;
+
+/// Bytes shown per line of a hexdump
+const BYTES_PER_LINE: usize = 16;
+
+/// Prints the memory at `addr` in the classic hexdump layout
+///
+/// The memory must be mapped and readable.
+pub fn hexdump(addr: usize, len: usize) {
+    let bytes = unsafe { core::slice::from_raw_parts(addr as *const u8, len) };
+    let _ = write_hexdump(crate::vga::get_vgabuffer(), bytes, addr);
+}
+
+/// Writes a hexdump of `bytes`, labelling the first with address `base`
+///
+/// Each line shows the address, sixteen bytes in hex, and the same bytes as
+/// ASCII with anything unprintable replaced by '.', for example:
+///
+/// ```text
+/// ffffffff80100000  7f 45 4c 46 02 01 01 00  00 00 00 00 00 00 00 00  |.ELF............|
+/// ```
+pub fn write_hexdump<W: fmt::Write>(w: &mut W, bytes: &[u8], base: usize) -> fmt::Result {
+    for (i, line) in bytes.chunks(BYTES_PER_LINE).enumerate() {
+        write!(w, "{:016x} ", base + i * BYTES_PER_LINE)?;
+        for column in 0..BYTES_PER_LINE {
+            if column % 8 == 0 {
+                w.write_str(" ")?;
+            }
+            match line.get(column) {
+                Some(byte) => write!(w, "{:02x} ", byte)?,
+                None => w.write_str("   ")?, // pad a partial line
+            }
+        }
+        w.write_str(" |")?;
+        for &byte in line {
+            let c = if byte >= 0x20 && byte < 0x7f { byte as char } else { '.' };
+            w.write_char(c)?;
+        }
+        w.write_str("|\n")?;
+    }
+    Ok(())
+}
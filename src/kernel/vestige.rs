@@ -1,21 +1,89 @@
 use core;
+use core::fmt::{self, Write};
 use core::panic::PanicInfo;
 use core::alloc::Layout;
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+use crate::arch::generic::backtrace;
+use crate::arch::generic::intrinsics::{halt, halt_other_cores};
+use crate::drivers::serial::{self, SerialPort};
+use crate::sync::current_cpu;
+use crate::vga::EmergencyWriter;
+
+/// `PANICKING_CPU` before any panic
+const NO_CPU: usize = usize::max_value();
+
+/// Set to the core of the first panic, so later ones do not interleave their
+/// output with it or recurse
+static PANICKING_CPU: AtomicUsize = AtomicUsize::new(NO_CPU);
 
 #[lang = "eh_personality"] extern fn eh_personality() {}
 
 #[panic_handler]
 pub fn rust_panic_handler(panic: &PanicInfo) -> ! {
-    use crate::vga::print_error;
-    // TODO SMP need to stop other cores
+    let cpu = current_cpu();
+    match PANICKING_CPU.compare_and_swap(NO_CPU, cpu, Ordering::SeqCst) {
+        NO_CPU => {}
+        owner if owner == cpu => nested_panic(), // panicked while reporting
+        _ => halt(), // another core is reporting, and is halting this one
+    }
+    halt_other_cores();
 
+    // the panic may have happened with the screen or serial port locked
+    let mut out = unsafe { PanicWriter::new() };
     let unknown = format_args!("unknown");
     let msg = panic.message().unwrap_or(&unknown);
     if let Some(loc) = panic.location()  {
-        print_error(format_args!("PANIC at {}:{}:{}\n    {}", loc.file(), loc.line(), loc.column(), msg));
+        let _ = write!(out, "PANIC at {}:{}:{}\n    {}\n", loc.file(), loc.line(), loc.column(), msg);
     } else {
-        print_error(format_args!("PANIC at unknown\n    {}", msg));
+        let _ = write!(out, "PANIC at unknown\n    {}\n", msg);
+    }
+
+    let _ = write!(out, "backtrace:");
+    backtrace::trace(|addr| {
+        let _ = write!(out, " 0x{:x}", addr);
+    });
+    halt()
+}
+
+/// Writes a panic report to the screen, and to the serial port once it is
+/// initialized, since serial output outlives the machine in logs
+///
+/// Neither lock is taken, so this also suits reports from contexts which may
+/// have interrupted their holder, such as NMIs and the watchdog.
+pub struct PanicWriter {
+    screen: EmergencyWriter,
+    serial: Option<SerialPort>,
+}
+
+impl PanicWriter {
+    /// Unsafe since output may interleave with that of the lock holders
+    pub unsafe fn new() -> PanicWriter {
+        PanicWriter { screen: EmergencyWriter::new(), serial: serial::com1_unlocked() }
+    }
+}
+
+impl fmt::Write for PanicWriter {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        if let Some(ref mut serial) = self.serial {
+            let _ = serial.write_str(s);
+        }
+        self.screen.write_str(s)
+    }
+}
+
+/// Reports a panic raised while reporting another, then halts
+///
+/// Whatever broke the first report may well break this one too, so a fixed
+/// message is printed without formatting.
+fn nested_panic() -> ! {
+    unsafe {
+        crate::vga::emergency_print("\nNESTED PANIC");
+        if let Some(mut serial) = serial::com1_unlocked() {
+            let _ = serial.write_str("\nNESTED PANIC\n");
+        }
     }
+    halt()
 }
 
 #[alloc_error_handler]
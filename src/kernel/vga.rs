@@ -6,27 +6,53 @@
 //! VGA Buffer Access
 //!
 //! This module provides the ability to write characters to the screen buffer.
+//!
+//! Once the heap is available, lines scrolling off the top of the screen are
+//! kept in a scrollback buffer which may be reviewed with `scroll_up()` and
+//! `scroll_down()`. The next write returns the view to the live screen.
 
 // TODO consider moving VGA access to arch::x86 or a device driver
 
-use core::ptr::Unique;
+use core::ops::{Deref, DerefMut};
+use core::ptr::{self, Unique};
+use core::sync::atomic::{AtomicUsize, Ordering};
 use core::fmt;
-use spin::Mutex;
+use alloc::boxed::Box;
+use alloc::vec::Vec;
+use crate::sync::{SpinLock, SpinLockGuard};
 
+use crate::arch::x86::interrupts::InterruptGuard;
 use crate::arch::x86::KERNEL_BASE;
 
 /// The number of rows of text
 pub const BUFFER_HEIGHT: usize = 25;
 /// The number of columns per row of text
 pub const BUFFER_WIDTH: usize = 80;
-/// The address of the VGA buffer
+/// The default address of the VGA buffer. See `set_backing()`
 pub const BUFFER_ADDR: usize = KERNEL_BASE + 0xb8000;
+/// The number of lines kept after scrolling off the screen
+pub const SCROLLBACK_LINES: usize = 1000;
 
 static mut BUFFER: VgaBuffer = unsafe { VgaBuffer::new() };
 
+/// Address of the displayed text buffer, readable without the lock
+static BACKING: AtomicUsize = AtomicUsize::new(BUFFER_ADDR);
+/// Index of the cell written next by `emergency_print()`
+static EMERGENCY_CELL: AtomicUsize = AtomicUsize::new(0);
+
 /// Safe wrapper around the screen buffer
 pub struct VgaBuffer {
-    writer: Mutex<Writer>,
+    writer: SpinLock<Writer>,
+}
+
+/// The writer, locked with interrupts disabled
+///
+/// Interrupt handlers print, so an interrupt arriving while the lock is held
+/// would otherwise spin forever. The lock is released before interrupts are
+/// restored, as fields are dropped in order.
+struct LockedWriter<'a> {
+    writer: SpinLockGuard<'a, Writer>,
+    _interrupts: InterruptGuard,
 }
 
 struct Writer {
@@ -34,23 +60,48 @@ struct Writer {
     row: usize,
     color_code: ColorCode,
     buffer: Unique<Buffer>,
+    scrollback: Option<Scrollback>,
 }
 
+#[repr(C)]
 struct Buffer {
-    chars: [[ScreenChar; BUFFER_WIDTH]; BUFFER_HEIGHT],
+    chars: Screen,
 }
 
-#[derive(Clone, Copy)]
+/// A row of the screen
+pub type Line = [ScreenChar; BUFFER_WIDTH];
+/// The contents of the whole screen
+pub type Screen = [Line; BUFFER_HEIGHT];
+
+/// Ring buffer of the lines which have scrolled off the screen
+struct Scrollback {
+    lines: Vec<Line>,
+    /// Index of the oldest line once `lines` is full
+    oldest: usize,
+    /// Number of lines the view is scrolled up from the live screen
+    offset: usize,
+    /// Contents of the live screen while the view is scrolled up
+    live: Box<Buffer>,
+}
+
+/// A character cell as laid out in the VGA buffer
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
 #[repr(C)]
-struct ScreenChar {
-    ascii_character: u8,
-    color_code: ColorCode,
+pub struct ScreenChar {
+    pub ascii_character: u8,
+    pub color_code: ColorCode,
 }
 
 /// Wrapper around a packed foreground / background pair
-#[derive(Clone, Copy)]
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
 pub struct ColorCode(u8);
 
+/// Restores the previous color code when dropped. See `push_color()`.
+#[must_use]
+pub struct ColorGuard {
+    previous: ColorCode,
+}
+
 /// The various foreground and background text colors
 #[allow(dead_code)]
 #[repr(u8)]
@@ -73,40 +124,223 @@ pub enum Color {
     White = 15,
 }
 
+/// RGB values of `Color`, as 0x00RRGGBB
+const PALETTE: [u32; 16] = [
+    0x000000, 0x0000aa, 0x00aa00, 0x00aaaa, 0xaa0000, 0xaa00aa, 0xaa5500, 0xaaaaaa,
+    0x555555, 0x5555ff, 0x55ff55, 0x55ffff, 0xff5555, 0xff55ff, 0xffff55, 0xffffff,
+];
+
+impl Color {
+    /// Returns the color of the standard VGA palette as 0x00RRGGBB
+    pub fn to_rgb(self) -> u32 {
+        PALETTE[self as usize]
+    }
+}
+
 impl VgaBuffer {
     /// Creates a new wrapper around the buffer
     const unsafe fn new() -> VgaBuffer {
         VgaBuffer {
-            writer: Mutex::new(Writer {
+            writer: SpinLock::new(Writer {
                 col: 0,
                 row: 0,
                 color_code: ColorCode::new(Color::White, Color::Black),
                 buffer: Unique::new_unchecked(BUFFER_ADDR as *mut _),
+                scrollback: None,
             }),
         }
     }
 
+    /// Locks the writer, disabling interrupts until it is unlocked
+    fn lock(&self) -> LockedWriter {
+        let interrupts = InterruptGuard::new();
+        LockedWriter {
+            writer: self.writer.lock(),
+            _interrupts: interrupts,
+        }
+    }
+
+    /// Redirects output to another text buffer
+    ///
+    /// The contents of the new buffer are kept and the cursor is moved to
+    /// the top left.
+    pub unsafe fn set_backing(&self, backing: *mut Screen) {
+        let mut writer = self.lock();
+        writer.reset_view();
+        writer.buffer = Unique::new_unchecked(backing as *mut Buffer);
+        writer.row = 0;
+        writer.col = 0;
+        BACKING.store(backing as usize, Ordering::SeqCst);
+    }
+
+    /// Starts recording lines which scroll off the screen
+    ///
+    /// Requires the heap.
+    pub fn enable_scrollback(&self) {
+        self.lock().scrollback = Some(Scrollback::new());
+    }
+
+    /// Moves the view `n` lines back into the scrollback
+    pub fn scroll_up(&self, n: usize) {
+        self.lock().scroll_view(n as isize);
+    }
+
+    /// Moves the view `n` lines towards the live screen
+    pub fn scroll_down(&self, n: usize) {
+        self.lock().scroll_view(-(n as isize));
+    }
+
     /// Sets the color code to use when drawing to screen
     pub fn set_colorcode(&self, color_code: ColorCode) {
-        self.writer.lock().color_code = color_code
+        self.lock().color_code = color_code
     }
 
     /// Returns the current color code
     pub fn get_colorcode(&self) -> ColorCode {
-        self.writer.lock().color_code
+        self.lock().color_code
+    }
+
+    /// Sets the color code until the returned guard is dropped
+    pub fn push_color(&self, color_code: ColorCode) -> ColorGuard {
+        let mut writer = self.lock();
+        let previous = writer.color_code;
+        writer.color_code = color_code;
+        ColorGuard { previous: previous }
+    }
+
+    /// Writes a string starting at the given cell, leaving the cursor alone
+    ///
+    /// Characters past the end of the row are dropped.
+    pub fn write_at(&self, row: usize, col: usize, s: &str, color_code: ColorCode) {
+        assert!(row < BUFFER_HEIGHT && col < BUFFER_WIDTH,
+                "write_at({}, {}) is off the screen", row, col);
+        let mut writer = self.lock();
+        let line = &mut writer.live_buffer().chars[row];
+        for (cell, byte) in line[col..].iter_mut().zip(s.bytes()) {
+            *cell = ScreenChar {
+                ascii_character: byte,
+                color_code: color_code,
+            };
+        }
+    }
+
+    /// Returns the character and color at the given cell
+    pub fn read_char(&self, row: usize, col: usize) -> (u8, ColorCode) {
+        assert!(row < BUFFER_HEIGHT && col < BUFFER_WIDTH,
+                "read_char({}, {}) is off the screen", row, col);
+        let cell = self.lock().live_buffer().chars[row][col];
+        (cell.ascii_character, cell.color_code)
+    }
+
+    /// Returns a copy of the screen contents
+    pub fn snapshot(&self) -> Screen {
+        self.lock().live_buffer().chars
+    }
+
+    /// Replaces the screen contents with an earlier snapshot
+    ///
+    /// The cursor is left where it is.
+    pub fn restore(&self, snapshot: &Screen) {
+        self.lock().live_buffer().chars = *snapshot;
     }
 
     /// Clears the entire screen
     pub fn clear(&self) {
-        self.writer.lock().clear();
+        self.lock().clear();
+    }
+}
+
+impl Scrollback {
+    fn new() -> Scrollback {
+        let blank = ScreenChar {
+            ascii_character: b' ',
+            color_code: ColorCode::new(Color::White, Color::Black),
+        };
+        Scrollback {
+            lines: Vec::with_capacity(SCROLLBACK_LINES),
+            oldest: 0,
+            offset: 0,
+            live: box Buffer { chars: [[blank; BUFFER_WIDTH]; BUFFER_HEIGHT] },
+        }
+    }
+
+    /// Records a line, replacing the oldest once full
+    fn push(&mut self, line: Line) {
+        if self.lines.len() < SCROLLBACK_LINES {
+            self.lines.push(line);
+        } else {
+            self.lines[self.oldest] = line;
+            self.oldest = (self.oldest + 1) % SCROLLBACK_LINES;
+        }
+    }
+
+    /// Returns a line of the scrollback followed by the live screen
+    ///
+    /// Index 0 is the oldest line of the scrollback.
+    fn line(&self, index: usize) -> Line {
+        let len = self.lines.len();
+        if index < len {
+            self.lines[(self.oldest + index) % len]
+        } else {
+            self.live.chars[index - len]
+        }
+    }
+
+    /// Returns the index in `line()` of the top row of the view
+    fn view_start(&self) -> usize {
+        self.lines.len() - self.offset
     }
 }
 
 impl Writer {
+    /// Scrolls the view by `delta` lines, positive being back in history
+    fn scroll_view(&mut self, delta: isize) {
+        let (was_live, offset) = match self.scrollback {
+            Some(ref scrollback) => {
+                let max = scrollback.lines.len() as isize;
+                let offset = (scrollback.offset as isize + delta).max(0).min(max);
+                (scrollback.offset == 0, offset as usize)
+            }
+            None => return,
+        };
+
+        if was_live {
+            if offset == 0 {
+                return;
+            }
+            // save the live screen so it can be restored
+            let chars = self.buffer().chars;
+            self.scrollback.as_mut().unwrap().live.chars = chars;
+        }
+        self.scrollback.as_mut().unwrap().offset = offset;
+        self.redraw();
+    }
+
+    /// Draws the view selected by the scrollback offset
+    fn redraw(&mut self) {
+        for row in 0..BUFFER_HEIGHT {
+            let line = {
+                let scrollback = self.scrollback.as_ref().unwrap();
+                scrollback.line(scrollback.view_start() + row)
+            };
+            self.buffer().chars[row] = line;
+        }
+    }
+
+    /// Returns the view to the live screen
+    fn reset_view(&mut self) {
+        let scrolled = self.scrollback.as_ref().map_or(false, |s| s.offset != 0);
+        if scrolled {
+            self.scrollback.as_mut().unwrap().offset = 0;
+            self.redraw();
+        }
+    }
+
     /// Writes bytes to buffer
     ///
     /// This grows from top down.
     fn write_byte(&mut self, byte: u8) {
+        self.reset_view();
         match byte {
             b'\n' => self.new_line(),
             byte => {
@@ -127,14 +361,30 @@ impl Writer {
         unsafe { self.buffer.as_mut() }
     }
 
+    /// Returns the live screen, which is not the one displayed while the
+    /// view is scrolled back
+    fn live_buffer(&mut self) -> &mut Buffer {
+        let scrolled = self.scrollback.as_ref().map_or(false, |s| s.offset != 0);
+        if scrolled {
+            &mut self.scrollback.as_mut().unwrap().live
+        } else {
+            self.buffer()
+        }
+    }
+
     /// Moves all lines up one row and clears the last line
     fn new_line(&mut self) {
         const LAST_ROW: usize = BUFFER_HEIGHT - 1;
 
         if self.row >= LAST_ROW {
-            for row in 0..LAST_ROW {
-                let buffer = self.buffer();
-                buffer.chars[row] = buffer.chars[row + 1]
+            let top = self.buffer().chars[0];
+            if let Some(ref mut scrollback) = self.scrollback {
+                scrollback.push(top);
+            }
+            // one bulk move rather than a small access per character
+            let chars = self.buffer().chars.as_mut_ptr() as *mut ScreenChar;
+            unsafe {
+                ptr::copy(chars.offset(BUFFER_WIDTH as isize), chars, LAST_ROW * BUFFER_WIDTH);
             }
         } else {
             self.row += 1
@@ -163,9 +413,23 @@ impl Writer {
     }
 }
 
+impl<'a> Deref for LockedWriter<'a> {
+    type Target = Writer;
+
+    fn deref(&self) -> &Writer {
+        &self.writer
+    }
+}
+
+impl<'a> DerefMut for LockedWriter<'a> {
+    fn deref_mut(&mut self) -> &mut Writer {
+        &mut self.writer
+    }
+}
+
 impl fmt::Write for VgaBuffer {
     fn write_str(&mut self, s: &str) -> fmt::Result {
-        let mut writer = self.writer.lock();
+        let mut writer = self.lock();
         for byte in s.bytes() {
             writer.write_byte(byte)
         }
@@ -182,6 +446,55 @@ pub fn get_vgabuffer<'a>() -> &'a mut VgaBuffer {
     unsafe { &mut BUFFER }
 }
 
+/// Writes red text straight to the screen, without taking any lock
+///
+/// Meant for reporting panics, which may happen while the writer is locked.
+/// The first call starts at the top left of the screen, and each row is
+/// cleared as it is reached, wrapping around at the bottom. A newline at the
+/// start of a row does nothing, so a line filling its row is not followed by
+/// a blank one. Output is best effort: it ignores the writer's cursor, and
+/// concurrent calls interleave.
+pub unsafe fn emergency_print(s: &str) {
+    const RED_ON_BLACK: u16 = 0x04 << 8;
+    const CELLS: usize = BUFFER_WIDTH * BUFFER_HEIGHT;
+
+    let cells = BACKING.load(Ordering::SeqCst) as *mut u16;
+    let mut cell = EMERGENCY_CELL.load(Ordering::SeqCst);
+    for byte in s.bytes() {
+        if byte == b'\n' && cell % BUFFER_WIDTH != 0 {
+            cell += BUFFER_WIDTH - cell % BUFFER_WIDTH;
+        }
+        cell %= CELLS;
+        if cell % BUFFER_WIDTH == 0 {
+            for i in cell..cell + BUFFER_WIDTH {
+                ptr::write_volatile(cells.offset(i as isize), RED_ON_BLACK | b' ' as u16);
+            }
+        }
+        if byte != b'\n' {
+            ptr::write_volatile(cells.offset(cell as isize), RED_ON_BLACK | byte as u16);
+            cell += 1;
+        }
+    }
+    EMERGENCY_CELL.store(cell, Ordering::SeqCst);
+}
+
+/// Formats with `emergency_print()`
+pub struct EmergencyWriter(());
+
+impl EmergencyWriter {
+    /// See `emergency_print()` for why this is unsafe
+    pub unsafe fn new() -> EmergencyWriter {
+        EmergencyWriter(())
+    }
+}
+
+impl fmt::Write for EmergencyWriter {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        unsafe { emergency_print(s); }
+        Ok(())
+    }
+}
+
 /// Prints a message in red text then stops execution
 pub fn print_error(fmt: fmt::Arguments) -> ! {
     use core::fmt::Write;
@@ -192,11 +505,38 @@ pub fn print_error(fmt: fmt::Arguments) -> ! {
     intrinsics::halt();
 }
 
+impl Drop for ColorGuard {
+    fn drop(&mut self) {
+        get_vgabuffer().set_colorcode(self.previous);
+    }
+}
+
 impl ColorCode {
     /// Creates a new ColorCode from the specified colors
     pub const fn new(foreground: Color, background: Color) -> ColorCode {
         ColorCode((background as u8) << 4 | (foreground as u8))
     }
+
+    /// Returns the foreground color as 0x00RRGGBB
+    pub fn foreground_rgb(self) -> u32 {
+        PALETTE[(self.0 & 0x0f) as usize]
+    }
+
+    /// Returns the background color as 0x00RRGGBB
+    pub fn background_rgb(self) -> u32 {
+        PALETTE[(self.0 >> 4) as usize]
+    }
+}
+
+/// Evaluates a block with the given foreground and background colors
+///
+/// The previous color is restored however the block is left.
+macro_rules! with_color {
+    ($fg:expr, $bg:expr, $body:block) => ({
+        let _guard = $crate::vga::get_vgabuffer()
+            .push_color($crate::vga::ColorCode::new($fg, $bg));
+        $body
+    });
 }
 
 macro_rules! println {
@@ -206,7 +546,6 @@ macro_rules! println {
 
 macro_rules! print {
     ($($arg:tt)*) => ({
-        use core::fmt::Write;
-        $crate::vga::get_vgabuffer().write_fmt(format_args!($($arg)*)).unwrap();
+        $crate::console::print(format_args!($($arg)*));
     });
 }